@@ -35,6 +35,14 @@ mod logview;
 pub use self::logview::*;
 mod memoryview;
 pub use self::memoryview::*;
+mod gpuview;
+pub use self::gpuview::*;
+mod texview;
+pub use self::texview::*;
+mod optionview;
+pub use self::optionview::*;
+mod infoview;
+pub use self::infoview::*;
 
 pub trait DebuggerModel {
     /// Return a vector of the name of all CPUS.
@@ -213,6 +221,14 @@ impl DebuggerUI {
                         self.dbg.disable_breakpoint_oneshot();
                         return false;
                     }
+                    TraceEvent::GpuCommand(gpu_name, cmd_name) => {
+                        self.paused = true;
+                        self.dbg.disable_breakpoint_oneshot();
+                        self.uictx
+                            .get_mut()
+                            .add_flash_msg(&format!("{} command: {}", gpu_name, cmd_name));
+                        return false;
+                    }
                     TraceEvent::GenericBreak(msg) => {
                         self.paused = true;
                         self.dbg.disable_breakpoint_oneshot();
@@ -274,6 +290,11 @@ impl DebuggerUI {
                 self.dbg.set_breakpoint_oneshot(&cpu_name, Some(pc));
                 self.paused = false;
             }
+            Some(UiCommand::GpuBreak(ref gpu_name, op)) => {
+                let gpu_name = gpu_name.clone();
+                self.dbg.set_gpu_break(Some((gpu_name, op)));
+                self.paused = false;
+            }
             Some(UiCommand::CpuStep(ref cpu_name)) => {
                 let _ = model.trace_step(&cpu_name, &Tracer::null());
                 self.paused = true;
@@ -357,8 +378,21 @@ impl DebuggerUI {
             .build(ui, || {
                 let tsid = self.tex_screen.id();
                 let reg = ui.content_region_avail();
+                let pos = ui.cursor_screen_pos();
                 let image = imgui::Image::new(tsid.into(), reg);
                 image.build(ui);
+
+                // Highlight the area selected in a GPU command view (if any)
+                if let Some(hl) = self.uictx.get_mut().screen_highlight {
+                    ui.get_window_draw_list()
+                        .add_rect(
+                            [pos[0] + hl[0] * reg[0], pos[1] + hl[1] * reg[1]],
+                            [pos[0] + hl[2] * reg[0], pos[1] + hl[3] * reg[1]],
+                            [1.0, 0.0, 1.0, 1.0],
+                        )
+                        .thickness(2.0)
+                        .build();
+                }
             });
 
         // Render CPU debugger
@@ -480,4 +514,26 @@ impl<'a, 'ui> DebuggerRenderer<'a, 'ui> {
             .or_insert_with(|| MemWindow::default())
             .render(self.ui, v);
     }
+    pub fn render_texview<V: TextureView>(&self, v: &mut V) {
+        let mut ctx = self.ctx.borrow_mut();
+        ctx.texviews
+            .entry(v.name().to_string())
+            .or_insert_with(|| TexWindow::default())
+            .render(self.ui, v);
+    }
+    pub fn render_optionview<V: OptionView>(&self, v: &mut V) {
+        render_optionview(self.ui, v)
+    }
+    pub fn render_infoview<V: InfoView>(&self, v: &V) {
+        render_infoview(self.ui, v)
+    }
+    /// Render the command list of a GPU. Returns true if the list is
+    /// visible, so that the GPU only records commands while needed.
+    pub fn render_gpuview<V: GpuCommandView>(&self, v: &mut V) -> bool {
+        let mut ctx = self.ctx.borrow_mut();
+        let mut win = ctx.gpuviews.remove(v.name()).unwrap_or_default();
+        let visible = win.render(self.ui, &mut ctx, v);
+        ctx.gpuviews.insert(v.name().to_string(), win);
+        visible
+    }
 }
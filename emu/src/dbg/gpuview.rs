@@ -0,0 +1,135 @@
+use super::{UiCommand, UiCtx};
+use imgui::*;
+
+use std::borrow::Cow;
+
+/// A command executed by a GPU, as displayed in the command list of a
+/// [`GpuCommandView`](trait.GpuCommandView.html).
+#[derive(Clone, Default, Debug)]
+pub struct GpuCommand {
+    /// Command type (opcode). This is the value passed to
+    /// [`Tracer::trace_gpu`](struct.Tracer.html#method.trace_gpu).
+    pub op: u32,
+    /// Name of the command.
+    pub name: &'static str,
+    /// Raw command words.
+    pub words: Vec<u64>,
+    /// Decoded parameters, one per line.
+    pub params: Vec<String>,
+    /// Area of the framebuffer touched by the command (x0, y0, x1, y1),
+    /// with an inclusive lower-right corner.
+    pub area: Option<(usize, usize, usize, usize)>,
+}
+
+/// GpuCommandView is a trait implemented by GPUs whose command stream can be
+/// inspected within the debugger.
+pub trait GpuCommandView {
+    /// Return the name of this view (and of the GPU)
+    fn name(&self) -> &str;
+
+    /// Return all the command types that can be processed by the GPU, as
+    /// (opcode, name). This is the list of commands that can be used for a
+    /// "break on next command" request.
+    fn command_types(&self) -> Vec<(u32, &'static str)>;
+
+    /// Return the commands executed during the last completed frame.
+    fn commands(&self) -> &[GpuCommand];
+
+    /// Return the size of the framebuffer the commands were drawn into,
+    /// used to map the area of a command onto the screen view.
+    fn framebuffer_size(&self) -> (usize, usize);
+}
+
+#[derive(Default)]
+pub(crate) struct GpuWindow {
+    selected: Option<usize>, // command currently selected in the list
+    break_type: usize,       // index of the command type selected for break
+}
+
+impl GpuWindow {
+    // Returns true if the command list is visible (the window is not
+    // collapsed).
+    pub(crate) fn render(&mut self, ui: &Ui, ctx: &mut UiCtx, v: &mut dyn GpuCommandView) -> bool {
+        let types = v.command_types();
+        let cmds = v.commands();
+        let fbsize = v.framebuffer_size();
+        if self.selected.map_or(false, |idx| idx >= cmds.len()) {
+            self.selected = None;
+        }
+
+        let mut visible = false;
+        Window::new(&im_str!("[{}] Command list", v.name()))
+            .size([450.0, 500.0], Condition::FirstUseEver)
+            .build(ui, || {
+                visible = true;
+
+                // Break on next command of the selected type
+                ui.set_next_item_width(180.0);
+                ComboBox::new(im_str!("##type")).build_simple(
+                    ui,
+                    &mut self.break_type,
+                    &types,
+                    &|t: &(u32, &'static str)| Cow::Owned(im_str!("{:02X} {}", t.0, t.1)),
+                );
+                ui.same_line(0.0);
+                if ui.button(im_str!("Break on next"), [0.0, 0.0]) && !types.is_empty() {
+                    ctx.command = Some(UiCommand::GpuBreak(
+                        v.name().to_owned(),
+                        types[self.break_type].0,
+                    ));
+                }
+                ui.same_line(0.0);
+                ui.text(im_str!("{} commands in last frame", cmds.len()));
+                ui.separator();
+
+                ChildWindow::new(im_str!("##list"))
+                    .size([0.0, -ui.text_line_height_with_spacing() * 8.0])
+                    .border(true)
+                    .build(ui, || {
+                        for (idx, cmd) in cmds.iter().enumerate() {
+                            let label = im_str!(
+                                "{:5} {:016X} {}##cmd{}",
+                                idx,
+                                cmd.words[0],
+                                cmd.name,
+                                idx
+                            );
+                            if Selectable::new(&label)
+                                .selected(self.selected == Some(idx))
+                                .build(ui)
+                            {
+                                self.selected = Some(idx);
+                            }
+                        }
+                    });
+
+                // Details of the selected command
+                ChildWindow::new(im_str!("##details")).build(ui, || {
+                    if let Some(cmd) = self.selected.map(|idx| &cmds[idx]) {
+                        ui.text(im_str!("{} (0x{:02X})", cmd.name, cmd.op));
+                        for w in cmd.words.iter().skip(1) {
+                            ui.text_disabled(im_str!("    {:016X}", w));
+                        }
+                        for p in cmd.params.iter() {
+                            ui.bullet_text(&im_str!("{}", p));
+                        }
+                    }
+                });
+            });
+
+        // Highlight the area touched by the selected command in the screen view.
+        ctx.screen_highlight = match self.selected.and_then(|idx| cmds[idx].area) {
+            Some((x0, y0, x1, y1)) if fbsize.0 != 0 && fbsize.1 != 0 => {
+                let (w, h) = (fbsize.0 as f32, fbsize.1 as f32);
+                Some([
+                    x0 as f32 / w,
+                    y0 as f32 / h,
+                    ((x1 + 1) as f32 / w).min(1.0),
+                    ((y1 + 1) as f32 / h).min(1.0),
+                ])
+            }
+            _ => None,
+        };
+        visible
+    }
+}
@@ -0,0 +1,30 @@
+use imgui::*;
+
+/// InfoView is a trait implemented by objects that expose read-only
+/// information to be displayed in the debugger (eg: metadata of the
+/// loaded game).
+pub trait InfoView {
+    /// Return the name of this view
+    fn name(&self) -> &str;
+
+    /// Visit all the information fields, calling the visitor with the
+    /// name and the textual value of each of them.
+    fn visit_info<F>(&self, visit: F)
+    where
+        F: for<'a> FnMut(&'a str, &'a str);
+}
+
+pub(crate) fn render_infoview<'a, 'ui, V: InfoView>(ui: &'a Ui<'ui>, v: &V) {
+    Window::new(&im_str!("[{}] Info", v.name()))
+        .size([300.0, 250.0], Condition::FirstUseEver)
+        .build(ui, || {
+            ui.columns(2, im_str!("##columns"), true);
+            v.visit_info(|name, val| {
+                ui.text(name);
+                ui.next_column();
+                ui.text(val);
+                ui.next_column();
+            });
+            ui.columns(1, im_str!("##columns"), false);
+        });
+}
@@ -0,0 +1,25 @@
+use imgui::*;
+
+/// OptionView is a trait implemented by objects that expose boolean options
+/// that can be toggled from the debugger (eg: to compare the output of
+/// a video filter with and without it).
+pub trait OptionView {
+    /// Return the name of this view
+    fn name(&self) -> &str;
+
+    /// Visit all the options, calling the visitor with the name and the
+    /// current value of each of them.
+    fn visit_options<F>(&mut self, visit: F)
+    where
+        F: for<'a> FnMut(&'a str, &'a mut bool);
+}
+
+pub(crate) fn render_optionview<'a, 'ui, V: OptionView>(ui: &'a Ui<'ui>, v: &mut V) {
+    Window::new(&im_str!("[{}] Options", v.name()))
+        .size([250.0, 150.0], Condition::FirstUseEver)
+        .build(ui, || {
+            v.visit_options(|name, val| {
+                ui.checkbox(&im_str!("{}", name), val);
+            });
+        });
+}
@@ -0,0 +1,142 @@
+use crate::gfx::{OwnedGfxBufferLE, Rgba8888};
+use crate::hw::glutils::Texture;
+use image::png::PNGEncoder;
+use image::ColorType;
+use imgui::*;
+use tinyfiledialogs::save_file_dialog_with_filter;
+
+use std::fs::File;
+
+/// TextureView is a trait implemented by GPUs that can display the textures
+/// they are currently configured to use (eg: texture tiles in a texture
+/// memory).
+pub trait TextureView {
+    /// Return the name of this view
+    fn name(&self) -> &str;
+
+    /// Return the number of textures that can be inspected.
+    fn num_textures(&self) -> usize;
+
+    /// Return a description of the specified texture (format, size, etc.),
+    /// one property per line.
+    fn texture_info(&self, idx: usize) -> Vec<String>;
+
+    /// Decode the specified texture into an image. Returns None if the
+    /// texture is not configured or cannot be decoded.
+    fn texture(&self, idx: usize) -> Option<OwnedGfxBufferLE<Rgba8888>>;
+
+    /// Decode the palette (if any) into an image, with one pixel per entry.
+    fn palette(&self) -> Option<OwnedGfxBufferLE<Rgba8888>>;
+}
+
+pub(crate) struct TexWindow {
+    selected: usize,
+    zoom: i32,
+    show_palette: bool,
+    tex: Option<Texture>,
+    tex_palette: Option<Texture>,
+    error_msg: Option<String>,
+}
+
+impl Default for TexWindow {
+    fn default() -> TexWindow {
+        TexWindow {
+            selected: 0,
+            zoom: 2,
+            show_palette: false,
+            tex: None,
+            tex_palette: None,
+            error_msg: None,
+        }
+    }
+}
+
+fn export_png(buf: &OwnedGfxBufferLE<Rgba8888>, filename: &str) -> std::io::Result<()> {
+    let (width, height) = (buf.width(), buf.height());
+    let b = buf.buf();
+    let (raw, _pitch) = b.raw();
+    PNGEncoder::new(File::create(filename)?).encode(
+        raw,
+        width as u32,
+        height as u32,
+        ColorType::RGBA(8),
+    )
+}
+
+impl TexWindow {
+    pub(crate) fn render(&mut self, ui: &Ui, v: &mut dyn TextureView) {
+        if v.num_textures() == 0 {
+            return;
+        }
+        self.selected = self.selected.min(v.num_textures() - 1);
+
+        Window::new(&im_str!("[{}] Textures", v.name()))
+            .size([400.0, 500.0], Condition::FirstUseEver)
+            .build(ui, || {
+                for idx in 0..v.num_textures() {
+                    if idx != 0 {
+                        ui.same_line(0.0);
+                    }
+                    ui.radio_button(&im_str!("{}", idx), &mut self.selected, idx);
+                }
+                ui.set_next_item_width(100.0);
+                ui.slider_int(im_str!("Zoom"), &mut self.zoom, 1, 8).build();
+                ui.same_line(0.0);
+                ui.checkbox(im_str!("TLUT"), &mut self.show_palette);
+                ui.separator();
+
+                for line in v.texture_info(self.selected) {
+                    ui.text(&im_str!("{}", line));
+                }
+
+                let img = match v.texture(self.selected) {
+                    Some(img) => img,
+                    None => {
+                        ui.text_disabled(im_str!("(texture not configured)"));
+                        return;
+                    }
+                };
+                if ui.button(im_str!("Export PNG..."), [0.0, 0.0]) {
+                    let default = format!("tile{}.png", self.selected);
+                    if let Some(path) = save_file_dialog_with_filter(
+                        "Export texture",
+                        &default,
+                        &vec!["*.png"],
+                        "PNG image",
+                    ) {
+                        if let Err(err) = export_png(&img, &path) {
+                            self.error_msg = Some(format!("Cannot export texture: {}", err));
+                        }
+                    }
+                }
+                if let Some(msg) = &self.error_msg {
+                    ui.text_colored([1.0, 0.3, 0.3, 1.0], &im_str!("{}", msg));
+                }
+
+                let zoom = self.zoom as f32;
+                let tex = self.tex.get_or_insert_with(Texture::new);
+                tex.copy_from_buffer(&img.buf());
+                Image::new(
+                    tex.id().into(),
+                    [img.width() as f32 * zoom, img.height() as f32 * zoom],
+                )
+                .border_col([0.5, 0.5, 0.5, 1.0])
+                .build(ui);
+
+                if self.show_palette {
+                    if let Some(pal) = v.palette() {
+                        ui.separator();
+                        ui.text(im_str!("TLUT"));
+                        let tex = self.tex_palette.get_or_insert_with(Texture::new);
+                        tex.copy_from_buffer(&pal.buf());
+                        // Palette entries are always displayed as 8x8 squares.
+                        Image::new(
+                            tex.id().into(),
+                            [pal.width() as f32 * 8.0, pal.height() as f32 * 8.0],
+                        )
+                        .build(ui);
+                    }
+                }
+            });
+    }
+}
@@ -21,9 +21,24 @@ pub enum TraceEvent {
     BreakpointOneShot(String, u64), // A one-shot breakpoint was hit (cpu_idx, pc)
     WatchpointWrite(String, usize), // A watchpoint was hit during a write (cpu_idx, wp_idx)
     WatchpointRead(String, usize), // A watchpoint was hit during a read (cpu_idx, wp_idx)
+    GpuCommand(String, String), // A GPU is about to process a command of the requested type (gpu, command)
     GenericBreak(String), // Another kind of condition was hit, and we want to stop the tracing.
 }
 
+/// An event generated by a GPU, traced through
+/// [`Tracer::trace_gpu`](struct.Tracer.html#method.trace_gpu).
+#[derive(Debug, Copy, Clone)]
+pub enum GpuEvent<'a> {
+    /// The video output started drawing a new line.
+    Line(usize),
+    /// A GPU is about to process a command.
+    Command {
+        gpu: &'a str,
+        op: u32,
+        name: &'a str,
+    },
+}
+
 pub type Result<T> = std::result::Result<T, Box<TraceEvent>>;
 
 bitflags! {
@@ -78,8 +93,8 @@ impl Tracer<'_> {
     }
 
     #[inline(always)]
-    pub fn trace_gpu(&self, line: usize) -> Result<()> {
-        self.dbg.map(|t| t.trace_gpu(line)).unwrap_or(Ok(()))
+    pub fn trace_gpu(&self, ev: GpuEvent) -> Result<()> {
+        self.dbg.map(|t| t.trace_gpu(ev)).unwrap_or(Ok(()))
     }
 
     #[inline(always)]
@@ -324,6 +339,8 @@ pub struct Debugger {
     cpus: HashMap<String, DbgCpu>,
     #[serde(skip)]
     next_poll: Cell<Option<Instant>>,
+    #[serde(skip)]
+    gpu_break: Option<(String, u32)>, // Special one-shot break on GPU command type
 }
 
 impl Debugger {
@@ -336,6 +353,7 @@ impl Debugger {
         Self {
             cpus: cpumap,
             next_poll: Cell::new(None),
+            gpu_break: None,
         }
     }
 
@@ -356,6 +374,13 @@ impl Debugger {
         for (_, cpu) in &mut self.cpus {
             cpu.set_breakpoint_oneshot(None);
         }
+        self.gpu_break = None;
+    }
+
+    /// Break when the specified GPU is about to process a command of the
+    /// specified type (gpu_name, op).
+    pub fn set_gpu_break(&mut self, gpu_break: Option<(String, u32)>) {
+        self.gpu_break = gpu_break;
     }
 
     pub fn set_poll_event(&mut self, when: Instant) {
@@ -442,17 +467,28 @@ impl Debugger {
         }
     }
 
-    fn trace_gpu(&self, _line: usize) -> Result<()> {
-        // Check if the polling interval is elapsed. Do this only every line
-        // (not every insn or memory access, since otherwise the overhead is
-        // too big).
-        if let Some(poll_when) = self.next_poll.get() {
-            if poll_when <= Instant::now() {
-                self.next_poll.set(None);
-                return Err(box TraceEvent::Poll());
+    fn trace_gpu(&self, ev: GpuEvent) -> Result<()> {
+        match ev {
+            GpuEvent::Line(_) => {
+                // Check if the polling interval is elapsed. Do this only every line
+                // (not every insn or memory access, since otherwise the overhead is
+                // too big).
+                if let Some(poll_when) = self.next_poll.get() {
+                    if poll_when <= Instant::now() {
+                        self.next_poll.set(None);
+                        return Err(box TraceEvent::Poll());
+                    }
+                }
+                Ok(())
             }
+            GpuEvent::Command { gpu, op, name } => match self.gpu_break {
+                Some((ref bgpu, bop)) if bgpu == gpu && bop == op => Err(box TraceEvent::GpuCommand(
+                    gpu.to_owned(),
+                    name.to_owned(),
+                )),
+                _ => Ok(()),
+            },
         }
-        Ok(())
     }
 }
 
@@ -1,4 +1,4 @@
-use super::{MemWindow, TraceEvent};
+use super::{GpuWindow, MemWindow, TexWindow, TraceEvent};
 use crate::log::{LogLine, LogView};
 use imgui::ImString;
 
@@ -10,6 +10,7 @@ use std::time::Instant;
 pub(crate) enum UiCommand {
     BreakpointOneShot(String, u64), // Run with a temporary breakpoint set
     CpuStep(String),                // Step a single opcode for the specified CPU
+    GpuBreak(String, u32),          // Run until the specified GPU processes a command of this type
     Pause(bool),                    // Set global pause status
 }
 
@@ -99,6 +100,16 @@ pub(crate) struct UiCtx {
     // Memory views
     pub memviews: HashMap<String, MemWindow>,
 
+    // GPU command views
+    pub gpuviews: HashMap<String, GpuWindow>,
+
+    // Texture views
+    pub texviews: HashMap<String, TexWindow>,
+
+    // Area of the screen view to highlight (x0, y0, x1, y1), as fractions
+    // of the screen size.
+    pub screen_highlight: Option<[f32; 4]>,
+
     // Flash messages (auto-hide after 2s)
     pub flash_msg: Option<(String, Instant)>,
 
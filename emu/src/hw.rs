@@ -6,9 +6,9 @@ use self::input_mapping::{InputConfig, InputMapping};
 
 use crate::dbg::{DebuggerModel, DebuggerUI};
 use crate::gfx::{GfxBufferLE, GfxBufferMutLE, OwnedGfxBufferLE, Rgb888};
-use crate::input::{InputEvent, InputManager};
+use crate::input::{FeedbackEvent, InputEvent, InputManager};
 use crate::log::LogPoolPtr;
-use crate::snd::{OwnedSndBuffer, SampleFormat, SampleInt, SndBuffer, SndBufferMut};
+use crate::snd::{OwnedSndBuffer, Resampler, SampleFormat, SampleInt, SndBuffer, SndBufferMut};
 
 use byteorder::NativeEndian;
 use sdl2::audio::{AudioFormatNum, AudioQueue, AudioSpecDesired};
@@ -28,11 +28,16 @@ pub struct VideoConfig {
     pub window_title: String,
     pub width: isize,
     pub height: isize,
+    /// Frame rate of the emulated system (eg: 50 for PAL systems). The
+    /// output is paced by the audio, generating fps frames per second.
     pub fps: isize,
 }
 
 pub struct AudioConfig {
     pub frequency: isize,
+    /// Target amount of audio queued for playback. Higher values are more
+    /// robust against crackles, at the cost of a longer delay.
+    pub latency: Duration,
 }
 
 struct Video {
@@ -105,10 +110,20 @@ impl Video {
     }
 }
 
+// Maximum adjustment of the playback rate performed by the dynamic rate
+// control: a small one in normal conditions (inaudible), and a larger one
+// to stretch audio when the queue is about to underrun.
+const MAX_RATE_ADJUST: f64 = 0.005;
+const MAX_RATE_STRETCH: f64 = 0.1;
+
 struct Audio<SI: SampleInt + AudioFormatNum, SF: SampleFormat<ORDER = NativeEndian, SAMPLE = SI>> {
     audio: AudioSubsystem,
     queue: AudioQueue<SI>,
     frame_size: usize,
+    frequency: f64,
+    latency: usize, // target queue size, in audio frames
+    resampler: Resampler,
+    resampled: Vec<i16>,
     phantom: PhantomData<SF>,
 }
 
@@ -140,10 +155,16 @@ where
         let queue = audio.open_queue(None, &spec).unwrap();
         queue.resume();
 
+        let frequency = acfg.frequency as f64;
+        let latency = (acfg.latency.as_micros() as f64 * frequency / 1_000_000.0) as usize;
         Self {
             audio,
             queue,
             frame_size: nsamples_per_frame * SF::frame_size(),
+            frequency,
+            latency: latency.max(nsamples_per_frame),
+            resampler: Resampler::new(frequency, frequency),
+            resampled: Vec::new(),
             phantom: PhantomData,
         }
     }
@@ -152,22 +173,54 @@ where
         self.frame_size / SF::frame_size()
     }
 
+    // Dynamic rate control: slightly change the playback rate to keep the
+    // queue around the target latency. This compensates for the emulator
+    // running slightly faster or slower than the host audio clock, without
+    // dropping samples or inserting silence (which would cause crackles).
+    fn adjust_rate(&mut self) {
+        let queued = self.queue.size() as usize / SF::frame_size();
+
+        // Positive when the queue is emptier than the target, which means
+        // that more samples must be generated.
+        let err = 1.0 - queued as f64 / self.latency as f64;
+        let adjust = if queued < self.samples_per_frame() {
+            err * MAX_RATE_STRETCH
+        } else {
+            (err * MAX_RATE_ADJUST).max(-MAX_RATE_ADJUST).min(MAX_RATE_ADJUST)
+        };
+        self.resampler.set_output_frequency(self.frequency * (1.0 + adjust));
+    }
+
     fn render_frame(&mut self, buf: &SndBuffer<SF>, throttle: bool) {
+        self.adjust_rate();
+
+        self.resampled.clear();
+        for i in 0..buf.count() {
+            let left: i16 = buf.get_sample(i, 0).sconv();
+            let right: i16 = buf.get_sample(i, SF::CHANNELS - 1).sconv();
+            self.resampler.push([left, right], &mut self.resampled);
+        }
+        let out: Vec<SI> = self
+            .resampled
+            .chunks(2)
+            .flat_map(|f| f[..SF::CHANNELS].iter().map(|s| s.sconv()))
+            .collect();
+
         if throttle {
-            // Wait until the queue is less than one frame small. This
-            // crates one buffer worth of lag, but should keep the audio
-            // playing with no cracks.
-            while self.queue.size() > self.frame_size as u32 * 2 {
+            // Wait until the queue is back within the target latency. This
+            // paces the emulation to the host audio clock.
+            let max_size = ((self.latency + self.samples_per_frame()) * SF::frame_size()) as u32;
+            while self.queue.size() > max_size {
                 std::thread::sleep(Duration::from_micros(100));
             }
-            self.queue.queue(buf.as_ref());
+            self.queue.queue(&out);
         } else {
             // If we're not throttling there are two possibilities:
-            // we're either running too slow (in which case, there would be
-            // audio cracks), or too fast; in the latter case, we want to skip
-            // some audio frames to avoid desyncing audio and video.
-            if self.queue.size() < self.frame_size as u32 {
-                self.queue.queue(buf.as_ref());
+            // we're either running too slow (in which case, the rate control
+            // stretches the audio), or too fast; in the latter case, we want
+            // to skip some audio frames to avoid desyncing audio and video.
+            if self.queue.size() < (self.latency * SF::frame_size()) as u32 {
+                self.queue.queue(&out);
             }
         }
     }
@@ -276,8 +329,14 @@ impl Output {
         let mut event_pump = self.context.event_pump().unwrap();
         let mut screen = OwnedGfxBufferLE::<Rgb888>::new(width, height);
 
-        let input = match producer.input_manager() {
-            Some(im) => Some(InputMapping::new(InputConfig::default(im))),
+        // Game controllers are optional: if SDL cannot initialize them, only
+        // the keyboard is mapped.
+        let gc = self.context.game_controller().ok();
+        let mut input = match producer.input_manager() {
+            Some(im) => Some(InputMapping::new(
+                InputConfig::default(im, gc.as_ref()),
+                gc.as_ref(),
+            )),
             None => None,
         };
 
@@ -301,6 +360,11 @@ impl Output {
             let v = self.video.as_mut().unwrap();
             if !self.debug {
                 producer.render_frame(&mut screen.buf_mut(), &mut audio_buf.buf_mut());
+                if let (Some(map), Some(im)) = (input.as_mut(), producer.input_manager()) {
+                    for evt in im.drain_feedback() {
+                        map.process_feedback(&evt);
+                    }
+                }
                 v.render_frame(&screen.buf());
                 audio.render_frame(&audio_buf.buf(), true);
                 v.update_fps();
@@ -339,13 +403,14 @@ impl Output {
         let (tx_frame, rx_frame) = mpsc::sync_channel(3);
         let (tx_event, rx_event) = mpsc::sync_channel::<Vec<InputEvent>>(3);
         let (tx_input, rx_input) = mpsc::sync_channel(1);
+        let (tx_feedback, rx_feedback) = mpsc::channel::<Vec<FeedbackEvent>>();
 
         let mut audio = Audio::new(&self.context, self.vcfg.fps, self.acfg.clone());
         let audio_frame_size = audio.samples_per_frame();
 
         let mut event_pump = self.context.event_pump().unwrap();
 
-        thread::spawn(move || {
+        let producer_thread = thread::spawn(move || {
             let mut producer = create().unwrap();
 
             // Send a clone of the input manager to the main thread,
@@ -361,6 +426,14 @@ impl Output {
                     return;
                 }
 
+                // Forward feedback events (eg: rumble) to the main thread.
+                if let Some(im) = producer.input_manager() {
+                    let evts = im.drain_feedback();
+                    if evts.len() > 0 {
+                        tx_feedback.send(evts).ok();
+                    }
+                }
+
                 // If we received any input event from the main thread, process
                 // them through the input manager.
                 if let Ok(evts) = rx_event.try_recv() {
@@ -375,13 +448,20 @@ impl Output {
 
         // Initialize input mapping, using the default config for the
         // current input manager. TODO: add load/save of input mapping.
-        let input = match rx_input.recv() {
-            Ok(Some(im)) => Some(InputMapping::new(InputConfig::default(&im))),
+        // Game controllers are optional: if SDL cannot initialize them, only
+        // the keyboard is mapped.
+        let gc = self.context.game_controller().ok();
+        let mut input = match rx_input.recv() {
+            Ok(Some(im)) => Some(InputMapping::new(
+                InputConfig::default(&im, gc.as_ref()),
+                gc.as_ref(),
+            )),
             Ok(None) => None,
             Err(_) => panic!("error while receiving input manager?"),
         };
 
-        let polling_interval = Duration::from_millis(20);
+        // Poll for events (and new frames) once per frame.
+        let polling_interval = Duration::from_micros(1_000_000 / self.vcfg.fps as u64);
         while !self.quit {
             let mut events = Vec::new();
             for event in event_pump.poll_iter() {
@@ -399,16 +479,28 @@ impl Output {
             if events.len() > 0 {
                 tx_event.send(events);
             }
+            while let Ok(evts) = rx_feedback.try_recv() {
+                if let Some(map) = input.as_mut() {
+                    for e in evts.iter() {
+                        map.process_feedback(e);
+                    }
+                }
+            }
 
             match rx_frame.recv_timeout(polling_interval) {
                 Ok((ref screen, ref sound)) => {
                     self.render_frame(&screen.buf());
                     audio.render_frame(&sound.buf(), true);
                 }
-                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
                 Err(mpsc::RecvTimeoutError::Timeout) => {}
             }
         }
+
+        // Stop the producer thread and wait for it to exit, so that the
+        // producer is dropped (and can eg. flush its state to disk).
+        drop(rx_frame);
+        producer_thread.join().ok();
     }
 
     /// Render a single frame to the video output.
@@ -1,15 +1,21 @@
-use crate::input::{InputDeviceKind, InputEvent, InputKind, InputManager};
+use crate::input::{FeedbackEvent, InputDeviceKind, InputEvent, InputKind, InputManager};
 
 use sdl2;
+use sdl2::controller::{Axis, Button, GameController};
 use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::GameControllerSubsystem;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+// Duration of a rumble effect. SDL requires one, so use the longest
+// supported: the emulated device turns the motor off explicitly.
+const RUMBLE_MS: u32 = 0xFFFF;
+
 /// PhysicalDevice describes how a device was mapped.
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 enum PhysicalDevice {
     Keyboard,
-    Joystick(String),
+    Joystick(String), // name of the game controller
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,32 +41,88 @@ fn default_scancode_for_kind(kind: InputKind) -> Option<Scancode> {
     }
 }
 
+fn default_button_for_kind(kind: InputKind) -> Option<Button> {
+    use self::InputKind::*;
+    match kind {
+        Start => Some(Button::Start),
+        Select => Some(Button::Back),
+        Up => Some(Button::DPadUp),
+        Down => Some(Button::DPadDown),
+        Left => Some(Button::DPadLeft),
+        Right => Some(Button::DPadRight),
+        Button1 => Some(Button::A),
+        Button2 => Some(Button::B),
+        Button3 => Some(Button::X),
+        Button4 => Some(Button::Y),
+        _ => None,
+    }
+}
+
+fn default_axis_for_kind(kind: InputKind) -> Option<Axis> {
+    match kind {
+        InputKind::Horizontal => Some(Axis::LeftX),
+        InputKind::Vertical => Some(Axis::LeftY),
+        _ => None,
+    }
+}
+
+// Indices of the connected joysticks that are supported as game controllers.
+// There are none if the game controller subsystem is not available.
+fn controller_indices(gc: Option<&GameControllerSubsystem>) -> Vec<u32> {
+    match gc {
+        Some(gc) => (0..gc.num_joysticks().unwrap_or(0))
+            .filter(|&idx| gc.is_game_controller(idx))
+            .collect(),
+        None => vec![],
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct InputConfig {
     devices: HashMap<String, InputDeviceConfig>, // device name => mapped device
 }
 
 impl InputConfig {
-    pub fn default(im: &InputManager) -> InputConfig {
+    /// Default configuration: connected game controllers are mapped to the
+    /// emulated joysticks in order, and the keyboard to the first emulated
+    /// joystick left. Without a game controller subsystem, only the keyboard
+    /// is mapped.
+    pub fn default(im: &InputManager, gc: Option<&GameControllerSubsystem>) -> InputConfig {
         let mut devices = HashMap::new();
+        let mut controllers = controller_indices(gc)
+            .into_iter()
+            .filter_map(|idx| gc?.name_for_index(idx).ok());
         let mut first_joystick = true;
 
         im.visit(|dev| {
             let mut mapping = HashMap::new();
-            if first_joystick && dev.kind() == InputDeviceKind::Joystick {
-                dev.visit(|inp| {
-                    if let Some(scan) = default_scancode_for_kind(inp.kind()) {
-                        let key_name = Keycode::from_scancode(scan).unwrap().name();
-                        mapping.insert(inp.name().to_owned(), key_name);
-                    }
-                });
-                first_joystick = false;
+            let mut phys = PhysicalDevice::Keyboard;
+            if dev.kind() == InputDeviceKind::Joystick {
+                if let Some(name) = controllers.next() {
+                    dev.visit(|inp| {
+                        let ctrl = default_button_for_kind(inp.kind())
+                            .map(|b| b.string())
+                            .or_else(|| default_axis_for_kind(inp.kind()).map(|a| a.string()));
+                        if let Some(ctrl) = ctrl {
+                            mapping.insert(inp.name().to_owned(), ctrl);
+                        }
+                    });
+                    phys = PhysicalDevice::Joystick(name);
+                } else if first_joystick {
+                    dev.visit(|inp| {
+                        if let Some(scan) = default_scancode_for_kind(inp.kind()) {
+                            let key_name = Keycode::from_scancode(scan).unwrap().name();
+                            mapping.insert(inp.name().to_owned(), key_name);
+                        }
+                    });
+                    first_joystick = false;
+                }
             }
 
             devices.insert(
                 dev.name().to_owned(),
                 InputDeviceConfig {
-                    phys: PhysicalDevice::Keyboard,
+                    phys: phys,
                     mapping: mapping,
                 },
             );
@@ -88,12 +150,65 @@ impl InputConfig {
 pub struct InputMapping {
     cfg: InputConfig,
     key_lookup: HashMap<Scancode, (String, String)>,
+    controllers: HashMap<String, GameController>, // device name => controller
+    button_lookup: HashMap<(u32, Button), (String, String)>, // keyed by instance id
+    axis_lookup: HashMap<(u32, Axis), (String, String)>,
 }
 
 impl InputMapping {
-    pub fn new(cfg: InputConfig) -> Self {
-        let key_lookup = cfg.all_keys();
-        Self { cfg, key_lookup }
+    /// Create the input mapping, and open the game controllers it refers
+    /// to. Controllers must be connected when the mapping is created; if the
+    /// game controller subsystem is not available, only the keyboard is used.
+    pub fn new(cfg: InputConfig, gc: Option<&GameControllerSubsystem>) -> Self {
+        let mut map = Self {
+            key_lookup: cfg.all_keys(),
+            cfg,
+            controllers: HashMap::new(),
+            button_lookup: HashMap::new(),
+            axis_lookup: HashMap::new(),
+        };
+
+        let gc = match gc {
+            Some(gc) => gc,
+            None => return map,
+        };
+        let mut free = controller_indices(Some(gc));
+        let joysticks = map
+            .cfg
+            .devices
+            .iter()
+            .filter_map(|(dev_name, d)| match &d.phys {
+                PhysicalDevice::Joystick(name) => Some((dev_name.clone(), name.clone())),
+                PhysicalDevice::Keyboard => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (dev_name, name) in joysticks {
+            let pos = free
+                .iter()
+                .position(|&idx| gc.name_for_index(idx).ok().as_ref() == Some(&name));
+            let ctrl = match pos.map(|pos| gc.open(free.remove(pos))) {
+                Some(Ok(ctrl)) => ctrl,
+                _ => continue,
+            };
+            map.map_controller(&dev_name, ctrl.instance_id());
+            map.controllers.insert(dev_name, ctrl);
+        }
+        map
+    }
+
+    // Route the events of the game controller with the specified instance id
+    // to the emulated device.
+    fn map_controller(&mut self, dev_name: &str, id: u32) {
+        let d = &self.cfg.devices[dev_name];
+        for (inp_name, ctrl_name) in d.mapping.iter() {
+            let target = (dev_name.to_owned(), inp_name.clone());
+            if let Some(button) = Button::from_string(ctrl_name) {
+                self.button_lookup.insert((id, button), target);
+            } else if let Some(axis) = Axis::from_string(ctrl_name) {
+                self.axis_lookup.insert((id, axis), target);
+            }
+        }
     }
 
     pub fn map_event(&self, event: &sdl2::event::Event) -> Option<InputEvent> {
@@ -119,7 +234,178 @@ impl InputMapping {
                 None => None,
             },
 
+            ControllerButtonDown { which, button, .. } => {
+                match self.button_lookup.get(&(*which, *button)) {
+                    Some((dev, inp)) => {
+                        Some(InputEvent::Digital(dev.to_string(), inp.to_string(), true))
+                    }
+                    None => None,
+                }
+            }
+
+            ControllerButtonUp { which, button, .. } => {
+                match self.button_lookup.get(&(*which, *button)) {
+                    Some((dev, inp)) => {
+                        Some(InputEvent::Digital(dev.to_string(), inp.to_string(), false))
+                    }
+                    None => None,
+                }
+            }
+
+            ControllerAxisMotion {
+                which, axis, value, ..
+            } => match self.axis_lookup.get(&(*which, *axis)) {
+                Some((dev, inp)) => {
+                    // SDL reports the stick pushed down as positive.
+                    let value = match axis {
+                        Axis::LeftY | Axis::RightY => {
+                            value.checked_neg().unwrap_or(i16::max_value())
+                        }
+                        _ => *value,
+                    };
+                    Some(InputEvent::Analog(dev.to_string(), inp.to_string(), value))
+                }
+                None => None,
+            },
+
             _ => None,
         }
     }
+
+    /// Forward a feedback event to the physical device mapped to the
+    /// emulated device. Keyboards have no force feedback, so events for
+    /// devices mapped to the keyboard are dropped, and so are those for
+    /// game controllers without rumble support.
+    pub fn process_feedback(&mut self, event: &FeedbackEvent) {
+        match event {
+            FeedbackEvent::Rumble(dev, on) => {
+                if let Some(ctrl) = self.controllers.get_mut(dev) {
+                    let _ = if *on {
+                        ctrl.set_rumble(0xFFFF, 0xFFFF, RUMBLE_MS)
+                    } else {
+                        ctrl.set_rumble(0, 0, 0)
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{Input, InputDevice};
+    use sdl2::event::Event;
+
+    fn input_manager() -> InputManager {
+        let joy = InputDevice::new(
+            "Joy1",
+            InputDeviceKind::Joystick,
+            vec![
+                Input::new_digital("A", InputKind::Button1, 0),
+                Input::new_digital("Start", InputKind::Start, 0),
+                Input::new_analog("X", InputKind::Horizontal, 0),
+                Input::new_analog("Y", InputKind::Vertical, 0),
+            ],
+        );
+        InputManager::new(vec![joy.dup("Joy1"), joy.dup("Joy2")])
+    }
+
+    #[test]
+    fn default_keyboard_only() {
+        // Key names come from the SDL keymap, which is only set up by the
+        // video subsystem.
+        std::env::set_var("SDL_VIDEODRIVER", "dummy");
+        let sdl = sdl2::init().unwrap();
+        let _video = sdl.video().unwrap();
+
+        let cfg = InputConfig::default(&input_manager(), None);
+        assert!(cfg
+            .devices
+            .values()
+            .all(|d| d.phys == PhysicalDevice::Keyboard));
+        assert_eq!(cfg.devices["Joy1"].mapping.len(), 2);
+        assert!(cfg.devices["Joy2"].mapping.is_empty());
+
+        let map = InputMapping::new(cfg, None);
+        let ev = map.map_event(&Event::KeyDown {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(Keycode::Return),
+            scancode: Some(Scancode::Return),
+            keymod: sdl2::keyboard::Mod::empty(),
+            repeat: false,
+        });
+        match ev {
+            Some(InputEvent::Digital(dev, inp, true)) => {
+                assert_eq!((&*dev, &*inp), ("Joy1", "Start"))
+            }
+            ev => panic!("unexpected event: {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn controller_events() {
+        let mut mapping = HashMap::new();
+        mapping.insert("A".to_owned(), Button::A.string());
+        mapping.insert("Y".to_owned(), Axis::LeftY.string());
+        let mut devices = HashMap::new();
+        devices.insert(
+            "Joy2".to_owned(),
+            InputDeviceConfig {
+                phys: PhysicalDevice::Joystick("Pad".to_owned()),
+                mapping,
+            },
+        );
+
+        let mut map = InputMapping::new(InputConfig { devices }, None);
+        map.map_controller("Joy2", 7);
+
+        let ev = map.map_event(&Event::ControllerButtonDown {
+            timestamp: 0,
+            which: 7,
+            button: Button::A,
+        });
+        match ev {
+            Some(InputEvent::Digital(dev, inp, true)) => assert_eq!((&*dev, &*inp), ("Joy2", "A")),
+            ev => panic!("unexpected event: {:?}", ev),
+        }
+        let ev = map.map_event(&Event::ControllerButtonUp {
+            timestamp: 0,
+            which: 7,
+            button: Button::A,
+        });
+        match ev {
+            Some(InputEvent::Digital(dev, inp, false)) => assert_eq!((&*dev, &*inp), ("Joy2", "A")),
+            ev => panic!("unexpected event: {:?}", ev),
+        }
+
+        // The vertical axis is inverted, so that up is positive.
+        let ev = map.map_event(&Event::ControllerAxisMotion {
+            timestamp: 0,
+            which: 7,
+            axis: Axis::LeftY,
+            value: -32768,
+        });
+        match ev {
+            Some(InputEvent::Analog(dev, inp, val)) => {
+                assert_eq!((&*dev, &*inp, val), ("Joy2", "Y", 32767))
+            }
+            ev => panic!("unexpected event: {:?}", ev),
+        }
+
+        // Events from other controllers, or unmapped inputs, are ignored.
+        let ev = map.map_event(&Event::ControllerButtonDown {
+            timestamp: 0,
+            which: 8,
+            button: Button::A,
+        });
+        assert!(ev.is_none());
+        let ev = map.map_event(&Event::ControllerButtonDown {
+            timestamp: 0,
+            which: 7,
+            button: Button::B,
+        });
+        assert!(ev.is_none());
+    }
 }
@@ -166,6 +166,14 @@ pub enum InputEvent {
     Coordinate(String, String, u16),
 }
 
+/// Events sent by the emulated machine back to the input devices (eg:
+/// force feedback), that the host can forward to physical devices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FeedbackEvent {
+    /// Turn the rumble motor of a device on (true) or off (false).
+    Rumble(String, bool),
+}
+
 #[derive(Clone)]
 pub struct InputManager {
     // Devices defined in this input manager. NOTE: it's using
@@ -173,6 +181,7 @@ pub struct InputManager {
     // while iterating.
     devices: IndexMap<String, InputDevice>,
     events: Vec<(usize, InputEvent)>,
+    feedback: Vec<FeedbackEvent>,
     curframe: usize,
 }
 
@@ -184,6 +193,7 @@ impl InputManager {
                 .map(|d| (d.name.clone(), d.clone()))
                 .collect(),
             events: Vec::with_capacity(256),
+            feedback: Vec::new(),
             curframe: 0,
         }
     }
@@ -229,6 +239,16 @@ impl InputManager {
         self.events.push((self.curframe, event));
     }
 
+    /// Queue a feedback event for the host.
+    pub fn send_feedback(&mut self, event: FeedbackEvent) {
+        self.feedback.push(event);
+    }
+
+    /// Return (and clear) the feedback events queued since the last call.
+    pub fn drain_feedback(&mut self) -> Vec<FeedbackEvent> {
+        std::mem::replace(&mut self.feedback, Vec::new())
+    }
+
     pub fn end_frame(&mut self) {
         self.curframe += 1;
     }
@@ -454,6 +454,64 @@ pub type S16BE_MONO = sf<i16, BigEndian, typenum::U1>;
 #[allow(non_camel_case_types)]
 pub type S16BE_STEREO = sf<i16, BigEndian, typenum::U2>;
 
+/// Resampler converts a stream of stereo 16-bit frames from an input
+/// frequency to an output frequency, using linear interpolation.
+///
+/// Both frequencies can be changed at any time, without discontinuities in
+/// the output (eg: when the emulated hardware reprograms its DAC).
+pub struct Resampler {
+    in_freq: f64,
+    out_freq: f64,
+    step: f64,      // input frames per output frame
+    pos: f64,       // position of the next output frame, relative to prev
+    prev: [i16; 2], // last input frame
+}
+
+impl Resampler {
+    pub fn new(in_freq: f64, out_freq: f64) -> Self {
+        Self {
+            in_freq,
+            out_freq,
+            step: in_freq / out_freq,
+            pos: 0.0,
+            prev: [0, 0],
+        }
+    }
+
+    pub fn input_frequency(&self) -> f64 {
+        self.in_freq
+    }
+
+    pub fn output_frequency(&self) -> f64 {
+        self.out_freq
+    }
+
+    pub fn set_input_frequency(&mut self, freq: f64) {
+        self.in_freq = freq;
+        self.step = self.in_freq / self.out_freq;
+    }
+
+    pub fn set_output_frequency(&mut self, freq: f64) {
+        self.out_freq = freq;
+        self.step = self.in_freq / self.out_freq;
+    }
+
+    /// Feed an input frame, appending the output frames that it completes
+    /// (zero or more, depending on the ratio) to `out`, as interleaved
+    /// left/right samples.
+    pub fn push(&mut self, frame: [i16; 2], out: &mut Vec<i16>) {
+        while self.pos < 1.0 {
+            for ch in 0..2 {
+                let (a, b) = (self.prev[ch] as f64, frame[ch] as f64);
+                out.push((a + (b - a) * self.pos).round() as i16);
+            }
+            self.pos += self.step;
+        }
+        self.pos -= 1.0;
+        self.prev = frame;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -555,4 +613,31 @@ mod tests {
         assert_eq!(0x5566, buf2.get_sample(3, 0));
         assert_eq!(0x7788, buf2.get_sample(3, 1));
     }
+
+    #[test]
+    fn resampler() {
+        // Upsample 2x
+        let mut rs = Resampler::new(22050.0, 44100.0);
+        let mut out = Vec::new();
+        rs.push([0, 0], &mut out);
+        rs.push([100, -100], &mut out);
+        rs.push([200, -200], &mut out);
+        assert_eq!(out, vec![0, 0, 0, 0, 0, 0, 50, -50, 100, -100, 150, -150]);
+
+        // Downsample 2x
+        let mut rs = Resampler::new(88200.0, 44100.0);
+        let mut out = Vec::new();
+        for i in 0..8 {
+            rs.push([i * 10, i * 10], &mut out);
+        }
+        assert_eq!(out, vec![0, 0, 10, 10, 30, 30, 50, 50]);
+
+        // Changing frequency keeps the position in the stream.
+        rs.set_input_frequency(44100.0);
+        let mut out = Vec::new();
+        for i in 8..11 {
+            rs.push([i * 10, i * 10], &mut out);
+        }
+        assert_eq!(out, vec![70, 70, 80, 80, 90, 90]);
+    }
 }
@@ -20,6 +20,9 @@ pub struct Config {
     pub vdots: usize,
     pub hsyncs: Vec<usize>,
     pub vsyncs: Vec<usize>,
+    /// Additional dots at the end of each frame, after the last line (eg:
+    /// to model lines of a different length during vertical sync).
+    pub leap_dots: usize,
 }
 
 pub trait Subsystem {
@@ -43,6 +46,9 @@ pub trait Subsystem {
 }
 
 pub trait SyncEmu {
+    /// Return the current timing configuration. This is called again at
+    /// the beginning of each frame, so that emulators can change the timing
+    /// (eg: when the video mode is reprogrammed).
     fn config(&self) -> Config;
     fn subsystem(&self, idx: usize) -> Option<(&mut dyn Subsystem, i64)>;
 }
@@ -107,7 +113,8 @@ impl<E: SyncEmu + 'static> Sync<E> {
 
     fn calc(&mut self) {
         self.line_cycles = self.cfg.dot_clock_divider * self.cfg.hdots as i64;
-        self.frame_cycles = self.line_cycles * self.cfg.vdots as i64;
+        self.frame_cycles = self.line_cycles * self.cfg.vdots as i64
+            + self.cfg.dot_clock_divider * self.cfg.leap_dots as i64;
 
         self.frame_syncs = self
             .cfg
@@ -167,11 +174,15 @@ impl<E: SyncEmu + 'static> Sync<E> {
 
     fn do_frame<F: FnMut(Event)>(&mut self, mut cb: F, tracer: &dbg::Tracer) -> dbg::Result<()> {
         if self.curr_frame.is_none() {
+            let cfg = self.emu.config();
+            if cfg != self.cfg {
+                self.cfg = cfg;
+                self.calc();
+            }
             cb(Event::BeginFrame);
         }
         let (frame_start, idx) = self.curr_frame.unwrap_or((self.cycles, 0));
         let frame_end = frame_start + self.frame_cycles;
-        assert_eq!(frame_start % self.frame_cycles, 0);
 
         for idx in idx..self.frame_syncs.len() {
             self.curr_frame = Some((frame_start, idx));
@@ -183,7 +194,7 @@ impl<E: SyncEmu + 'static> Sync<E> {
             // FIXME: this relies on the fact that this specific HSync event
             // was requested. Find out how to handle more generally.
             match evt {
-                Event::HSync(x, y) if x == 0 => tracer.trace_gpu(y)?,
+                Event::HSync(x, y) if x == 0 => tracer.trace_gpu(dbg::GpuEvent::Line(y))?,
                 _ => {}
             };
         }
@@ -227,14 +238,15 @@ impl<E: SyncEmu + 'static> Sync<E> {
 mod tests {
     use super::*;
     use crate::log::new_console_logger;
+    use std::cell::RefCell;
 
     struct FakeEmu {
-        cfg: Config,
+        cfg: RefCell<Config>,
     }
 
     impl SyncEmu for FakeEmu {
         fn config(&self) -> Config {
-            self.cfg.clone()
+            self.cfg.borrow().clone()
         }
         fn subsystem(&self, _idx: usize) -> Option<(&mut dyn Subsystem, i64)> {
             None
@@ -246,14 +258,15 @@ mod tests {
         let mut sync = Sync::new(
             new_console_logger(),
             FakeEmu {
-                cfg: Config {
+                cfg: RefCell::new(Config {
                     main_clock: 128,
                     dot_clock_divider: 2,
                     hdots: 4,
                     vdots: 4,
                     hsyncs: vec![0, 2],
                     vsyncs: vec![2],
-                },
+                    leap_dots: 0,
+                }),
             },
         );
 
@@ -282,4 +295,43 @@ mod tests {
             events.iter().map(|(_, evt)| *evt).collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn reconfigure() {
+        let mut sync = Sync::new(
+            new_console_logger(),
+            FakeEmu {
+                cfg: RefCell::new(Config {
+                    main_clock: 128,
+                    dot_clock_divider: 2,
+                    hdots: 4,
+                    vdots: 4,
+                    hsyncs: vec![0],
+                    vsyncs: vec![],
+                    leap_dots: 0,
+                }),
+            },
+        );
+
+        sync.run_frame(|_| {});
+        assert_eq!(sync.cycles(), 32);
+
+        // The new configuration is picked up at the beginning of next frame.
+        sync.emu.cfg.borrow_mut().vdots = 2;
+        sync.emu.cfg.borrow_mut().leap_dots = 3;
+        let mut record = Vec::new();
+        sync.run_frame(|evt| {
+            record.push(evt);
+        });
+        assert_eq!(
+            record,
+            vec![
+                Event::BeginFrame,
+                Event::HSync(0, 0),
+                Event::HSync(0, 1),
+                Event::EndFrame
+            ]
+        );
+        assert_eq!(sync.cycles(), 32 + 16 + 6);
+    }
 }
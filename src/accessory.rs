@@ -0,0 +1,135 @@
+//! Controller accessories, plugged into the expansion slot of a controller
+//! and accessed through the joybus accessory read/write commands.
+use crate::errors::*;
+use crate::mempak::Mempak;
+use crate::transferpak::TransferPak;
+
+use std::str::FromStr;
+
+/// Accessories are read and written in blocks of 32 bytes.
+pub const BLOCK_SIZE: usize = 32;
+
+pub enum Accessory {
+    Mempak(Mempak),
+    Rumble(RumblePak),
+    Transfer(TransferPak),
+}
+
+impl Accessory {
+    pub fn read(&mut self, addr: u16, out: &mut [u8]) {
+        match self {
+            Accessory::Mempak(pak) => pak.read(addr, out),
+            Accessory::Rumble(pak) => pak.read(addr, out),
+            Accessory::Transfer(pak) => pak.read(addr, out),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: &[u8]) {
+        match self {
+            Accessory::Mempak(pak) => pak.write(addr, data),
+            Accessory::Rumble(pak) => pak.write(addr, data),
+            Accessory::Transfer(pak) => pak.write(addr, data),
+        }
+    }
+
+    /// Current state of the rumble motor (always off, for accessories
+    /// other than the Rumble Pak).
+    pub fn motor(&self) -> bool {
+        match self {
+            Accessory::Rumble(pak) => pak.motor(),
+            _ => false,
+        }
+    }
+
+    /// Write the accessory contents to disk, if it has any.
+    pub fn flush(&mut self) -> Result<()> {
+        match self {
+            Accessory::Mempak(pak) => pak.flush(),
+            Accessory::Rumble(_) => Ok(()),
+            Accessory::Transfer(pak) => pak.flush(),
+        }
+    }
+
+    /// Called once per frame.
+    pub fn end_frame(&mut self) -> Result<()> {
+        match self {
+            Accessory::Mempak(pak) => pak.autosave(),
+            Accessory::Rumble(_) => Ok(()),
+            Accessory::Transfer(pak) => pak.end_frame(),
+        }
+    }
+}
+
+/// Kind of accessory plugged into a controller, as selected by the user.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PakType {
+    None,
+    Mempak,
+    Rumble,
+    Transfer,
+}
+
+impl FromStr for PakType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(PakType::None),
+            "mempak" => Ok(PakType::Mempak),
+            "rumble" => Ok(PakType::Rumble),
+            "transfer" => Ok(PakType::Transfer),
+            _ => Err(format!("invalid accessory: {}", s)),
+        }
+    }
+}
+
+/// Rumble Pak: a motor that is turned on and off by writing to 0xC000.
+/// Games detect it by reading back 0x80 from 0x8000.
+#[derive(Default)]
+pub struct RumblePak {
+    motor: bool,
+}
+
+impl RumblePak {
+    pub fn new() -> RumblePak {
+        RumblePak::default()
+    }
+
+    pub fn motor(&self) -> bool {
+        self.motor
+    }
+
+    pub fn read(&self, addr: u16, out: &mut [u8]) {
+        let val = match addr {
+            0x8000..=0x8FFF => 0x80,
+            _ => 0x00,
+        };
+        for v in out.iter_mut() {
+            *v = val;
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: &[u8]) {
+        if let 0xC000..=0xCFFF = addr {
+            self.motor = data[data.len() - 1] != 0;
+        }
+    }
+}
+
+/// CRC of a data block, returned by the controller after every accessory
+/// read and write (polynomial 0x85). The game uses it to detect transfer
+/// errors, and to tell if an accessory is connected.
+pub fn data_crc(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for i in 0..=data.len() {
+        for bit in (0..8).rev() {
+            let xor = if crc & 0x80 != 0 { 0x85 } else { 0x00 };
+            crc <<= 1;
+            if i < data.len() && data[i] & (1 << bit) != 0 {
+                crc |= 1;
+            }
+            crc ^= xor;
+        }
+    }
+    crc
+}
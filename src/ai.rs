@@ -1,19 +1,120 @@
 use super::mi::{IrqMask, Mi};
 use super::r4300::R4300;
+use super::vi::Vi;
 use emu::bus::be::{Device, Reg32};
 use emu::dbg;
 use emu::int::Numerics;
-use emu::snd::{SampleFormat, SampleInt, SndBuffer, SndBufferMut, S16_STEREO};
-use emu::state::{ArrayField, Field};
+use emu::snd::{Resampler, SampleFormat, SampleInt, SndBuffer, SndBufferMut, S16_STEREO};
+use emu::state::Field;
 use emu::sync;
 use emu_derive::DeviceBE;
 use serde_derive::{Deserialize, Serialize};
 
+/// AudioFifo models the DMA FIFO of the AI: the buffer being played, plus
+/// one pending buffer queued behind it.
 #[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
-struct AudioFifo {
-    src: u32,   // Source RDRAM address of sample data
-    len: u32,   // Source length of sample data in bytes
-    full: bool, // True if this AudioFifo is full (not empty)
+pub struct AudioFifo {
+    addr: [u32; 2], // RDRAM address of the next frame of each buffer
+    len: [u32; 2],  // Remaining length in bytes of each buffer
+    count: usize,   // Number of buffers in the FIFO (0-2)
+    carry: bool,    // Carry into the upper address bits (see pop())
+}
+
+impl AudioFifo {
+    /// Queue a new buffer. Returns true if the buffer started playing
+    /// immediately (because the FIFO was empty), which raises the AI
+    /// interrupt. Buffers are ignored if the FIFO is full, or if their
+    /// length is zero (the bottom 3 bits are ignored).
+    pub fn push(&mut self, addr: u32, len: u32) -> bool {
+        let len = len & 0x3_FFF8;
+        if len == 0 || self.full() {
+            return false;
+        }
+        let idx = self.count;
+        self.addr[idx] = addr & 0xFF_FFF8;
+        self.len[idx] = len;
+        self.count += 1;
+        if idx == 0 {
+            self.carry = false;
+            return true;
+        }
+        false
+    }
+
+    /// Fetch the next frame (32 bits) of the current buffer. Returns its
+    /// RDRAM address, and whether the pending buffer started playing because
+    /// the current one was finished (which raises the AI interrupt).
+    pub fn pop(&mut self) -> Option<(u32, bool)> {
+        if self.count == 0 {
+            return None;
+        }
+        let addr = self.addr[0];
+
+        // Hardware bug: the address is incremented by a 13-bit adder, and the
+        // carry into the upper bits is applied one frame late. So when a
+        // buffer crosses a 8KB boundary, the first frame after it is fetched
+        // from the beginning of the previous 8KB block.
+        let lo = (addr + 4) & 0x1FFF;
+        let hi = (addr & 0xFF_E000) + if self.carry { 0x2000 } else { 0 };
+        self.addr[0] = (hi | lo) & 0xFF_FFFF;
+        self.carry = lo == 0;
+        self.len[0] -= 4;
+
+        let mut irq = false;
+        if self.len[0] == 0 {
+            self.count -= 1;
+            if self.count != 0 {
+                self.addr[0] = self.addr[1];
+                self.len[0] = self.len[1];
+                self.carry = false;
+                irq = true;
+            }
+        }
+        Some((addr, irq))
+    }
+
+    /// True if no more buffers can be queued.
+    pub fn full(&self) -> bool {
+        self.count == 2
+    }
+
+    /// True if a buffer is being played.
+    pub fn busy(&self) -> bool {
+        self.count != 0
+    }
+
+    /// Address of the next frame of the current buffer.
+    pub fn addr(&self) -> u32 {
+        self.addr[0]
+    }
+
+    /// Remaining length of the current buffer.
+    pub fn len(&self) -> u32 {
+        if self.busy() {
+            self.len[0]
+        } else {
+            0
+        }
+    }
+}
+
+/// Return the number of VCLK cycles between two frames converted by the DAC,
+/// given the values of the DAC rate and bit rate registers, or None if the
+/// DAC clock is stopped (bit rate zero).
+///
+/// Frames are shifted to the DAC over a serial bus clocked at
+/// VCLK/(2*(bit_rate+1)), and a frame takes 33 cycles of this clock (which is
+/// where the (dac_rate+1) >= 66*(bit_rate+1) requirement comes from). When the
+/// requirement is met, as libultra ensures, the bit rate has no effect on the
+/// pitch. Otherwise the DAC has to wait for the transfer of each frame, and
+/// the bus clock sets the sample rate.
+pub fn dac_period(dac_rate: u32, bit_rate: u32) -> Option<i64> {
+    if bit_rate == 0 {
+        return None;
+    }
+    let period = dac_rate as i64 + 1;
+    let transfer = 66 * (bit_rate as i64 + 1);
+    Some(period.max(transfer))
 }
 
 #[derive(DeviceBE)]
@@ -24,7 +125,8 @@ pub struct Ai {
 
     // [14:0] transfer length (v1.0) - Bottom 3 bits are ignored
     // [17:0] transfer length (v2.0) - Bottom 3 bits are ignored
-    #[reg(bank = 0, offset = 0x04, rwmask = 0x3FFF8, wcb)]
+    // (R): remaining length of the current transfer
+    #[reg(bank = 0, offset = 0x04, rwmask = 0x3FFF8, wcb, rcb)]
     reg_length: Reg32,
 
     // (W): [0] DMA enable - if LSB == 1, DMA is enabled
@@ -33,9 +135,10 @@ pub struct Ai {
 
     // (R): [31]/[0] ai_full (addr & len buffer full)
     //      [30] ai_busy
-    //      Note that a 1to0 transition in ai_full will set interrupt
+    //      [25] DMA enabled
+    //      [24], [20] always set
     // (W): clear audio interrupt
-    #[reg(bank = 0, offset = 0x0C, wcb)]
+    #[reg(bank = 0, offset = 0x0C, wcb, rcb)]
     reg_status: Reg32,
 
     // (W): [13:0] dac rate
@@ -50,14 +153,16 @@ pub struct Ai {
     #[reg(bank = 0, offset = 0x14, rwmask = 0xF, writeonly)]
     reg_bit_rate: Reg32,
 
-    fifo: ArrayField<AudioFifo>,
-    fifo_cur: Field<usize>,
+    fifo: Field<AudioFifo>,
     cycles: Field<i64>,
 
     // Internal sound buffer for the current frame. We're not storing this in
     // the state right now, so after reload there might be some missing samples.
     sndbuffer: Vec<i16>,
 
+    // Conversion from the DAC sample rate to OUTPUT_FREQUENCY.
+    resampler: Resampler,
+
     logger: slog::Logger,
 }
 
@@ -72,69 +177,51 @@ impl Ai {
             reg_status: Reg32::default(),
             reg_dac_sample_period: Reg32::default(),
             reg_bit_rate: Reg32::default(),
-            fifo: ArrayField::new("Ai::fifo", AudioFifo::default(), 2),
-            fifo_cur: Field::new("Ai::fifo_cur", 0),
+            fifo: Field::new("Ai::fifo", AudioFifo::default()),
             cycles: Field::new("Ai::cycles", 0),
             sndbuffer: Vec::new(),
+            resampler: Resampler::new(Self::OUTPUT_FREQUENCY as f64, Self::OUTPUT_FREQUENCY as f64),
             logger,
         })
     }
 
-    fn update_status(&mut self) {
-        let mut status = self.reg_status.as_ref::<u32>();
-        if self.fifo[0].full && self.fifo[1].full {
-            *status |= 1 << 31;
-        } else {
-            if *status & (1 << 31) != 0 {
-                // 1-to-0 transition of full bit triggers an interrupt
-                Mi::get_mut().set_irq_line(IrqMask::AI, true);
-                info!(self.logger, "audio fifo slot available, trigger IRQ");
-            }
-            *status &= !(1 << 31);
-        }
-        if self.fifo[0].full || self.fifo[1].full {
-            *status |= 1 << 30;
-        } else {
-            if (*status & (1 << 30)) != 0 {
-                info!(self.logger, "DMA finished");
-            }
-            *status &= !(1 << 30);
-        }
-    }
-
-    fn cb_write_reg_length(&mut self, _old: u32, _new: u32) {
+    fn cb_write_reg_length(&mut self, _old: u32, new: u32) {
         let src = self.reg_dram_address.get();
-        let len = self.reg_length.get();
-
-        // Do not start DMA when len==0. TODO: verify
-        if len == 0 {
+        if self.fifo.full() {
+            error!(self.logger, "audio fifo overflow");
             return;
         }
 
-        let mut widx = *self.fifo_cur;
-        if self.fifo[widx].full {
-            widx ^= 1;
-            if self.fifo[widx].full {
-                error!(self.logger, "audio fifo overflow");
-                return;
-            }
+        info!(self.logger, "start DMA"; "src" => src.hex(), "len" => new);
+        if self.fifo.push(src, new) {
+            // The buffer started playing immediately
+            Mi::get_mut().set_irq_line(IrqMask::AI, true);
         }
+    }
 
-        info!(self.logger, "start DMA"; "src" => src.hex(), "len" => len);
-        self.fifo[widx] = AudioFifo {
-            src,
-            len,
-            full: true,
-        };
-        self.update_status();
+    fn cb_read_reg_length(&self, _old: u32) -> u32 {
+        self.fifo.len()
     }
 
     fn cb_write_reg_control(&self, _old: u32, new: u32) {
         info!(self.logger, "written reg_control"; "val" => new.hex());
     }
 
-    fn cb_write_reg_status(&mut self, old: u32, _new: u32) {
-        self.reg_status.set(old);
+    fn cb_read_reg_status(&self, _old: u32) -> u32 {
+        let mut status = (1 << 24) | (1 << 20);
+        if self.fifo.full() {
+            status |= (1 << 31) | 1;
+        }
+        if self.fifo.busy() {
+            status |= 1 << 30;
+        }
+        if self.reg_control.get() & 1 != 0 {
+            status |= 1 << 25;
+        }
+        status
+    }
+
+    fn cb_write_reg_status(&mut self, _old: u32, _new: u32) {
         Mi::get_mut().set_irq_line(IrqMask::AI, false);
         info!(self.logger, "IRQ acknowledge");
     }
@@ -162,49 +249,37 @@ impl sync::Subsystem for Ai {
 
     fn run(&mut self, target_cycles: i64, _tracer: &dbg::Tracer) -> dbg::Result<()> {
         debug!(self.logger, "AI run";
-            "fifo" => ?self.fifo[*self.fifo_cur],
-            "other" => ?self.fifo[*self.fifo_cur^1],
-            "cur" => *self.fifo_cur,
+            "fifo" => ?*self.fifo,
             "period" => self.reg_dac_sample_period.get());
-        let audioframe_bitsize = self.reg_bit_rate.get() + 1;
+        // The DAC converts one stereo 16-bit frame every period (see
+        // dac_period). While its clock is stopped, time still advances at the
+        // programmed DAC rate, but no sample is converted. DMA transfers also
+        // only progress while enabled.
+        let dac_rate = self.reg_dac_sample_period.get();
+        let (period, running) = match dac_period(dac_rate, self.reg_bit_rate.get()) {
+            Some(period) => (period, true),
+            None => (dac_rate as i64 + 1, false),
+        };
+        let enabled = self.reg_control.get() & 1 != 0 && running;
+        let vclk = Vi::get().region().vclk();
+        self.resampler.set_input_frequency(vclk as f64 / period as f64);
 
         while *self.cycles < target_cycles {
-            let fifo = &mut self.fifo[*self.fifo_cur];
-            if fifo.full {
-                // One DMA step: consume one frame of audio
-                match audioframe_bitsize {
-                    16 => {
-                        let sample = R4300::get().bus.read::<u32>(fifo.src);
-                        let left = (sample >> 16) as i16;
-                        let right = (sample & 0xFFFF) as i16;
-                        self.sndbuffer.push(left.sconv());
-                        self.sndbuffer.push(right.sconv());
+            let next = if enabled { self.fifo.pop() } else { None };
+            let frame = match next {
+                Some((addr, irq)) => {
+                    if irq {
+                        // The pending buffer started playing
+                        Mi::get_mut().set_irq_line(IrqMask::AI, true);
                     }
-                    _ => unimplemented!(),
+                    let sample = R4300::get().bus.read::<u32>(addr);
+                    [(sample >> 16) as i16, (sample & 0xFFFF) as i16]
                 }
-
-                // Update DMA source and length
-                fifo.src += (audioframe_bitsize / 8) * 2;
-                fifo.len = fifo.len.checked_sub((audioframe_bitsize / 8) * 2).unwrap();
-
-                // End of buffer? If so, switch to other buffer.
-                if fifo.len == 0 {
-                    fifo.full = false;
-                    *self.fifo_cur ^= 1;
-                }
-            } else {
-                self.sndbuffer.push(i16::MUTE);
-                self.sndbuffer.push(i16::MUTE);
-            }
-            *self.cycles += self.reg_dac_sample_period.get() as i64 + 1;
+                None => [i16::MUTE, i16::MUTE],
+            };
+            self.resampler.push(frame, &mut self.sndbuffer);
+            *self.cycles += period;
         }
-
-        // Update also user-visibile registers (that reflect current FIFO)
-        let fifo = &self.fifo[*self.fifo_cur];
-        self.reg_dram_address.set(fifo.src);
-        self.reg_length.set(fifo.len);
-
-        self.update_status();
         Ok(())
     }
     fn step(&mut self, _tracer: &dbg::Tracer) -> dbg::Result<()> {
@@ -0,0 +1,48 @@
+#[macro_use]
+extern crate error_chain;
+
+use emu::log;
+use image::png::PNGEncoder;
+use image::ColorType;
+use r64emu::dpcap::DpReplay;
+use r64emu::errors::*;
+use std::fs::File;
+
+use structopt::StructOpt;
+
+/// Replay a RDP capture file (created with --dp-capture) and save the
+/// resulting framebuffer as PNG.
+#[derive(StructOpt)]
+#[structopt(raw(setting = "structopt::clap::AppSettings::ColoredHelp"))]
+struct Cli {
+    /// Path to the capture file
+    #[structopt(parse(from_os_str))]
+    capture: std::path::PathBuf,
+
+    /// Path to the output PNG file
+    #[structopt(parse(from_os_str), default_value = "dpreplay.png")]
+    output: std::path::PathBuf,
+}
+
+quick_main!(run);
+
+fn run() -> Result<()> {
+    let args = Cli::from_args();
+
+    let mut replay = DpReplay::new(log::new_console_logger());
+    replay
+        .run_file(&args.capture)
+        .chain_err(|| "error replaying capture file")?;
+
+    let mut fb = replay.framebuffer()?;
+    let (width, height) = (fb.width(), fb.height());
+    let mut buf = fb.buf_mut();
+    let (raw, _pitch) = buf.raw();
+    PNGEncoder::new(File::create(&args.output)?).encode(
+        raw,
+        width as u32,
+        height as u32,
+        ColorType::RGBA(8),
+    )?;
+    Ok(())
+}
@@ -1,10 +1,15 @@
 use crate::errors::*;
+use crate::n64::Region;
 use emu::bus::be::{Mem, MemFlags, Reg32};
+use emu::dbg::InfoView;
 
 use crc::crc32;
+use flate2::read::GzDecoder;
 use std::fs::File;
-use std::io::Read;
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Cursor, Read};
 use std::path::Path;
+use std::str::FromStr;
 
 #[derive(DeviceBE)]
 pub struct Cartridge {
@@ -16,8 +21,11 @@ pub struct Cartridge {
 
     #[reg(bank = 1, offset = 0x208)]
     drive64_cmd: Reg32,
+
+    header: RomHeader,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CicModel {
     Cic6101 = 6101,
     Cic6102 = 6102,
@@ -26,38 +34,284 @@ pub enum CicModel {
     Cic6106 = 6106,
 }
 
-pub fn romswap(rom: Vec<u8>) -> Vec<u8> {
-    if rom[0] == 0x80 {
-        // ROM is big-endian: nothing to do
-        return rom;
-    } else if rom[1] == 0x80 {
-        // ROM is byteswapped
-        return rom
-            .iter()
-            .enumerate()
-            .map(|(idx, _)| rom[idx ^ 1])
-            .collect();
-    } else {
-        panic!("unsupported ROM format")
+/// Convert a ROM image to big-endian (.z64) byte order. The current byte
+/// order is detected through the position of the 0x80 byte that starts
+/// every ROM header (.z64: 80 37 12 40, .v64: 37 80 40 12, .n64: 40 12 37 80).
+pub fn romswap(mut rom: Vec<u8>) -> Result<Vec<u8>> {
+    if rom.len() < 0x1000 {
+        bail!("ROM image is too small ({} bytes)", rom.len());
+    }
+    rom.resize((rom.len() + 3) & !3, 0);
+
+    match rom[..4] {
+        [0x80, _, _, _] => {}
+        [_, 0x80, _, _] => rom.chunks_mut(2).for_each(|w| w.swap(0, 1)),
+        [_, _, _, 0x80] => rom.chunks_mut(4).for_each(|w| w.reverse()),
+        _ => bail!(
+            "unrecognized ROM header: {:02x} {:02x} {:02x} {:02x}",
+            rom[0],
+            rom[1],
+            rom[2],
+            rom[3]
+        ),
+    }
+    Ok(rom)
+}
+
+fn is_rom_header(magic: &[u8; 4]) -> bool {
+    match magic {
+        [0x80, 0x37, 0x12, 0x40] | [0x37, 0x80, 0x40, 0x12] | [0x40, 0x12, 0x37, 0x80] => true,
+        _ => false,
+    }
+}
+
+/// Extract a ROM image from a container (gzip or zip archive), if any.
+/// The container format is detected through its magic number; data which
+/// is not in a known container is returned as-is.
+pub fn unpack(data: Vec<u8>) -> Result<Vec<u8>> {
+    let mut rom = vec![];
+    match data.get(..4) {
+        Some([0x1f, 0x8b, _, _]) => {
+            GzDecoder::new(&data[..])
+                .read_to_end(&mut rom)
+                .chain_err(|| "cannot decompress gzip ROM")?;
+        }
+        Some(b"PK\x03\x04") => {
+            let mut zip =
+                zip::ZipArchive::new(Cursor::new(data)).chain_err(|| "cannot open zip archive")?;
+
+            // Prefer a file with a ROM extension; otherwise, pick the first
+            // file whose contents look like a ROM header.
+            let mut found = None;
+            for idx in 0..zip.len() {
+                let mut file = zip.by_index(idx).chain_err(|| "cannot read zip archive")?;
+                let name = file.name().to_lowercase();
+                if name.ends_with('/') {
+                    continue;
+                }
+                if [".z64", ".v64", ".n64"].iter().any(|ext| name.ends_with(ext)) {
+                    found = Some(idx);
+                    break;
+                }
+                let mut magic = [0u8; 4];
+                if found.is_none() && file.read_exact(&mut magic).is_ok() && is_rom_header(&magic) {
+                    found = Some(idx);
+                }
+            }
+
+            let idx = found.ok_or("no ROM found in zip archive")?;
+            zip.by_index(idx)
+                .chain_err(|| "cannot read zip archive")?
+                .read_to_end(&mut rom)
+                .chain_err(|| "cannot decompress ROM from zip archive")?;
+        }
+        _ => return Ok(data),
+    }
+    Ok(rom)
+}
+
+/// Load a ROM image from a file, extracting it from its container (if any)
+/// and converting it to big-endian byte order.
+pub fn load_rom(romfn: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(romfn)?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents)?;
+
+    romswap(unpack(contents)?).chain_err(|| format!("invalid ROM file: {}", romfn.display()))
+}
+
+/// Save memory type of a cartridge.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SaveType {
+    None,
+    Eeprom4k,
+    Eeprom16k,
+    Sram32k,
+    Sram96k,
+    FlashRam,
+}
+
+impl FromStr for SaveType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SaveType::None),
+            "eeprom4k" => Ok(SaveType::Eeprom4k),
+            "eeprom16k" => Ok(SaveType::Eeprom16k),
+            "sram32k" => Ok(SaveType::Sram32k),
+            "sram96k" => Ok(SaveType::Sram96k),
+            "flashram" => Ok(SaveType::FlashRam),
+            _ => Err(format!("invalid save type: {}", s)),
+        }
+    }
+}
+
+/// Metadata parsed from the 64-byte header at the beginning of a ROM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RomHeader {
+    /// Initial value of the PI domain 1 timing registers.
+    pub pi_config: u32,
+    /// CPU clock rate override (0 = default).
+    pub clock_rate: u32,
+    /// Address where IPL3 jumps to (game entrypoint).
+    pub entrypoint: u32,
+    /// Version of libultra the game was built with (eg: 0x1444 = "2.0D").
+    pub libultra: u32,
+    pub crc1: u32,
+    pub crc2: u32,
+    /// Internal name of the game (trimmed).
+    pub title: String,
+    /// Four-character game code: media format, game ID, country code
+    /// (eg: "NSME" for Super Mario 64, USA).
+    pub game_code: String,
+    /// Revision of the game.
+    pub version: u8,
+}
+
+impl RomHeader {
+    /// Parse the header of a big-endian ROM image.
+    pub fn parse(rom: &[u8]) -> RomHeader {
+        let text = |data: &[u8]| {
+            data.iter()
+                .map(|&c| if c >= 0x20 && c < 0x7F { c as char } else { ' ' })
+                .collect::<String>()
+                .trim()
+                .to_owned()
+        };
+        RomHeader {
+            pi_config: BigEndian::read_u32(&rom[0x00..]),
+            clock_rate: BigEndian::read_u32(&rom[0x04..]),
+            entrypoint: BigEndian::read_u32(&rom[0x08..]),
+            libultra: BigEndian::read_u32(&rom[0x0C..]),
+            crc1: BigEndian::read_u32(&rom[0x10..]),
+            crc2: BigEndian::read_u32(&rom[0x14..]),
+            title: text(&rom[0x20..0x34]),
+            game_code: text(&rom[0x3B..0x3F]),
+            version: rom[0x3F],
+        }
+    }
+
+    /// Country code (last letter of the game code).
+    pub fn country(&self) -> char {
+        self.game_code.chars().nth(3).unwrap_or(' ')
+    }
+
+    /// Human-readable libultra version (eg: "2.0D").
+    pub fn libultra_version(&self) -> String {
+        let ver = (self.libultra >> 8) as u8;
+        let rev = self.libultra as u8;
+        if rev.is_ascii_uppercase() {
+            format!("{}.{}{}", ver / 10, ver % 10, rev as char)
+        } else {
+            format!("{}.{}", ver / 10, ver % 10)
+        }
+    }
+
+    /// Console region the game was released for, as deduced by the
+    /// country code.
+    pub fn region(&self) -> Region {
+        match self.country() {
+            'D' | 'F' | 'H' | 'I' | 'L' | 'P' | 'S' | 'U' | 'W' | 'X' | 'Y' | 'Z' => Region::Pal,
+            'B' => Region::Mpal,
+            _ => Region::Ntsc,
+        }
+    }
+
+    /// Save type declared in the header. Only homebrew ROMs using the
+    /// "advanced homebrew header" (game ID "ED") declare it; for all other
+    /// ROMs, this returns None.
+    pub fn save_type(&self) -> Option<SaveType> {
+        if self.game_code.get(1..3) != Some("ED") {
+            return None;
+        }
+        match self.version >> 4 {
+            0 => Some(SaveType::None),
+            1 => Some(SaveType::Eeprom4k),
+            2 => Some(SaveType::Eeprom16k),
+            3 => Some(SaveType::Sram32k),
+            4 => Some(SaveType::Sram96k),
+            5 => Some(SaveType::FlashRam),
+            _ => None,
+        }
+    }
+}
+
+/// Compute the ROM checksum (CRC1, CRC2), as verified by IPL3 at boot.
+/// The algorithm covers the first megabyte after IPL3, and differs slightly
+/// for each CIC model.
+pub fn rom_crc(rom: &[u8], cic: CicModel) -> (u32, u32) {
+    let read = |off: usize| rom.get(off..off + 4).map_or(0, BigEndian::read_u32);
+    let seed: u32 = match cic {
+        CicModel::Cic6101 | CicModel::Cic6102 => 0xF8CA_4DDC,
+        CicModel::Cic6103 => 0xA388_6759,
+        CicModel::Cic6105 => 0xDF26_F436,
+        CicModel::Cic6106 => 0x1FEA_617A,
+    };
+
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+    for off in (0x1000..0x10_1000).step_by(4) {
+        let d = read(off);
+        let (sum, carry) = t6.overflowing_add(d);
+        if carry {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = sum;
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+        if cic == CicModel::Cic6105 {
+            t1 = t1.wrapping_add(read(0x0750 + (off & 0xFF)) ^ d);
+        } else {
+            t1 = t1.wrapping_add(t5 ^ d);
+        }
+    }
+
+    match cic {
+        CicModel::Cic6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        CicModel::Cic6106 => (
+            t6.wrapping_mul(t4).wrapping_add(t3),
+            t5.wrapping_mul(t2).wrapping_add(t1),
+        ),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
     }
 }
 
 impl Cartridge {
     pub fn new(romfn: &Path) -> Result<Box<Cartridge>> {
-        let mut file = File::open(romfn)?;
-        let mut contents = vec![];
-        file.read_to_end(&mut contents)?;
+        Ok(Cartridge::from_image(load_rom(romfn)?))
+    }
 
+    /// Create a cartridge from a ROM image already loaded with
+    /// [`load_rom`](fn.load_rom.html).
+    pub fn from_image(mut contents: Vec<u8>) -> Box<Cartridge> {
+        let header = RomHeader::parse(&contents);
         if !contents.len().is_power_of_two() {
             let newsize = contents.len().next_power_of_two();
             contents.resize(newsize, 0xff);
         }
 
-        Ok(Box::new(Cartridge {
+        Box::new(Cartridge {
             drive64_status: Reg32::default(),
             drive64_cmd: Reg32::default(),
-            rom: Mem::from_buffer("rom", romswap(contents), MemFlags::READACCESS),
-        }))
+            rom: Mem::from_buffer("rom", contents, MemFlags::READACCESS),
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &RomHeader {
+        &self.header
+    }
+
+    /// Verify the checksum stored in the ROM header, using the algorithm of
+    /// the specified CIC model.
+    pub fn verify_crc(&self, cic: CicModel) -> bool {
+        rom_crc(&self.rom, cic) == (self.header.crc1, self.header.crc2)
     }
 
     // Detect the CIC model by checksumming the header of the ROM.
@@ -72,3 +326,32 @@ impl Cartridge {
         }
     }
 }
+
+impl InfoView for Cartridge {
+    fn name(&self) -> &str {
+        "Cartridge"
+    }
+
+    fn visit_info<F>(&self, mut visit: F)
+    where
+        F: for<'a> FnMut(&'a str, &'a str),
+    {
+        let h = &self.header;
+        visit("Title", &h.title);
+        visit("Game code", &h.game_code);
+        visit("Version", &format!("1.{}", h.version));
+        visit("Region", &format!("{:?}", h.region()));
+        visit("Entrypoint", &format!("{:08X}", h.entrypoint));
+        visit("Clock rate", &format!("{:08X}", h.clock_rate));
+        visit("Libultra", &h.libultra_version());
+        visit("CRC", &format!("{:08X} {:08X}", h.crc1, h.crc2));
+        match self.detect_cic_model() {
+            Ok(cic) => {
+                visit("CIC", &format!("{}", cic as u32));
+                let ok = self.verify_crc(cic);
+                visit("CRC check", if ok { "ok" } else { "MISMATCH" });
+            }
+            Err(_) => visit("CIC", "unknown"),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! CIC-NUS: the lockout chip found in each cartridge. PIF talks to it at
+//! boot to obtain the seeds that IPL2/IPL3 use to verify the cartridge,
+//! and later on request of the game (challenge/response).
+use crate::cartridge::CicModel;
+
+/// Seed sent by the CIC to PIF at boot. PAL CICs (710x) use the same seeds
+/// as their NTSC counterparts.
+pub fn seed(cic: CicModel) -> u8 {
+    match cic {
+        CicModel::Cic6101 => 0x3F, // starfox
+        CicModel::Cic6102 => 0x3F, // mario
+        CicModel::Cic6103 => 0x78, // banjo
+        CicModel::Cic6105 => 0x91, // zelda
+        CicModel::Cic6106 => 0x85, // f-zero x
+    }
+}
+
+/// Word written by PIF at offset 0x24 of PIF RAM during boot, after the
+/// negotiation with the CIC. It is read by IPL3 and by the game.
+///
+/// bits     | reg | description
+/// 00080000 | S3  | osRomType (0=GamePack, 1=DD)
+/// 00040000 | S7  | osVersion
+/// 00020000 | S5  | osResetType (1 = NMI, 0 = cold reset)
+/// 0000FF00 | S6  | CIC IPL3 seed value
+/// 000000FF | --  | CIC IPL2 seed value
+pub fn boot_info(cic: CicModel, hard_reset: bool) -> u32 {
+    let seed = seed(cic) as u32;
+    let mut info = (seed << 8) | seed;
+    if !hard_reset {
+        info |= 0x0002_0000;
+    }
+    info
+}
+
+/// Compute the response of the CIC to a challenge sent by the game. Both
+/// are made of 30 nibbles, packed into 15 bytes.
+///
+/// Only the CIC-6105 (and its PAL version, 7105) implements the challenge;
+/// the other models do not answer, and no game using them sends it.
+pub fn challenge(cic: CicModel, challenge: &[u8; 15]) -> Option<[u8; 15]> {
+    if cic != CicModel::Cic6105 {
+        return None;
+    }
+
+    const LUT0: [u8; 16] = [
+        0x4, 0x7, 0xA, 0x7, 0xE, 0x5, 0xE, 0x1, 0xC, 0xF, 0x8, 0xF, 0x6, 0x3, 0x6, 0x9,
+    ];
+    const LUT1: [u8; 16] = [
+        0x4, 0x1, 0xA, 0x7, 0xE, 0x5, 0xE, 0x1, 0xC, 0x9, 0x8, 0x5, 0x6, 0x3, 0xC, 0x9,
+    ];
+
+    let mut response = [0u8; 15];
+    let mut key = 0xB;
+    let mut lut1 = false;
+    for i in 0..30 {
+        let chl = (challenge[i / 2] >> (4 - (i % 2) * 4)) & 0xF;
+        let rsp = (key + 5 * chl) & 0xF;
+        response[i / 2] |= rsp << (4 - (i % 2) * 4);
+
+        let lut = if lut1 { &LUT1 } else { &LUT0 };
+        key = lut[rsp as usize];
+        let sgn = (rsp >> 3) & 1;
+        let mag = (if sgn == 1 { !rsp } else { rsp }) & 7;
+        let mut sel = if mag % 3 == 1 { sgn } else { 1 - sgn };
+        if lut1 && (rsp == 0x1 || rsp == 0x9) {
+            sel = 1;
+        }
+        if lut1 && (rsp == 0xB || rsp == 0xE) {
+            sel = 0;
+        }
+        lut1 = sel == 1;
+    }
+    Some(response)
+}
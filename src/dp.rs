@@ -2,14 +2,20 @@ extern crate bit_field;
 extern crate byteorder;
 extern crate emu;
 extern crate slog;
+use self::bit_field::BitField;
+use super::dpcap::DpCapture;
+use super::errors::*;
 use super::mi::{IrqMask, Mi};
 use super::r4300::R4300;
-use super::rdp::Rdp;
+use super::rdp::{command_name, command_words, COMMAND_NAMES};
+use super::rdp::{Rdp, RdramPtr, TmemView};
 use super::sp::RSPCPU;
 use emu::bus::be::{Device, MemIoR, Reg32, RegDeref, RegRef};
 use emu::dbg;
+use emu::dbg::{DebuggerRenderer, GpuCommand, GpuCommandView, GpuEvent};
 use emu::int::Numerics;
 use emu::sync;
+use std::path::Path;
 
 bitflags! {
     struct StatusFlags: u32 {
@@ -51,6 +57,22 @@ pub struct Dp {
     #[reg(bank = 0, offset = 0xC, wcb)]
     cmd_status: Reg32,
 
+    // [23:0] clock counter
+    #[reg(bank = 0, offset = 0x10, rwmask = 0x00FFFFFF, readonly)]
+    clock: Reg32,
+
+    // [23:0] clocks in which the command buffer was busy
+    #[reg(bank = 0, offset = 0x14, rwmask = 0x00FFFFFF, readonly)]
+    buf_busy: Reg32,
+
+    // [23:0] clocks in which the pixel pipeline was busy
+    #[reg(bank = 0, offset = 0x18, rwmask = 0x00FFFFFF, readonly)]
+    pipe_busy: Reg32,
+
+    // [23:0] clocks in which TMEM was busy
+    #[reg(bank = 0, offset = 0x1C, rwmask = 0x00FFFFFF, readonly)]
+    tmem_busy: Reg32,
+
     logger: slog::Logger,
 
     fetched_mem: MemIoR<u64>,
@@ -58,8 +80,11 @@ pub struct Dp {
     fetched_end_addr: u32,
     cycles: i64,
     running: bool,
+    cmdleft: usize, // words left of the command being fetched
 
     gfx: Box<Rdp>,
+    capture: Option<DpCapture>,
+    cmdlog_visible: bool, // command list displayed in the debugger since last frame
 }
 
 impl Dp {
@@ -70,16 +95,66 @@ impl Dp {
             cmd_end: Reg32::default(),
             cmd_current: Reg32::default(),
             cmd_status: Reg32::default(),
+            clock: Reg32::default(),
+            buf_busy: Reg32::default(),
+            pipe_busy: Reg32::default(),
+            tmem_busy: Reg32::default(),
             logger,
             cycles: 0,
             running: false,
+            cmdleft: 0,
             fetched_mem: MemIoR::default(),
             fetched_start_addr: 0,
             fetched_end_addr: 0,
             gfx: Box::new(Rdp::new(gfx_logger)),
+            capture: None,
+            cmdlog_visible: false,
         })
     }
 
+    /// Start capturing all the command buffers executed by the DP into the
+    /// specified file (see [`dpcap`](../dpcap/index.html)).
+    pub fn start_capture(&mut self, path: &Path) -> Result<()> {
+        self.capture = Some(DpCapture::create(path)?);
+        info!(self.logger, "RDP capture started"; o!("path" => path.display().to_string()));
+        Ok(())
+    }
+
+    pub fn stop_capture(&mut self) {
+        self.capture_end_buffer();
+        self.capture = None;
+    }
+
+    fn capture_end_buffer(&mut self) {
+        if let Some(cap) = self.capture.as_mut() {
+            if let Err(e) = cap.end_buffer() {
+                error!(self.logger, "error writing RDP capture, stopping"; o!("err" => e.to_string()));
+                self.capture = None;
+            }
+        }
+    }
+
+    /// Mark the end of a frame, for the command list shown in the debugger.
+    pub fn end_frame(&mut self) {
+        self.gfx.end_frame();
+        // Stop recording if the command list was not displayed during
+        // the last frame (eg: the debugger was closed).
+        if !self.cmdlog_visible {
+            self.gfx.set_cmdlog(false);
+        }
+        self.cmdlog_visible = false;
+    }
+
+    pub fn render_debug<'a, 'ui>(&mut self, dr: &DebuggerRenderer<'a, 'ui>) {
+        dr.render_memoryview(&mut TmemView(&mut self.gfx));
+        dr.render_texview(&mut TmemView(&mut self.gfx));
+
+        // Commands are only recorded while the command list is displayed.
+        let visible = dr.render_gpuview(self);
+        self.cmdlog_visible |= visible;
+        self.gfx.set_cmdlog(visible);
+    }
+
     fn cmd_status_ref(&self) -> RegRef<StatusFlags> {
         self.cmd_status.as_ref::<StatusFlags>()
     }
@@ -100,16 +175,49 @@ impl Dp {
         self.check_start();
     }
 
+    // (W): [0] clear xbus_dmem_dma  [1] set xbus_dmem_dma
+    //      [2] clear freeze         [3] set freeze
+    //      [4] clear flush          [5] set flush
+    //      [6] clear tmem counter   [7] clear pipe counter
+    //      [8] clear cmd counter    [9] clear clock counter
     fn cb_write_cmd_status(&mut self, old: u32, new: u32) {
         self.cmd_status.set(old);
         let mut status = self.cmd_status_ref();
         warn!(self.logger, "writing to DP status"; o!("val" => new.hex()));
-        if new & (1<<0) != 0 {
+        if new & (1 << 0) != 0 {
             status.remove(StatusFlags::XBUS_DMA);
         }
-        if new & (1<<1) != 0 {
+        if new & (1 << 1) != 0 {
             status.insert(StatusFlags::XBUS_DMA);
         }
+        if new & (1 << 2) != 0 {
+            status.remove(StatusFlags::FREEZE);
+        }
+        if new & (1 << 3) != 0 {
+            status.insert(StatusFlags::FREEZE);
+        }
+        if new & (1 << 4) != 0 {
+            status.remove(StatusFlags::FLUSH);
+        }
+        if new & (1 << 5) != 0 {
+            // Flushing drops any partially-received command, so that the
+            // next buffer starts on a command boundary.
+            status.insert(StatusFlags::FLUSH);
+            self.gfx.flush();
+            self.cmdleft = 0;
+        }
+        if new & (1 << 6) != 0 {
+            self.tmem_busy.set(0);
+        }
+        if new & (1 << 7) != 0 {
+            self.pipe_busy.set(0);
+        }
+        if new & (1 << 8) != 0 {
+            self.buf_busy.set(0);
+        }
+        if new & (1 << 9) != 0 {
+            self.clock.set(0);
+        }
     }
 
     fn check_start(&mut self) {
@@ -138,6 +246,7 @@ impl Dp {
 
         self.fetched_end_addr = self.cmd_end.get();
         status.remove(StatusFlags::END_VALID);
+        status.insert(StatusFlags::CMD_BUSY | StatusFlags::START_GLK);
         self.running = true;
         warn!(
             self.logger,
@@ -145,6 +254,19 @@ impl Dp {
             o!("start" => self.fetched_start_addr.hex(), "end" => self.fetched_end_addr.hex())
         );
     }
+
+    // Advance time while the RDP is not processing commands.
+    fn idle(&mut self, until: i64) {
+        if until > self.cycles {
+            add_counter(&mut self.clock, until - self.cycles);
+            self.cycles = until;
+        }
+    }
+}
+
+// Increment one of the 24-bit DP counters.
+fn add_counter(reg: &mut Reg32, cycles: i64) {
+    reg.set(reg.get().wrapping_add(cycles as u32) & 0x00FF_FFFF);
 }
 
 impl sync::Subsystem for Dp {
@@ -152,13 +274,18 @@ impl sync::Subsystem for Dp {
         "RDP"
     }
 
-    fn run(&mut self, until: i64, _: &dbg::Tracer) -> dbg::Result<()> {
-        if !self.running {
-            self.cycles = until;
+    fn run(&mut self, until: i64, t: &dbg::Tracer) -> dbg::Result<()> {
+        if !self.running || self.cmd_status_ref().contains(StatusFlags::FREEZE) {
+            self.idle(until);
             return Ok(());
         }
+        // Safe because RDRAM is owned by the bus for the whole emulation, and
+        // the RDP executes commands on the emulation thread, so nothing else
+        // accesses it while a command runs.
+        unsafe { self.gfx.set_rdram(RdramPtr::current()) };
         loop {
             let mut curr_addr = self.cmd_current_ref();
+            let mut status = self.cmd_status_ref();
             for cmd in self
                 .fetched_mem
                 .iter()
@@ -166,9 +293,48 @@ impl sync::Subsystem for Dp {
                 .skip((*curr_addr - self.fetched_start_addr) as usize / 8)
                 .take((self.fetched_end_addr - *curr_addr) as usize / 8)
             {
-                self.gfx.op(cmd);
+                // Trace the beginning of each command, so that the debugger
+                // can break before it is executed.
+                if self.cmdleft == 0 {
+                    let op = cmd.get_bits(56..62);
+                    t.trace_gpu(GpuEvent::Command {
+                        gpu: "RDP",
+                        op: op as u32,
+                        name: command_name(op),
+                    })?;
+                    self.cmdleft = command_words(op);
+                }
+                self.cmdleft -= 1;
+
+                if let Some(cap) = self.capture.as_mut() {
+                    if let Err(e) = cap.op(cmd) {
+                        error!(self.logger, "error writing RDP capture, stopping"; o!("err" => e.to_string()));
+                        self.capture = None;
+                    }
+                }
+                let timing = self.gfx.op(cmd);
                 *curr_addr += 8;
-                self.cycles += 1;
+
+                let cycles = timing.cycles.max(1);
+                self.cycles += cycles;
+                add_counter(&mut self.clock, cycles);
+                add_counter(&mut self.buf_busy, cycles);
+                add_counter(&mut self.pipe_busy, timing.pipe);
+                add_counter(&mut self.tmem_busy, timing.tmem);
+                if timing.pipe > 0 {
+                    status.insert(StatusFlags::PIPE_BUSY);
+                }
+                if timing.tmem > 0 {
+                    status.insert(StatusFlags::TMEM_BUSY);
+                }
+
+                // The DP interrupt is only raised by Sync Full, once all
+                // the previous primitives have been fully rendered.
+                if timing.sync_full {
+                    status.remove(StatusFlags::PIPE_BUSY | StatusFlags::TMEM_BUSY);
+                    Mi::get_mut().set_irq_line(IrqMask::DP, true);
+                }
+
                 if self.cycles >= until {
                     return Ok(());
                 }
@@ -176,11 +342,12 @@ impl sync::Subsystem for Dp {
 
             // Finished the current buffer: stop iteration, but
             // check if there's a new buffer pending
+            self.capture_end_buffer();
             self.running = false;
+            status.remove(StatusFlags::CMD_BUSY);
             self.check_start();
             if !self.running {
-                self.cycles = until;
-                Mi::get_mut().set_irq_line(IrqMask::DP, true);
+                self.idle(until);
                 return Ok(());
             }
         }
@@ -198,3 +365,24 @@ impl sync::Subsystem for Dp {
         None
     }
 }
+
+impl GpuCommandView for Dp {
+    fn name(&self) -> &str {
+        "RDP"
+    }
+
+    fn command_types(&self) -> Vec<(u32, &'static str)> {
+        COMMAND_NAMES
+            .iter()
+            .map(|(op, name)| (*op as u32, *name))
+            .collect()
+    }
+
+    fn commands(&self) -> &[GpuCommand] {
+        self.gfx.last_frame().0
+    }
+
+    fn framebuffer_size(&self) -> (usize, usize) {
+        self.gfx.last_frame().1
+    }
+}
@@ -0,0 +1,397 @@
+//! Capture of the RDP command stream, and offline replay.
+//!
+//! A capture file contains every command buffer executed by the DP, together
+//! with the RDRAM regions referenced by the commands (color and Z images, and
+//! the texture areas loaded into TMEM), so that it can be replayed through
+//! the RDP without booting the game that produced it.
+//!
+//! The file format is a sequence of big-endian records, after an 8-byte magic
+//! and a 32-bit version number:
+//!
+//! | Tag | Contents |
+//! | -- | -- |
+//! | `M` | RDRAM address (u32), length (u32), followed by the memory contents |
+//! | `C` | number of words (u32), followed by 64-bit command words |
+extern crate bit_field;
+extern crate byteorder;
+extern crate emu;
+extern crate slog;
+use super::errors::*;
+use super::rdp::{command_words, RdramPtr, Rdp};
+use bit_field::BitField;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use emu::gfx::{BufferLineGetter, BufferLineSetter, Color, ColorConverter};
+use emu::gfx::{GfxBufferBE, GfxBufferLE, OwnedGfxBufferLE, Rgba8888, Xbgr1555, I8};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"R64DPCAP";
+const VERSION: u32 = 1;
+
+const TAG_MEM: u8 = b'M';
+const TAG_CMDS: u8 = b'C';
+
+// Size of the RDRAM buffer used for replay. This covers the 8MB of RDRAM
+// with the Expansion Pak.
+const REPLAY_RDRAM_SIZE: usize = 8 * 1024 * 1024;
+
+// Height used for color images when the scissor was not configured.
+const DEFAULT_IMAGE_HEIGHT: usize = 240;
+
+/// An image in RDRAM, as configured by Set Color/Z/Texture Image.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ImageDesc {
+    pub dram_addr: u32,
+    pub width: usize,
+    pub bpp: usize,
+}
+
+impl ImageDesc {
+    pub fn pitch(&self) -> usize {
+        self.width * self.bpp / 8
+    }
+}
+
+// Tracks the command boundaries within the stream, and the subset of the RDP
+// state required to know which RDRAM areas are referenced by the commands.
+#[derive(Default)]
+struct CmdDecoder {
+    cmd: Vec<u64>,
+    left: usize,
+
+    color: ImageDesc,
+    zbuf: ImageDesc,
+    tex: ImageDesc,
+    scissor_height: usize,
+}
+
+impl CmdDecoder {
+    // Push a command word. Returns true if a command was completed; it can
+    // then be accessed through self.cmd.
+    fn push(&mut self, word: u64) -> bool {
+        if self.left == 0 {
+            self.cmd.clear();
+            self.left = command_words(word.get_bits(56..62));
+        }
+        self.cmd.push(word);
+        self.left -= 1;
+        if self.left != 0 {
+            return false;
+        }
+
+        let cmd = self.cmd[0];
+        match cmd.get_bits(56..62) {
+            0x2D => {
+                // Set Scissor: keep the lower bound (10.2)
+                self.scissor_height = ((cmd.get_bits(0..12) + 3) >> 2) as usize;
+            }
+            0x3D | 0x3E | 0x3F => {
+                let image = ImageDesc {
+                    dram_addr: cmd.get_bits(0..26) as u32,
+                    width: cmd.get_bits(32..42) as usize + 1,
+                    bpp: 4 << cmd.get_bits(51..53),
+                };
+                match cmd.get_bits(56..62) {
+                    0x3D => self.tex = image,
+                    0x3E => {
+                        // The Z image has the same width as the color image.
+                        self.zbuf = ImageDesc {
+                            width: self.color.width,
+                            bpp: 16,
+                            ..image
+                        }
+                    }
+                    _ => self.color = image,
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn image_height(&self) -> usize {
+        if self.scissor_height != 0 {
+            self.scissor_height
+        } else {
+            DEFAULT_IMAGE_HEIGHT
+        }
+    }
+
+    // Return the RDRAM regions (address, length) read by the command
+    // that was just completed.
+    fn regions(&mut self, color_dirty: &mut bool, zbuf_dirty: &mut bool) -> Vec<(u32, usize)> {
+        let cmd = self.cmd[0];
+        let mut regions = Vec::new();
+        match cmd.get_bits(56..62) {
+            0x3E => *zbuf_dirty = true,
+            0x3F => *color_dirty = true,
+            0x30 | 0x34 => {
+                // Load TLUT / Load Tile: rows t0..=t1 of the texture image (10.2)
+                let t0 = cmd.get_bits(32..44) as usize >> 2;
+                let t1 = cmd.get_bits(0..12) as usize >> 2;
+                if t1 >= t0 {
+                    let pitch = self.tex.pitch();
+                    regions.push((
+                        self.tex.dram_addr + (t0 * pitch) as u32,
+                        (t1 - t0 + 1) * pitch,
+                    ));
+                }
+            }
+            0x33 => {
+                // Load Block: texels sl..=sh starting at row tl
+                let sl = cmd.get_bits(44..56) as usize;
+                let tl = cmd.get_bits(32..44) as usize;
+                let sh = cmd.get_bits(12..24) as usize;
+                if sh >= sl {
+                    let start = (tl * self.tex.width + sl) * self.tex.bpp / 8;
+                    regions.push((
+                        self.tex.dram_addr + start as u32,
+                        ((sh - sl + 1) * self.tex.bpp + 7) / 8,
+                    ));
+                }
+            }
+            0x08..=0x0F | 0x24 | 0x25 | 0x36 => {
+                // Drawing primitives: the first one after a color or Z image
+                // change needs the current contents of the image.
+                if *color_dirty {
+                    let len = self.color.pitch() * self.image_height();
+                    regions.push((self.color.dram_addr, len));
+                    *color_dirty = false;
+                }
+                if *zbuf_dirty {
+                    let len = self.zbuf.pitch() * self.image_height();
+                    regions.push((self.zbuf.dram_addr, len));
+                    *zbuf_dirty = false;
+                }
+            }
+            _ => {}
+        }
+        regions
+    }
+}
+
+/// DpCapture writes a capture file, while the DP executes commands.
+pub struct DpCapture {
+    out: BufWriter<File>,
+    dec: CmdDecoder,
+    cmds: Vec<u64>,
+    color_dirty: bool,
+    zbuf_dirty: bool,
+}
+
+impl DpCapture {
+    pub fn create(path: &Path) -> Result<DpCapture> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_u32::<BigEndian>(VERSION)?;
+        Ok(DpCapture {
+            out,
+            dec: CmdDecoder::default(),
+            cmds: Vec::new(),
+            color_dirty: false,
+            zbuf_dirty: false,
+        })
+    }
+
+    fn write_cmds(&mut self) -> Result<()> {
+        if !self.cmds.is_empty() {
+            self.out.write_u8(TAG_CMDS)?;
+            self.out.write_u32::<BigEndian>(self.cmds.len() as u32)?;
+            for cmd in self.cmds.iter() {
+                self.out.write_u64::<BigEndian>(*cmd)?;
+            }
+            self.cmds.clear();
+        }
+        Ok(())
+    }
+
+    fn write_mem(&mut self, rdram: &RdramPtr, addr: u32, len: usize) -> Result<()> {
+        // Safe because the capture runs on the emulation thread, before the
+        // command that references this memory is executed.
+        let mem = match unsafe { rdram.slice(addr) } {
+            Some(mem) => &mem[..len.min(mem.len())],
+            None => return Ok(()),
+        };
+        self.out.write_u8(TAG_MEM)?;
+        self.out.write_u32::<BigEndian>(addr)?;
+        self.out.write_u32::<BigEndian>(mem.len() as u32)?;
+        self.out.write_all(mem)?;
+        Ok(())
+    }
+
+    /// Record a command word that is about to be executed. RDRAM regions
+    /// referenced by the command are captured before the command itself.
+    pub fn op(&mut self, cmd: u64) -> Result<()> {
+        if !self.dec.push(cmd) {
+            return Ok(());
+        }
+
+        let regions = self
+            .dec
+            .regions(&mut self.color_dirty, &mut self.zbuf_dirty);
+        if !regions.is_empty() {
+            self.write_cmds()?;
+            let rdram = unsafe { RdramPtr::current() };
+            for (addr, len) in regions {
+                self.write_mem(&rdram, addr, len)?;
+            }
+        }
+        self.cmds.extend_from_slice(&self.dec.cmd);
+        Ok(())
+    }
+
+    /// Mark the end of a command buffer executed by the DP.
+    pub fn end_buffer(&mut self) -> Result<()> {
+        self.write_cmds()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// DpReplay replays a capture file through the RDP, without emulating
+/// the rest of the console.
+pub struct DpReplay {
+    rdram: Vec<u8>,
+    rdp: Rdp,
+    dec: CmdDecoder,
+}
+
+impl DpReplay {
+    pub fn new(logger: slog::Logger) -> DpReplay {
+        DpReplay {
+            rdram: vec![0u8; REPLAY_RDRAM_SIZE],
+            rdp: Rdp::new(logger),
+            dec: CmdDecoder::default(),
+        }
+    }
+
+    /// Write a memory block into RDRAM.
+    pub fn write_mem(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        let addr = addr as usize;
+        if addr + data.len() > self.rdram.len() {
+            bail!("memory block out of RDRAM bounds: {:x}+{:x}", addr, data.len());
+        }
+        self.rdram[addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Write a memory block into TMEM.
+    pub fn write_tmem(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        let tmem = self.rdp.tmem_mut();
+        if addr + data.len() > tmem.len() {
+            bail!("memory block out of TMEM bounds: {:x}+{:x}", addr, data.len());
+        }
+        tmem[addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Return the current RDRAM contents.
+    pub fn rdram(&self) -> &[u8] {
+        &self.rdram[..]
+    }
+
+    /// Execute a sequence of RDP command words.
+    pub fn run(&mut self, cmds: &[u64]) {
+        // Safe because self.rdram is owned by the replay, is never resized,
+        // and is not accessed while the commands are executed.
+        unsafe { self.rdp.set_rdram(RdramPtr::from_slice(&mut self.rdram)) };
+        for cmd in cmds {
+            self.dec.push(*cmd);
+            self.rdp.op(*cmd);
+        }
+    }
+
+    /// Replay a whole capture file.
+    pub fn run_file(&mut self, path: &Path) -> Result<()> {
+        let mut f = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        f.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a RDP capture file");
+        }
+        let version = f.read_u32::<BigEndian>()?;
+        if version != VERSION {
+            bail!("unsupported capture version: {}", version);
+        }
+
+        loop {
+            let tag = match f.read_u8() {
+                Ok(tag) => tag,
+                Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            match tag {
+                TAG_MEM => {
+                    let addr = f.read_u32::<BigEndian>()?;
+                    let len = f.read_u32::<BigEndian>()? as usize;
+                    let mut data = vec![0u8; len];
+                    f.read_exact(&mut data)?;
+                    self.write_mem(addr, &data)?;
+                }
+                TAG_CMDS => {
+                    let count = f.read_u32::<BigEndian>()? as usize;
+                    let mut cmds = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        cmds.push(f.read_u64::<BigEndian>()?);
+                    }
+                    self.run(&cmds);
+                }
+                _ => bail!("invalid record in capture file: {:x}", tag),
+            }
+        }
+    }
+
+    /// Return the color image currently configured (by Set Color Image).
+    pub fn color_image(&self) -> ImageDesc {
+        self.dec.color
+    }
+
+    /// Convert the current color image into a RGBA8888 buffer. The height
+    /// of the image is derived from the scissor.
+    pub fn framebuffer(&self) -> Result<OwnedGfxBufferLE<Rgba8888>> {
+        let desc = self.dec.color;
+        let (width, height) = (desc.width, self.dec.image_height());
+        let mem = &self.rdram[(desc.dram_addr as usize).min(self.rdram.len())..];
+
+        let mut out = OwnedGfxBufferLE::<Rgba8888>::new(width, height);
+        {
+            let mut dst = out.buf_mut();
+            match desc.bpp {
+                32 => {
+                    let src = GfxBufferLE::<Rgba8888>::new(mem, width, height, desc.pitch())?;
+                    for y in 0..height {
+                        let (src, mut dst) = (src.line(y), dst.line(y));
+                        for x in 0..width {
+                            let (r, g, b, _) = src.get(x).components();
+                            dst.set(x, Color::new_clamped(r, g, b, 0xFF));
+                        }
+                    }
+                }
+                16 => {
+                    let src = GfxBufferBE::<Xbgr1555>::new(mem, width, height, desc.pitch())?;
+                    for y in 0..height {
+                        let (src, mut dst) = (src.line(y), dst.line(y));
+                        for x in 0..width {
+                            let c: Color<Rgba8888> = src.get(x).cconv();
+                            let (r, g, b, _) = c.components();
+                            dst.set(x, Color::new_clamped(r, g, b, 0xFF));
+                        }
+                    }
+                }
+                8 => {
+                    let src = GfxBufferBE::<I8>::new(mem, width, height, desc.pitch())?;
+                    for y in 0..height {
+                        let (src, mut dst) = (src.line(y), dst.line(y));
+                        for x in 0..width {
+                            let (i, _, _, _) = src.get(x).components();
+                            dst.set(x, Color::new_clamped(i, i, i, 0xFF));
+                        }
+                    }
+                }
+                bpp => bail!("unsupported color image bpp: {}", bpp),
+            }
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,95 @@
+//! Cartridge EEPROM, accessed through the joybus (channel 4).
+use crate::errors::*;
+use crate::n64::MAIN_CLOCK;
+use crate::savefile::SaveFile;
+
+use std::path::Path;
+
+// A block write takes ~15ms to complete, during which the EEPROM reports
+// itself as busy. Time is measured in PI cycles.
+const WRITE_CYCLES: i64 = MAIN_CLOCK * 15 / 1000;
+
+pub struct Eeprom {
+    data: Vec<u8>,
+    save: SaveFile,
+    busy_until: i64,
+}
+
+impl Eeprom {
+    /// Size of a 4 Kbit EEPROM, in bytes.
+    pub const SIZE_4K: usize = 512;
+    /// Size of a 16 Kbit EEPROM, in bytes.
+    pub const SIZE_16K: usize = 2048;
+    /// EEPROMs are read and written in blocks of 8 bytes.
+    pub const BLOCK_SIZE: usize = 8;
+
+    /// Create a blank EEPROM of the specified size, which is not
+    /// persisted on disk.
+    pub fn new(size: usize) -> Eeprom {
+        assert!(size == Self::SIZE_4K || size == Self::SIZE_16K);
+        Eeprom {
+            data: vec![0xFF; size],
+            save: SaveFile::none(),
+            busy_until: 0,
+        }
+    }
+
+    /// Create an EEPROM of the specified size, backed by the specified
+    /// save file. If the file exists, its contents are loaded.
+    pub fn load(size: usize, path: &Path) -> Result<Eeprom> {
+        let mut eeprom = Eeprom::new(size);
+        eeprom.save = SaveFile::new(path);
+        // Other emulators always use 2KB save files, so any size is
+        // accepted.
+        eeprom.save.load(&mut eeprom.data)?;
+        Ok(eeprom)
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Reply to the joybus status command: the identifier of the EEPROM
+    /// size, and whether a write is in progress.
+    pub fn status(&self, now: i64) -> [u8; 3] {
+        let id = if self.size() == Self::SIZE_16K {
+            0xC0
+        } else {
+            0x80
+        };
+        let busy = if now < self.busy_until { 0x80 } else { 0x00 };
+        [0x00, id, busy]
+    }
+
+    // The block address wraps around the EEPROM size (4K EEPROMs ignore the
+    // upper bits of the block number).
+    fn block_offset(&self, block: u8) -> usize {
+        (block as usize * Self::BLOCK_SIZE) % self.size()
+    }
+
+    pub fn read_block(&self, block: u8, out: &mut [u8]) {
+        let off = self.block_offset(block);
+        out.copy_from_slice(&self.data[off..off + Self::BLOCK_SIZE]);
+    }
+
+    pub fn write_block(&mut self, block: u8, data: &[u8], now: i64) {
+        let off = self.block_offset(block);
+        self.data[off..off + Self::BLOCK_SIZE].copy_from_slice(data);
+        self.save.modified();
+        self.busy_until = now + WRITE_CYCLES;
+    }
+
+    /// Write the EEPROM contents to its save file, if they were modified.
+    pub fn flush(&mut self) -> Result<()> {
+        self.save.flush(&self.data)
+    }
+
+    /// Called once per frame.
+    pub fn autosave(&mut self) -> Result<()> {
+        self.save.end_frame(&self.data)
+    }
+}
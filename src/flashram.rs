@@ -0,0 +1,187 @@
+//! Cartridge FlashRAM (128KB), mapped in PI domain 2.
+//!
+//! The FlashRAM is controlled through a command register; depending on the
+//! current mode, PI DMA reads return either the array contents or the
+//! status/silicon ID. Notice that in read mode, the array is addressed in
+//! 16-bit units (so 128KB span 64KB of the address space).
+use crate::errors::*;
+use crate::savefile::SaveFile;
+
+use byteorder::{BigEndian, ByteOrder};
+use emu::bus::be::Reg32;
+use emu::int::Numerics;
+use emu_derive::DeviceBE;
+use slog;
+use std::path::Path;
+
+pub const SIZE: usize = 0x2_0000;
+pub const PAGE_SIZE: usize = 128;
+
+// Silicon ID (Macronix MX29L1100), as returned in the lower word of the
+// status.
+const SILICON_ID: u64 = 0x00C2_001E;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    Read,
+    Status,
+    EraseSector,
+    EraseChip,
+    Write,
+}
+
+// Commands are written to the command register, and work as follows:
+//   4B / 3C: select sector (page) / chip erase; 78: execute erase
+//   B4: load page buffer (via DMA); A5: program page buffer into a page
+//   D2: status mode; E1: silicon ID mode; F0: read mode
+
+#[derive(DeviceBE)]
+pub struct FlashRam {
+    // (R): upper word of the status
+    // (W): any write clears the status
+    #[reg(offset = 0x0_0000, rcb, wcb)]
+    status: Reg32,
+
+    // (W): [31:24] command, [15:0] page number (for erase/write)
+    #[reg(offset = 0x1_0000, writeonly, wcb)]
+    command: Reg32,
+
+    data: Vec<u8>,
+    mode: Mode,
+    status64: u64,
+    offset: usize,
+    page: Vec<u8>,
+
+    save: SaveFile,
+    logger: slog::Logger,
+}
+
+impl FlashRam {
+    /// Create a FlashRAM backed by the specified save file. If the file
+    /// exists, its contents are loaded.
+    pub fn new(logger: slog::Logger, path: &Path) -> Result<Box<FlashRam>> {
+        let save = SaveFile::new(path);
+        let mut data = vec![0xFF; SIZE];
+        save.load(&mut data)?;
+
+        Ok(Box::new(FlashRam {
+            status: Reg32::default(),
+            command: Reg32::default(),
+            data,
+            mode: Mode::Idle,
+            status64: 0,
+            offset: 0,
+            page: vec![0; PAGE_SIZE],
+            save,
+            logger,
+        }))
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn cb_read_status(&self, _old: u32) -> u32 {
+        (self.status64 >> 32) as u32
+    }
+
+    fn cb_write_status(&mut self, _old: u32, _new: u32) {
+        self.status64 = 0;
+    }
+
+    fn cb_write_command(&mut self, _old: u32, cmd: u32) {
+        let offset = (cmd & 0xFFFF) as usize * PAGE_SIZE % SIZE;
+        match cmd >> 24 {
+            0x4B => {
+                // Select the page to erase
+                self.mode = Mode::EraseSector;
+                self.offset = offset;
+            }
+            0x3C => {
+                // Select chip erase
+                self.mode = Mode::EraseChip;
+            }
+            0x78 => {
+                // Execute erase
+                match self.mode {
+                    Mode::EraseSector => self.fill(self.offset, PAGE_SIZE, 0xFF),
+                    Mode::EraseChip => self.fill(0, SIZE, 0xFF),
+                    _ => warn!(self.logger, "FlashRAM erase without selection"),
+                }
+                self.status64 = 0x1111_8008_0000_0000 | SILICON_ID;
+            }
+            0xB4 => {
+                // Page buffer load: the buffer is filled via PI DMA
+                self.mode = Mode::Write;
+            }
+            0xA5 => {
+                // Program the page buffer into the specified page
+                if self.mode == Mode::Write {
+                    self.data[offset..offset + PAGE_SIZE].copy_from_slice(&self.page);
+                    self.save.modified();
+                } else {
+                    warn!(self.logger, "FlashRAM program without page buffer load");
+                }
+                self.status64 = 0x1111_8004_0000_0000 | SILICON_ID;
+            }
+            0xD2 => self.mode = Mode::Status,
+            0xE1 => {
+                // Silicon ID
+                self.mode = Mode::Status;
+                self.status64 = 0x1111_8001_0000_0000 | SILICON_ID;
+            }
+            0xF0 => {
+                self.mode = Mode::Read;
+                self.status64 = 0x1111_8004_F000_0000;
+            }
+            _ => warn!(self.logger, "unknown FlashRAM command"; o!("cmd" => cmd.hex())),
+        }
+    }
+
+    fn fill(&mut self, offset: usize, len: usize, val: u8) {
+        for v in &mut self.data[offset..offset + len] {
+            *v = val;
+        }
+        self.save.modified();
+    }
+
+    /// Read data through PI DMA (from cartridge to RDRAM). `addr` is the
+    /// offset within PI domain 2.
+    pub fn dma_read(&self, addr: u32, out: &mut [u8]) {
+        match self.mode {
+            Mode::Read => {
+                let off = (addr as usize & 0xFFFF) * 2;
+                for (i, v) in out.iter_mut().enumerate() {
+                    *v = self.data[(off + i) % SIZE];
+                }
+            }
+            _ => {
+                let mut status = [0u8; 8];
+                BigEndian::write_u64(&mut status, self.status64);
+                for (i, v) in out.iter_mut().enumerate() {
+                    *v = status[i % 8];
+                }
+            }
+        }
+    }
+
+    /// Write data through PI DMA (from RDRAM to cartridge): this fills the
+    /// page buffer, that is later programmed with the A5 command.
+    pub fn dma_write(&mut self, data: &[u8]) {
+        let len = data.len().min(PAGE_SIZE);
+        self.page[..len].copy_from_slice(&data[..len]);
+    }
+
+    /// Write the FlashRAM contents to its save file, if they were modified.
+    pub fn flush(&mut self) -> Result<()> {
+        self.save.flush(&self.data)
+    }
+
+    /// Called once per frame.
+    pub fn end_frame(&mut self) {
+        if let Err(err) = self.save.end_frame(&self.data) {
+            error!(self.logger, "cannot save FlashRAM"; o!("err" => err.to_string()));
+        }
+    }
+}
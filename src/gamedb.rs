@@ -0,0 +1,186 @@
+//! Database of per-game settings.
+//!
+//! Most of the information needed to correctly emulate a game (save type,
+//! supported accessories, etc.) is not stored in the ROM, so it is kept in a
+//! database compiled into the binary (see gamedb.ini for the format).
+use crate::cartridge::{CicModel, RomHeader, SaveType};
+use crate::errors::*;
+use crate::n64::Region;
+
+use bitflags::bitflags;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+const BUILTIN_DB: &str = include_str!("gamedb.ini");
+
+lazy_static! {
+    static ref BUILTIN: GameDb = GameDb::parse(BUILTIN_DB).expect("invalid builtin game database");
+}
+
+bitflags! {
+    /// Controller accessories supported by a game.
+    pub struct Accessories: u8 {
+        const MEMPAK =   0b00000001;
+        const RUMBLE =   0b00000010;
+        const TRANSFER = 0b00000100;
+    }
+}
+
+/// Settings for a game. All fields are optional: a missing field means that
+/// the default (or what can be deduced from the ROM header) should be used.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GameInfo {
+    pub name: Option<String>,
+    pub save_type: Option<SaveType>,
+    /// Whether the game requires the Expansion Pak (8 MiB of RDRAM).
+    pub expansion_pak: Option<bool>,
+    pub region: Option<Region>,
+    pub accessories: Option<Accessories>,
+    pub cic: Option<CicModel>,
+    /// Emulation hacks required by the game.
+    pub hacks: Option<Vec<String>>,
+}
+
+impl GameInfo {
+    /// Set a field given its key and textual value, as written in the
+    /// database.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let list = || {
+            value
+                .split(',')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+        };
+        match key {
+            "name" => self.name = Some(value.to_owned()),
+            "save" => self.save_type = Some(value.parse()?),
+            "expansion_pak" => {
+                self.expansion_pak = Some(match value {
+                    "required" | "yes" => true,
+                    "no" => false,
+                    _ => bail!("invalid expansion_pak value: {}", value),
+                })
+            }
+            "region" => self.region = Some(value.parse()?),
+            "accessories" => {
+                let mut acc = Accessories::empty();
+                for v in list() {
+                    acc |= match v {
+                        "mempak" => Accessories::MEMPAK,
+                        "rumble" => Accessories::RUMBLE,
+                        "transfer" => Accessories::TRANSFER,
+                        _ => bail!("invalid accessory: {}", v),
+                    };
+                }
+                self.accessories = Some(acc);
+            }
+            "cic" => {
+                self.cic = Some(match value {
+                    "6101" => CicModel::Cic6101,
+                    "6102" => CicModel::Cic6102,
+                    "6103" => CicModel::Cic6103,
+                    "6105" => CicModel::Cic6105,
+                    "6106" => CicModel::Cic6106,
+                    _ => bail!("invalid CIC model: {}", value),
+                })
+            }
+            "hacks" => self.hacks = Some(list().iter().map(|&v| v.to_owned()).collect()),
+            _ => bail!("unknown key: {}", key),
+        }
+        Ok(())
+    }
+
+    /// Parse a "key=value" setting (eg: from the command line).
+    pub fn set_option(&mut self, opt: &str) -> Result<()> {
+        let mut kv = opt.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) => self.set(key.trim(), value.trim()),
+            _ => bail!("invalid game setting (expected key=value): {}", opt),
+        }
+    }
+
+    /// Override the fields of self with those that are set in other.
+    pub fn merge(&mut self, other: &GameInfo) {
+        let other = other.clone();
+        self.name = other.name.or(self.name.take());
+        self.save_type = other.save_type.or(self.save_type);
+        self.expansion_pak = other.expansion_pak.or(self.expansion_pak);
+        self.region = other.region.or(self.region);
+        self.accessories = other.accessories.or(self.accessories);
+        self.cic = other.cic.or(self.cic);
+        self.hacks = other.hacks.or(self.hacks.take());
+    }
+
+    /// Return true if the specified hack is enabled for this game.
+    pub fn has_hack(&self, hack: &str) -> bool {
+        self.hacks.as_ref().map_or(false, |h| h.iter().any(|h| h == hack))
+    }
+}
+
+/// A game database, keyed by game code or CRC.
+pub struct GameDb {
+    entries: HashMap<String, GameInfo>,
+}
+
+impl GameDb {
+    /// The database compiled into the binary.
+    pub fn builtin() -> &'static GameDb {
+        &BUILTIN
+    }
+
+    /// Parse a database in text format.
+    pub fn parse(text: &str) -> Result<GameDb> {
+        let mut entries = HashMap::new();
+        let mut section: Option<(String, GameInfo)> = None;
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some((key, info)) = section.take() {
+                    entries.insert(key, info);
+                }
+                let key = line[1..line.len() - 1].trim().to_uppercase();
+                if entries.contains_key(&key) {
+                    bail!("line {}: duplicated entry: {}", num + 1, key);
+                }
+                section = Some((key, GameInfo::default()));
+                continue;
+            }
+            match section {
+                Some((_, ref mut info)) => info
+                    .set_option(line)
+                    .chain_err(|| format!("line {}", num + 1))?,
+                None => bail!("line {}: setting outside of an entry", num + 1),
+            }
+        }
+        if let Some((key, info)) = section {
+            entries.insert(key, info);
+        }
+
+        Ok(GameDb { entries })
+    }
+
+    /// Lookup the settings for a ROM. Entries matching the game in all
+    /// regions, a single release, and a specific ROM image (by CRC) are
+    /// merged in this order, so that more specific entries take precedence.
+    pub fn lookup(&self, header: &RomHeader) -> GameInfo {
+        let code = &header.game_code;
+        let keys = [
+            code.get(..3).unwrap_or("").to_owned(),
+            code.clone(),
+            format!("{:08X}-{:08X}", header.crc1, header.crc2),
+        ];
+
+        let mut info = GameInfo::default();
+        for key in keys.iter() {
+            if let Some(entry) = self.entries.get(key) {
+                info.merge(entry);
+            }
+        }
+        info
+    }
+}
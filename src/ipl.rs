@@ -0,0 +1,94 @@
+//! High-level emulation of the PIF boot code (IPL1/IPL2), used when no PIF
+//! ROM is available.
+//!
+//! IPL1 and IPL2 run from the PIF ROM: they initialize the CPU, ask PIF to
+//! verify the cartridge checksum, copy IPL3 (the first 4KB of the cartridge,
+//! after the header) into SP DMEM and jump to it. IPL3 is part of the game,
+//! so from that point on the boot is emulated as usual.
+use crate::cartridge::CicModel;
+use crate::cic;
+use crate::pi::Pi;
+use crate::r4300::R4300;
+
+use mips64::Cop;
+
+// Registers set by IPL2 before jumping to IPL3.
+const REG_A2: usize = 6;
+const REG_A3: usize = 7;
+const REG_T0: usize = 8;
+const REG_T2: usize = 10;
+const REG_T3: usize = 11;
+const REG_S3: usize = 19;
+const REG_S4: usize = 20;
+const REG_S5: usize = 21;
+const REG_S6: usize = 22;
+const REG_S7: usize = 23;
+const REG_SP: usize = 29;
+const REG_RA: usize = 31;
+
+const COP0_STATUS: usize = 12;
+
+// Entry point of IPL3, in SP DMEM (after the cartridge header).
+const IPL3_ENTRY: u32 = 0xA400_0040;
+
+// IPL2 runs from SP IMEM, and the CIC-6105 IPL3 jumps back into its last
+// instructions to wait for PIF, so they must be there.
+const IPL2_TAIL_6105: [u32; 8] = [
+    0x3C0D_BFC0, // lui   t5, 0xBFC0
+    0x8DA8_07FC, // lw    t0, 0x07FC(t5)
+    0x25AD_07C0, // addiu t5, t5, 0x07C0
+    0x3108_0080, // andi  t0, t0, 0x0080
+    0x5500_FFFC, // bnezl t0, -4
+    0x3C0D_BFC0, // lui   t5, 0xBFC0
+    0x8DA8_0024, // lw    t0, 0x0024(t5)
+    0x3C0B_B000, // lui   t3, 0xB000
+];
+
+fn sext(v: u32) -> u64 {
+    v as i32 as i64 as u64
+}
+
+/// Boot the console as IPL1/IPL2 would, leaving the CPU at the start of
+/// IPL3. PIF must have already verified the cartridge checksum.
+pub(crate) fn hle_boot(pi: &mut Pi, cic: CicModel, tv_type: u32, hard_reset: bool) {
+    let cpu = R4300::get_mut();
+
+    // IPL1 programs the cartridge domain timing from the ROM header, so
+    // that the rest of the ROM can be accessed at the intended speed.
+    pi.set_dom1_config(cpu.bus.read::<u32>(0x1000_0000));
+
+    // Copy IPL3 into SP DMEM, and the IPL2 leftovers into SP IMEM.
+    for off in (0x40..0x1000).step_by(4) {
+        let val = cpu.bus.read::<u32>(0x1000_0000 + off);
+        cpu.bus.write::<u32>(0x0400_0000 + off, val);
+    }
+    if cic == CicModel::Cic6105 {
+        for (i, &op) in IPL2_TAIL_6105.iter().enumerate() {
+            cpu.bus.write::<u32>(0x0400_1000 + i as u32 * 4, op);
+        }
+    }
+
+    // CPU state: COP0/COP1 usable, 64-bit FPU registers.
+    let mut ctx = *cpu.ctx();
+    cpu.cop0.set_reg(&mut ctx, COP0_STATUS, 0x3400_0000);
+
+    ctx.regs = [0; 32];
+    ctx.regs[REG_A2] = sext(0xA400_1F0C);
+    ctx.regs[REG_A3] = sext(0xA400_1F08);
+    ctx.regs[REG_T0] = 0xC0;
+    ctx.regs[REG_T2] = 0x40;
+    ctx.regs[REG_T3] = sext(IPL3_ENTRY);
+    ctx.regs[REG_SP] = sext(0xA400_1FF0);
+    ctx.regs[REG_RA] = sext(0xA400_1550);
+
+    // Boot parameters for IPL3 and the game (see cic::boot_info).
+    ctx.regs[REG_S3] = 0; // ROM type: cartridge
+    ctx.regs[REG_S4] = tv_type as u64;
+    ctx.regs[REG_S5] = if hard_reset { 0 } else { 1 };
+    ctx.regs[REG_S6] = cic::seed(cic) as u64;
+    ctx.regs[REG_S7] = 0; // osVersion
+
+    ctx.set_halt_line(false);
+    ctx.set_pc(sext(IPL3_ENTRY));
+    *cpu.ctx_mut() = ctx;
+}
@@ -0,0 +1,355 @@
+//! Joybus: the serial protocol used by PIF to talk to the devices connected
+//! to the controller ports (channels 0-3) and to the cartridge EEPROM
+//! (channel 4).
+//!
+//! Commands are written by the CPU into PIF RAM as a sequence of blocks, one
+//! per channel. Each block is made of a TX length byte, a RX length byte,
+//! the TX bytes (starting with the command), and room for the RX bytes.
+//! Some special values can appear in place of the TX length:
+//!
+//!   0x00  skip this channel
+//!   0xFD  reset this channel (and skip it)
+//!   0xFE  end of commands
+//!   0xFF  padding, ignored
+//!
+//! After execution, error bits are reported in the RX length byte.
+use crate::accessory::{self, Accessory};
+use crate::eeprom::Eeprom;
+use crate::errors::*;
+use crate::n64::JOY_NAMES;
+
+use bitfield::Bit;
+use byteorder::{BigEndian, ByteOrder};
+use emu::input::{FeedbackEvent, InputManager, InputValue};
+use std::result;
+
+/// Number of joybus channels: the 4 controller ports, plus the cartridge.
+pub const NUM_CHANNELS: usize = 5;
+
+/// Channel of the cartridge (EEPROM).
+pub const CARTRIDGE_CHANNEL: usize = 4;
+
+// Error bits, reported in the RX length byte
+const ERR_NO_DEVICE: u8 = 0x80;
+const ERR_LENGTH: u8 = 0x40;
+
+const CMD_STATUS: u8 = 0x00;
+const CMD_READ_BUTTONS: u8 = 0x01;
+const CMD_PAK_READ: u8 = 0x02;
+const CMD_PAK_WRITE: u8 = 0x03;
+const CMD_EEPROM_READ: u8 = 0x04;
+const CMD_EEPROM_WRITE: u8 = 0x05;
+const CMD_RESET: u8 = 0xFF;
+
+/// A device connected to a joybus channel.
+pub enum JoybusDevice {
+    None,
+    /// Standard controller, with an optional accessory plugged in.
+    Controller(Option<Accessory>),
+    Mouse,
+    Eeprom(Eeprom),
+}
+
+impl Default for JoybusDevice {
+    fn default() -> JoybusDevice {
+        JoybusDevice::None
+    }
+}
+
+// Read the state of the input device associated to a controller port,
+// in the format returned by the read buttons command.
+fn read_buttons(input: &InputManager, ch: usize) -> u32 {
+    let mut value: u32 = 0;
+    let dev = JOY_NAMES.get(ch).and_then(|name| input.device(name));
+    if let Some(dev) = dev {
+        dev.visit(|i| match i.value() {
+            InputValue::Digital(val) => {
+                if val {
+                    value.set_bit(i.custom_id(), true);
+                }
+            }
+            InputValue::Analog(val) => value |= ((val >> 8) as u8 as u32) << i.custom_id(),
+            _ => unreachable!(),
+        });
+    }
+    value
+}
+
+impl JoybusDevice {
+    // Execute a command, writing the reply into `out`. Returns the length
+    // of the reply, or None if the device does not respond (because the
+    // command is not supported, or is malformed).
+    fn command(
+        &mut self,
+        ch: usize,
+        cmd: &[u8],
+        out: &mut [u8],
+        input: &mut InputManager,
+        now: i64,
+    ) -> Option<usize> {
+        match (self, cmd[0]) {
+            (JoybusDevice::Controller(pak), CMD_STATUS)
+            | (JoybusDevice::Controller(pak), CMD_RESET) => {
+                // Third byte: 0x01 = accessory present, 0x02 = no accessory
+                let pak = if pak.is_some() { 0x01 } else { 0x02 };
+                out[..3].copy_from_slice(&[0x05, 0x00, pak]);
+                Some(3)
+            }
+            (JoybusDevice::Controller(_), CMD_READ_BUTTONS) => {
+                let mut value = read_buttons(input, ch);
+
+                // S+Left+Right => Reset.
+                if value.bit(21) && value.bit(20) && value.bit(18) {
+                    value.set_bit(23, true);
+                }
+                BigEndian::write_u32(out, value);
+                Some(4)
+            }
+            (JoybusDevice::Controller(pak), CMD_PAK_READ) => {
+                if cmd.len() < 3 {
+                    return None;
+                }
+                // The address is 32-byte aligned: the lower 5 bits hold
+                // a CRC of the address, which we ignore.
+                let addr = BigEndian::read_u16(&cmd[1..]) & !0x1F;
+                let (data, crc) = out.split_at_mut(accessory::BLOCK_SIZE);
+                crc[0] = match pak {
+                    Some(pak) => {
+                        pak.read(addr, data);
+                        accessory::data_crc(data)
+                    }
+                    None => {
+                        // Without an accessory, the CRC is inverted.
+                        for v in data.iter_mut() {
+                            *v = 0;
+                        }
+                        !accessory::data_crc(data)
+                    }
+                };
+                Some(accessory::BLOCK_SIZE + 1)
+            }
+            (JoybusDevice::Controller(pak), CMD_PAK_WRITE) => {
+                if cmd.len() < 3 + accessory::BLOCK_SIZE {
+                    return None;
+                }
+                let addr = BigEndian::read_u16(&cmd[1..]) & !0x1F;
+                let data = &cmd[3..3 + accessory::BLOCK_SIZE];
+                out[0] = match pak {
+                    Some(pak) => {
+                        let motor = pak.motor();
+                        pak.write(addr, data);
+                        if pak.motor() != motor && ch < JOY_NAMES.len() {
+                            let evt = FeedbackEvent::Rumble(JOY_NAMES[ch].into(), pak.motor());
+                            input.send_feedback(evt);
+                        }
+                        accessory::data_crc(data)
+                    }
+                    None => !accessory::data_crc(data),
+                };
+                Some(1)
+            }
+            (JoybusDevice::Mouse, CMD_STATUS) | (JoybusDevice::Mouse, CMD_RESET) => {
+                out[..3].copy_from_slice(&[0x02, 0x00, 0x00]);
+                Some(3)
+            }
+            (JoybusDevice::Mouse, CMD_READ_BUTTONS) => {
+                // Left/right buttons are mapped to A/B, and the relative
+                // motion to the analog stick.
+                let value = read_buttons(input, ch) & 0xC000_FFFF;
+                BigEndian::write_u32(out, value);
+                Some(4)
+            }
+            (JoybusDevice::Eeprom(eeprom), CMD_STATUS)
+            | (JoybusDevice::Eeprom(eeprom), CMD_RESET) => {
+                out[..3].copy_from_slice(&eeprom.status(now));
+                Some(3)
+            }
+            (JoybusDevice::Eeprom(eeprom), CMD_EEPROM_READ) => {
+                if cmd.len() < 2 {
+                    return None;
+                }
+                eeprom.read_block(cmd[1], &mut out[..Eeprom::BLOCK_SIZE]);
+                Some(Eeprom::BLOCK_SIZE)
+            }
+            (JoybusDevice::Eeprom(eeprom), CMD_EEPROM_WRITE) => {
+                if cmd.len() < 2 + Eeprom::BLOCK_SIZE {
+                    return None;
+                }
+                eeprom.write_block(cmd[1], &cmd[2..2 + Eeprom::BLOCK_SIZE], now);
+                out[0] = 0x00;
+                Some(1)
+            }
+            _ => None,
+        }
+    }
+
+    // Execute a transfer: run the command, copy the reply into the RX
+    // buffer, and return the error bits for the RX length byte.
+    fn transfer(
+        &mut self,
+        ch: usize,
+        cmd: &[u8],
+        rx: &mut [u8],
+        input: &mut InputManager,
+        now: i64,
+    ) -> u8 {
+        if cmd.is_empty() {
+            return 0;
+        }
+        let mut reply = [0u8; 64];
+        match self.command(ch, cmd, &mut reply, input, now) {
+            None => ERR_NO_DEVICE,
+            Some(len) => {
+                let n = len.min(rx.len());
+                rx[..n].copy_from_slice(&reply[..n]);
+                if len != rx.len() {
+                    ERR_LENGTH
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            JoybusDevice::Controller(Some(pak)) => pak.flush(),
+            JoybusDevice::Eeprom(eeprom) => eeprom.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    fn end_frame(&mut self) -> Result<()> {
+        match self {
+            JoybusDevice::Controller(Some(pak)) => pak.end_frame(),
+            JoybusDevice::Eeprom(eeprom) => eeprom.autosave(),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct Joybus {
+    devices: [JoybusDevice; NUM_CHANNELS],
+}
+
+impl Joybus {
+    /// Create a joybus with a single controller connected to the first
+    /// port, and no cartridge EEPROM.
+    pub fn new() -> Joybus {
+        Joybus {
+            devices: [
+                JoybusDevice::Controller(None),
+                JoybusDevice::None,
+                JoybusDevice::None,
+                JoybusDevice::None,
+                JoybusDevice::None,
+            ],
+        }
+    }
+
+    pub fn device(&self, ch: usize) -> &JoybusDevice {
+        &self.devices[ch]
+    }
+
+    /// Connect a device to the specified channel. The previous device,
+    /// if any, is saved.
+    pub fn set_device(&mut self, ch: usize, dev: JoybusDevice) -> Result<()> {
+        if ch >= NUM_CHANNELS {
+            bail!("invalid joybus channel: {}", ch);
+        }
+        self.devices[ch].flush()?;
+        self.devices[ch] = dev;
+        Ok(())
+    }
+
+    /// Plug an accessory into the controller connected to the specified
+    /// port (or unplug it, if None). The previous accessory, if any, is saved.
+    pub fn set_accessory(&mut self, port: usize, pak: Option<Accessory>) -> Result<()> {
+        match self.devices.get_mut(port) {
+            Some(JoybusDevice::Controller(slot)) if port < CARTRIDGE_CHANNEL => {
+                if let Some(old) = slot.as_mut() {
+                    old.flush()?;
+                }
+                *slot = pak;
+                Ok(())
+            }
+            _ => bail!("no controller connected to port {}", port + 1),
+        }
+    }
+
+    pub fn accessory_mut(&mut self, port: usize) -> Option<&mut Accessory> {
+        match self.devices.get_mut(port) {
+            Some(JoybusDevice::Controller(Some(pak))) => Some(pak),
+            _ => None,
+        }
+    }
+
+    /// Save the contents of all devices (EEPROM, accessories) to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        for dev in self.devices.iter_mut() {
+            dev.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Called once per frame.
+    pub fn end_frame(&mut self) -> Result<()> {
+        for dev in self.devices.iter_mut() {
+            dev.end_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Execute the commands stored in PIF RAM (excluding the final control
+    /// byte), writing the replies back into it. `now` is the current time
+    /// in PI cycles.
+    pub fn exec(
+        &mut self,
+        ram: &mut [u8],
+        input: &mut InputManager,
+        now: i64,
+    ) -> result::Result<(), &'static str> {
+        let mut ch = 0;
+        let mut idx = 0;
+        while idx < ram.len() {
+            match ram[idx] {
+                0xFE => return Ok(()),
+                0xFF => idx += 1,
+                0x00 | 0xFD => {
+                    ch += 1;
+                    idx += 1;
+                }
+                t => {
+                    let r = *ram.get(idx + 1).ok_or("joybus: premature end of RAM")?;
+                    if r == 0xFE {
+                        return Ok(());
+                    }
+                    let cmd = idx + 2;
+                    let out = cmd + (t & 0x3F) as usize;
+                    let end = out + (r & 0x3F) as usize;
+                    if end > ram.len() {
+                        return Err("joybus: command exceeds PIF RAM");
+                    }
+
+                    let err = match self.devices.get_mut(ch) {
+                        Some(dev) => {
+                            let (cmdbuf, rxbuf) = ram[cmd..end].split_at_mut(out - cmd);
+                            dev.transfer(ch, cmdbuf, rxbuf, input, now)
+                        }
+                        None => ERR_NO_DEVICE,
+                    };
+                    ram[idx + 1] = (r & 0x3F) | err;
+                    idx = end;
+                    ch += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Joybus {
+    fn default() -> Joybus {
+        Joybus::new()
+    }
+}
@@ -27,18 +27,30 @@ pub mod errors {
     }
 }
 
+mod ipl;
 mod rdp;
+mod savefile;
 
+pub mod accessory;
 pub mod ai;
 pub mod cartridge;
+pub mod cic;
 pub mod dp;
+pub mod dpcap;
+pub mod eeprom;
+pub mod flashram;
+pub mod gamedb;
+pub mod joybus;
+pub mod mempak;
 pub mod mi;
 pub mod pi;
 pub mod r4300;
 pub mod ri;
 pub mod si;
 pub mod sp;
+pub mod sram;
+pub mod transferpak;
 pub mod vi;
 
 mod n64;
-pub use self::n64::N64;
+pub use self::n64::{Region, N64};
@@ -4,11 +4,16 @@ extern crate error_chain;
 use emu::dbg;
 use emu::hw;
 use emu::log;
+use r64emu::accessory::{Accessory, PakType, RumblePak};
+use r64emu::cartridge::{load_rom, RomHeader};
 use r64emu::errors::*;
-use r64emu::N64;
-
-use std::path::Path;
+use r64emu::gamedb::{GameDb, GameInfo};
+use r64emu::mempak::Mempak;
+use r64emu::transferpak::{GbCart, TransferPak};
+use r64emu::vi::Deinterlace;
+use r64emu::{Region, N64};
 
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -18,14 +23,47 @@ struct Cli {
     #[structopt(short = "d", long = "debugger")]
     debugger: bool,
 
-    /// Path to the BIOS file
+    /// Path to the BIOS file (if not specified, the PIF boot is emulated)
+    #[structopt(short = "b", long = "bios", parse(from_os_str))]
+    bios: Option<std::path::PathBuf>,
+
+    /// Capture the RDP command stream into the specified file
+    #[structopt(long = "dp-capture", parse(from_os_str))]
+    dp_capture: Option<std::path::PathBuf>,
+
+    /// Audio latency in milliseconds
+    #[structopt(long = "audio-latency", default_value = "64")]
+    audio_latency: u64,
+
+    /// Console region (video standard) [default: from ROM header]
+    #[structopt(
+        long = "region",
+        raw(possible_values = r#"&["ntsc", "pal", "mpal"]"#)
+    )]
+    region: Option<Region>,
+
+    /// Deinterlacing method for interlaced video modes
+    #[structopt(
+        long = "deinterlace",
+        default_value = "weave",
+        raw(possible_values = r#"&["weave", "bob"]"#)
+    )]
+    deinterlace: Deinterlace,
+
+    /// Override a setting of the game database (eg: --game save=sram32k)
+    #[structopt(long = "game", number_of_values = 1)]
+    game: Vec<String>,
+
+    /// Accessory plugged into the first controller [default: from game database]
     #[structopt(
-        short = "b",
-        long = "bios",
-        parse(from_os_str),
-        default_value = "bios/pifdata.bin"
+        long = "pak",
+        raw(possible_values = r#"&["none", "mempak", "rumble", "transfer"]"#)
     )]
-    bios: std::path::PathBuf,
+    pak: Option<PakType>,
+
+    /// Game Boy ROM inserted into the Transfer Pak (implies --pak=transfer)
+    #[structopt(long = "gb-rom", parse(from_os_str))]
+    gb_rom: Option<std::path::PathBuf>,
 
     /// Path to the ROM file
     #[structopt(parse(from_os_str))]
@@ -34,24 +72,69 @@ struct Cli {
 
 quick_main!(run);
 
-fn create_n64(romfn: &Path, biosfn: &Path, logger: slog::Logger) -> Result<N64> {
-    let mut n64 = N64::new(logger, romfn, biosfn).unwrap();
+// Lookup the game in the database, and apply the overrides specified
+// on the command line.
+fn game_info(args: &Cli, header: &RomHeader) -> Result<GameInfo> {
+    let mut game = GameDb::builtin().lookup(header);
+    for opt in &args.game {
+        game.set_option(opt)?;
+    }
+    Ok(game)
+}
+
+// Create the controller accessory selected on the command line.
+fn create_pak(args: &Cli, pak: PakType) -> Result<Option<Accessory>> {
+    Ok(match pak {
+        PakType::None => None,
+        PakType::Mempak => Some(Accessory::Mempak(Mempak::load(
+            &args.rom.with_extension("mpk"),
+        )?)),
+        PakType::Rumble => Some(Accessory::Rumble(RumblePak::new())),
+        PakType::Transfer => {
+            let cart = match &args.gb_rom {
+                Some(path) => Some(GbCart::load(path)?),
+                None => None,
+            };
+            Some(Accessory::Transfer(TransferPak::new(cart)))
+        }
+    })
+}
+
+fn create_n64(args: &Cli, rom: Vec<u8>, game: GameInfo, logger: slog::Logger) -> Result<N64> {
+    let bios = args.bios.as_ref().map(|p| p.as_path());
+    let mut n64 = N64::with_game_info(logger, &args.rom, rom, bios, game).unwrap();
+    if let Some(region) = args.region {
+        n64.set_region(region);
+    }
+    let pak = args.pak.or(args.gb_rom.as_ref().map(|_| PakType::Transfer));
+    if let Some(pak) = pak {
+        n64.set_accessory(0, create_pak(args, pak)?)?;
+    }
+    n64.set_deinterlace(args.deinterlace);
+    if let Some(path) = &args.dp_capture {
+        n64.start_rdp_capture(path)?;
+    }
     n64.setup_cic(true)?;
     Ok(n64)
 }
 
 fn run() -> Result<()> {
     let args = Cli::from_args();
+    let rom = load_rom(&args.rom)?;
+    let header = RomHeader::parse(&rom);
+    let game = game_info(&args, &header)?;
+    let region = args.region.or(game.region).unwrap_or(header.region());
 
     let mut out = hw::Output::new(
         hw::VideoConfig {
-            window_title: "R64EMU - Nintendo 64 Emulator".into(),
+            window_title: format!("R64EMU - {} [{}]", header.title, header.game_code),
             width: 640,
             height: 480,
-            fps: 60,
+            fps: region.fps(),
         },
         hw::AudioConfig {
             frequency: N64::AUDIO_OUTPUT_FREQUENCY as isize,
+            latency: Duration::from_millis(args.audio_latency),
         },
     )?;
     out.enable_video()?;
@@ -59,14 +142,14 @@ fn run() -> Result<()> {
 
     if args.debugger {
         let (logger, logpool) = log::new_pool_logger();
-        let mut n64 = create_n64(&args.rom, &args.bios, logger).unwrap();
+        let mut n64 = create_n64(&args, rom, game, logger).unwrap();
         let mut dbgconfig = args.rom.clone();
         dbgconfig.set_extension("dbg");
         out.run_and_debug(&mut n64, &dbgconfig, logpool);
     } else {
         out.run_threaded(move || {
             let logger = log::new_console_logger();
-            let n64 = create_n64(&args.rom, &args.bios, logger).unwrap();
+            let n64 = create_n64(&args, rom, game, logger).unwrap();
             Ok(Box::new(n64))
         });
     }
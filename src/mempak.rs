@@ -0,0 +1,337 @@
+//! Controller Pak (memory pak): 32KB of battery-backed SRAM that plugs into
+//! a controller, and is accessed through joybus accessory commands.
+//!
+//! Contents are stored on disk in the standard .mpk format (a raw dump of
+//! the SRAM), which is compatible with other emulators. The SRAM holds a
+//! small filesystem made of 128 pages of 256 bytes:
+//!
+//!   page 0       ID area (label, serial number, checksums)
+//!   page 1       inode table: for each page, the next page of the note
+//!   page 2       backup copy of the inode table
+//!   pages 3-4    note table: 16 entries of 32 bytes
+//!   pages 5-127  note data
+use crate::errors::*;
+use crate::savefile::SaveFile;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::path::Path;
+
+pub const SIZE: usize = 0x8000;
+pub const PAGE_SIZE: usize = 256;
+pub const NUM_PAGES: usize = SIZE / PAGE_SIZE;
+pub const NUM_NOTES: usize = 16;
+
+/// Size of a note table entry, which is also the header of an exported note.
+pub const NOTE_ENTRY_SIZE: usize = 32;
+
+const INODE_PAGE: usize = 1;
+const INODE_BACKUP_PAGE: usize = 2;
+const NOTE_TABLE_PAGE: usize = 3;
+const FIRST_DATA_PAGE: usize = 5;
+
+// Special inode values
+const INODE_END: u16 = 0x0001;
+const INODE_FREE: u16 = 0x0003;
+
+// ID block written by the libultra formatting routine. The last 4 bytes
+// are the checksums, computed in format().
+const ID_BLOCK: [u8; 28] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0x05, 0x1A, 0x5F, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0xFF,
+];
+
+// The ID block is stored at these offsets within page 0 (main copy + 3 backups).
+const ID_BLOCK_OFFSETS: [usize; 4] = [0x20, 0x60, 0x80, 0xC0];
+
+/// A note (save file) stored in the Controller Pak.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+    /// Index in the note table
+    pub index: usize,
+    pub game_code: String,
+    pub publisher: String,
+    pub name: String,
+    pub extension: String,
+    /// Number of pages (of 256 bytes) used by the note
+    pub pages: usize,
+}
+
+pub struct Mempak {
+    data: Vec<u8>,
+    save: SaveFile,
+}
+
+impl Mempak {
+    /// Create a freshly formatted Controller Pak, which is not persisted
+    /// on disk.
+    pub fn new() -> Mempak {
+        let mut pak = Mempak {
+            data: vec![0; SIZE],
+            save: SaveFile::none(),
+        };
+        pak.format();
+        pak
+    }
+
+    /// Create a Controller Pak backed by the specified .mpk file. If the
+    /// file exists, its contents are loaded; otherwise, the pak is formatted.
+    pub fn load(path: &Path) -> Result<Mempak> {
+        let mut pak = Mempak::new();
+        pak.save = SaveFile::new(path);
+        let len = pak.save.load(&mut pak.data)?;
+        if len != 0 && len < SIZE {
+            bail!("invalid Controller Pak file: {}", path.display());
+        }
+        Ok(pak)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Erase all notes, and write an empty filesystem.
+    pub fn format(&mut self) {
+        for v in self.data.iter_mut() {
+            *v = 0;
+        }
+
+        // Label area
+        for (i, v) in self.data[0..0x20].iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        self.data[0] = 0x81;
+
+        let mut id = [0u8; 32];
+        id[..ID_BLOCK.len()].copy_from_slice(&ID_BLOCK);
+        let sum = (0..14).fold(0u16, |sum, i| {
+            sum.wrapping_add(BigEndian::read_u16(&id[i * 2..]))
+        });
+        BigEndian::write_u16(&mut id[28..], sum);
+        BigEndian::write_u16(&mut id[30..], 0xFFF2u16.wrapping_sub(sum));
+        for off in ID_BLOCK_OFFSETS.iter() {
+            self.data[*off..*off + 32].copy_from_slice(&id);
+        }
+
+        for page in 0..NUM_PAGES {
+            self.set_inode(page, INODE_FREE);
+        }
+        self.update_inodes();
+    }
+
+    /// Read a block of data (accessory read command). Addresses outside
+    /// of the SRAM read as zero.
+    pub fn read(&self, addr: u16, out: &mut [u8]) {
+        let addr = addr as usize;
+        if addr + out.len() <= SIZE {
+            out.copy_from_slice(&self.data[addr..addr + out.len()]);
+        } else {
+            for v in out.iter_mut() {
+                *v = 0;
+            }
+        }
+    }
+
+    /// Write a block of data (accessory write command). Writes outside
+    /// of the SRAM are ignored.
+    pub fn write(&mut self, addr: u16, data: &[u8]) {
+        let addr = addr as usize;
+        if addr + data.len() <= SIZE {
+            self.data[addr..addr + data.len()].copy_from_slice(data);
+            self.save.modified();
+        }
+    }
+
+    fn inode(&self, page: usize) -> u16 {
+        BigEndian::read_u16(&self.data[INODE_PAGE * PAGE_SIZE + page * 2..])
+    }
+
+    fn set_inode(&mut self, page: usize, val: u16) {
+        BigEndian::write_u16(&mut self.data[INODE_PAGE * PAGE_SIZE + page * 2..], val);
+    }
+
+    // Recompute the inode table checksum (stored in the second byte of
+    // the table, and covering the entries of the data pages), and update
+    // the backup copy.
+    fn update_inodes(&mut self) {
+        let table = INODE_PAGE * PAGE_SIZE;
+        let sum = self.data[table + FIRST_DATA_PAGE * 2..table + PAGE_SIZE]
+            .iter()
+            .fold(0u8, |sum, v| sum.wrapping_add(*v));
+        self.data[table] = 0;
+        self.data[table + 1] = sum;
+
+        let (inodes, backup) = self.data.split_at_mut(INODE_BACKUP_PAGE * PAGE_SIZE);
+        backup[..PAGE_SIZE].copy_from_slice(&inodes[table..table + PAGE_SIZE]);
+        self.save.modified();
+    }
+
+    fn note_entry(&self, index: usize) -> &[u8] {
+        let off = NOTE_TABLE_PAGE * PAGE_SIZE + index * NOTE_ENTRY_SIZE;
+        &self.data[off..off + NOTE_ENTRY_SIZE]
+    }
+
+    fn note_entry_mut(&mut self, index: usize) -> &mut [u8] {
+        let off = NOTE_TABLE_PAGE * PAGE_SIZE + index * NOTE_ENTRY_SIZE;
+        &mut self.data[off..off + NOTE_ENTRY_SIZE]
+    }
+
+    // First page of a note, or None if the note table entry is empty.
+    fn note_start(&self, index: usize) -> Option<usize> {
+        let start = BigEndian::read_u16(&self.note_entry(index)[6..]) as usize;
+        if start >= FIRST_DATA_PAGE && start < NUM_PAGES {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    // Follow the inode chain of a note, returning the list of its pages.
+    fn note_pages(&self, index: usize) -> Result<Vec<usize>> {
+        let mut pages = vec![];
+        let mut page = match self.note_start(index) {
+            Some(start) => start,
+            None => bail!("note {} is empty", index),
+        };
+        loop {
+            if pages.len() >= NUM_PAGES || pages.contains(&page) {
+                bail!("corrupted inode table (loop in note {})", index);
+            }
+            pages.push(page);
+            match self.inode(page) {
+                INODE_END => return Ok(pages),
+                next if next as usize >= FIRST_DATA_PAGE && (next as usize) < NUM_PAGES => {
+                    page = next as usize
+                }
+                next => bail!("corrupted inode table (page {} -> {:#x})", page, next),
+            }
+        }
+    }
+
+    /// Number of pages not allocated to any note.
+    pub fn free_pages(&self) -> usize {
+        (FIRST_DATA_PAGE..NUM_PAGES)
+            .filter(|p| self.inode(*p) == INODE_FREE)
+            .count()
+    }
+
+    /// List the notes stored in the Controller Pak.
+    pub fn notes(&self) -> Vec<Note> {
+        (0..NUM_NOTES)
+            .filter(|idx| self.note_start(*idx).is_some())
+            .map(|idx| {
+                let entry = self.note_entry(idx);
+                Note {
+                    index: idx,
+                    game_code: String::from_utf8_lossy(&entry[0..4]).into_owned(),
+                    publisher: String::from_utf8_lossy(&entry[4..6]).into_owned(),
+                    name: decode_text(&entry[0x10..0x20]),
+                    extension: decode_text(&entry[0x0C..0x10]),
+                    pages: self.note_pages(idx).map(|p| p.len()).unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+
+    /// Export a note: the result is its note table entry, followed by the
+    /// contents of its pages.
+    pub fn export_note(&self, index: usize) -> Result<Vec<u8>> {
+        if index >= NUM_NOTES {
+            bail!("invalid note index: {}", index);
+        }
+        let mut out = self.note_entry(index).to_vec();
+        for page in self.note_pages(index)? {
+            out.extend_from_slice(&self.data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE]);
+        }
+        Ok(out)
+    }
+
+    /// Import a note previously exported with `export_note`, allocating
+    /// a free note table entry and free pages. Returns the index of the
+    /// new note.
+    pub fn import_note(&mut self, note: &[u8]) -> Result<usize> {
+        if note.len() <= NOTE_ENTRY_SIZE || (note.len() - NOTE_ENTRY_SIZE) % PAGE_SIZE != 0 {
+            bail!("invalid note size: {}", note.len());
+        }
+        let npages = (note.len() - NOTE_ENTRY_SIZE) / PAGE_SIZE;
+
+        let index = match (0..NUM_NOTES).find(|idx| self.note_start(*idx).is_none()) {
+            Some(idx) => idx,
+            None => bail!("no free note entries in Controller Pak"),
+        };
+        let pages = (FIRST_DATA_PAGE..NUM_PAGES)
+            .filter(|p| self.inode(*p) == INODE_FREE)
+            .take(npages)
+            .collect::<Vec<_>>();
+        if pages.len() < npages {
+            bail!(
+                "not enough free pages in Controller Pak (need {}, found {})",
+                npages,
+                pages.len()
+            );
+        }
+
+        for (i, page) in pages.iter().enumerate() {
+            let src = &note[NOTE_ENTRY_SIZE + i * PAGE_SIZE..NOTE_ENTRY_SIZE + (i + 1) * PAGE_SIZE];
+            self.data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].copy_from_slice(src);
+            let next = pages.get(i + 1).map(|p| *p as u16).unwrap_or(INODE_END);
+            self.set_inode(*page, next);
+        }
+        self.update_inodes();
+
+        let entry = self.note_entry_mut(index);
+        entry.copy_from_slice(&note[..NOTE_ENTRY_SIZE]);
+        BigEndian::write_u16(&mut entry[6..], pages[0] as u16);
+        Ok(index)
+    }
+
+    /// Delete a note, freeing its pages.
+    pub fn delete_note(&mut self, index: usize) -> Result<()> {
+        if index >= NUM_NOTES {
+            bail!("invalid note index: {}", index);
+        }
+        for page in self.note_pages(index)? {
+            self.set_inode(page, INODE_FREE);
+        }
+        self.update_inodes();
+        for v in self.note_entry_mut(index) {
+            *v = 0;
+        }
+        Ok(())
+    }
+
+    /// Write the Controller Pak contents to its save file, if they were
+    /// modified.
+    pub fn flush(&mut self) -> Result<()> {
+        self.save.flush(&self.data)
+    }
+
+    /// Called once per frame.
+    pub fn autosave(&mut self) -> Result<()> {
+        self.save.end_frame(&self.data)
+    }
+}
+
+impl Default for Mempak {
+    fn default() -> Mempak {
+        Mempak::new()
+    }
+}
+
+// Decode a string in the N64 font encoding used for note names, stopping
+// at the first NUL character. Characters outside of the ASCII subset
+// (eg: katakana) are shown as '?'.
+fn decode_text(text: &[u8]) -> String {
+    const PUNCT: &[u8] = b"!\"#'*+,-./:=?@";
+    let s = text
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|&c| match c {
+            0x0F => ' ',
+            0x10..=0x19 => (b'0' + c - 0x10) as char,
+            0x1A..=0x33 => (b'A' + c - 0x1A) as char,
+            0x34..=0x41 => PUNCT[(c - 0x34) as usize] as char,
+            _ => '?',
+        })
+        .collect::<String>();
+    s.trim_end().to_owned()
+}
@@ -1,4 +1,5 @@
 use emu::bus::be::{Bus, Device};
+use emu::bus::CurrentDeviceMap;
 use emu::dbg;
 use emu::dbg::{DebuggerModel, DebuggerRenderer};
 use emu::gfx::{GfxBufferMutLE, Rgb888};
@@ -13,19 +14,27 @@ use emu_derive::DeviceBE;
 use slog;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
+use std::str::FromStr;
 
+use super::accessory::{Accessory, RumblePak};
 use super::ai::Ai;
-use super::cartridge::{Cartridge, CicModel};
+use super::cartridge::{load_rom, Cartridge, CicModel, RomHeader, SaveType};
+use super::eeprom::Eeprom;
 use super::dp::Dp;
 use super::errors::*;
+use super::flashram::FlashRam;
+use super::gamedb::{Accessories, GameDb, GameInfo};
+use super::joybus::JoybusDevice;
+use super::mempak::Mempak;
 use super::mi::Mi;
 use super::mips64;
 use super::pi::Pi;
 use super::r4300::R4300;
 use super::ri::Ri;
 use super::si::Si;
+use super::sram::Sram;
 use super::sp::{Sp, RSPCPU};
-use super::vi::Vi;
+use super::vi::{Deinterlace, Vi};
 
 // Used in debugger windows
 pub(crate) const MAINCPU_NAME: &'static str = "R4300";
@@ -35,6 +44,7 @@ pub struct N64 {
     logger: slog::Logger,
     sync: Box<sync::Sync<SyncEmu>>,
     initial_state: State,
+    game: GameInfo,
 }
 
 // N64 timings
@@ -42,24 +52,97 @@ pub struct N64 {
 
 // Oscillators
 const X1: i64 = 14_705_000;
-const X2: i64 = 14_318_000;
+const X2: i64 = 14_318_000; // NTSC
+const X2_PAL: i64 = 17_734_475;
+const X2_MPAL: i64 = 14_302_446;
 
 const RDRAM_CLOCK: i64 = X1 * 17;
-const MAIN_CLOCK: i64 = RDRAM_CLOCK / 4;
+pub(crate) const MAIN_CLOCK: i64 = RDRAM_CLOCK / 4;
 const _PIF_CLOCK: i64 = MAIN_CLOCK / 4;
 const _CARTRIDGE_CLOCK: i64 = _PIF_CLOCK / 8; // 1.953 MHZ
-pub(crate) const VCLK: i64 = X2 * 17 / 5; // 48.6812 MHZ
+
+/// Console region, which selects the video standard.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Mpal,
+}
+
+impl Region {
+    /// Video clock (VCLK), from which the VI and AI timings are derived.
+    pub fn vclk(self) -> i64 {
+        match self {
+            Region::Ntsc => X2 * 17 / 5,      // 48.6812 MHZ
+            Region::Pal => X2_PAL * 14 / 5,   // 49.6565 MHZ
+            Region::Mpal => X2_MPAL * 17 / 5, // 48.6283 MHZ
+        }
+    }
+
+    /// TV type, as reported by IPL to the game (register S4 at boot,
+    /// osTvType in libultra).
+    pub fn tv_type(self) -> u32 {
+        match self {
+            Region::Pal => 0,
+            Region::Ntsc => 1,
+            Region::Mpal => 2,
+        }
+    }
+
+    /// Nominal frame rate of the video standard.
+    pub fn fps(self) -> isize {
+        match self {
+            Region::Pal => 50,
+            Region::Ntsc | Region::Mpal => 60,
+        }
+    }
+
+    /// Standard VI timing for the video standard, as
+    /// (V_SYNC, H_SYNC, H_SYNC_LEAP). This is used until the game
+    /// programs the VI.
+    pub(crate) fn vi_timing(self) -> (u32, u32, u32) {
+        match self {
+            Region::Ntsc => (0x20D, 0xC15, 0x0C15_0C15),
+            Region::Pal => (0x271, 0x0015_0C69, 0x0C6F_0C6E),
+            Region::Mpal => (0x20D, 0x0004_0C11, 0x0C19_0C1A),
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "mpal" => Ok(Region::Mpal),
+            _ => Err(format!("invalid region: {}", s)),
+        }
+    }
+}
 
 struct SyncEmu;
 impl sync::SyncEmu for SyncEmu {
     fn config(&self) -> sync::Config {
+        // The VI is not available yet when the Sync object is created,
+        // so use the standard NTSC timing until then.
+        let (region, (vlines, line, leap)) = match CurrentDeviceMap().get::<Vi>() {
+            Some(vi) => (vi.region(), vi.timing()),
+            None => (Region::Ntsc, (526, 3094, 0)),
+        };
+
+        // Each line of the sync is a half-line (as counted by the VI), and
+        // each dot is a VCLK cycle.
+        let hdots = line / 2;
         sync::Config {
-            main_clock: VCLK,
-            dot_clock_divider: 2,
-            hdots: 773, // 773.5...
-            vdots: 525,
-            hsyncs: vec![0, 773 / 2], // sync two times per line
+            main_clock: region.vclk(),
+            dot_clock_divider: 1,
+            hdots,
+            vdots: vlines,
+            hsyncs: vec![0, hdots / 2], // sync two times per half-line
             vsyncs: vec![],
+            leap_dots: leap,
         }
     }
     fn subsystem(&self, idx: usize) -> Option<(&mut dyn sync::Subsystem, i64)> {
@@ -67,13 +150,24 @@ impl sync::SyncEmu for SyncEmu {
             0 => Some((R4300::get_mut().deref_mut(), MAIN_CLOCK + MAIN_CLOCK / 2)), // FIXME: uses DIVMOD),
             1 => Some((RSPCPU::get_mut().deref_mut(), MAIN_CLOCK)),
             2 => Some((Dp::get_mut(), MAIN_CLOCK)),
-            3 => Some((Ai::get_mut(), VCLK)),
+            3 => Some((Ai::get_mut(), Vi::get().region().vclk())),
             4 => Some((Pi::get_mut(), MAIN_CLOCK)),
             _ => None,
         }
     }
 }
 
+// Periodically save the cartridge SRAM/FlashRAM to disk (the EEPROM is
+// handled by PI).
+fn autosave() {
+    if let Some(sram) = CurrentDeviceMap().get_mut::<Sram>() {
+        sram.end_frame();
+    }
+    if let Some(flash) = CurrentDeviceMap().get_mut::<FlashRam>() {
+        flash.end_frame();
+    }
+}
+
 pub(crate) const JOY_NAMES: [&'static str; 4] = ["joy1", "joy2", "joy3", "joy4"];
 
 fn create_input_manager() -> InputManager {
@@ -116,14 +210,48 @@ fn create_input_manager() -> InputManager {
 impl N64 {
     pub const AUDIO_OUTPUT_FREQUENCY: i64 = Ai::OUTPUT_FREQUENCY;
 
-    pub fn new(logger: slog::Logger, romfn: &Path, biosfn: &Path) -> Result<N64> {
+    /// Create a N64 running the specified ROM. If no BIOS (PIF ROM) is
+    /// specified, the PIF boot code is emulated.
+    pub fn new(logger: slog::Logger, romfn: &Path, biosfn: Option<&Path>) -> Result<N64> {
+        let rom = load_rom(romfn).chain_err(|| "cannot open rom file")?;
+        N64::create(logger, romfn, rom, biosfn, None)
+    }
+
+    /// Create a N64 running a ROM image already loaded with
+    /// [`load_rom`](cartridge/fn.load_rom.html), using the specified game
+    /// settings instead of looking up the ROM in the builtin game database
+    /// (eg: to apply overrides). `romfn` is used to locate the save files.
+    pub fn with_game_info(
+        logger: slog::Logger,
+        romfn: &Path,
+        rom: Vec<u8>,
+        biosfn: Option<&Path>,
+        game: GameInfo,
+    ) -> Result<N64> {
+        N64::create(logger, romfn, rom, biosfn, Some(game))
+    }
+
+    fn create(
+        logger: slog::Logger,
+        romfn: &Path,
+        rom: Vec<u8>,
+        biosfn: Option<&Path>,
+        game: Option<GameInfo>,
+    ) -> Result<N64> {
         let sync = sync::Sync::new(logger.new(o!()), SyncEmu);
 
         R4300::new(sync::Sync::new_logger(&sync)).register();
         Mi::new(sync::Sync::new_logger(&sync)).register();
-        Cartridge::new(romfn)
-            .chain_err(|| "cannot open rom file")?
-            .register();
+        Cartridge::from_image(rom).register();
+
+        let header = Cartridge::get().header();
+        info!(logger, "loaded ROM"; o!(
+            "title" => header.title.clone(),
+            "game_code" => header.game_code.clone(),
+            "version" => header.version,
+            "region" => format!("{:?}", header.region()),
+            "libultra" => header.libultra_version()));
+        let game = game.unwrap_or_else(|| GameDb::builtin().lookup(header));
 
         Pi::new(
             sync::Sync::new_logger(&sync),
@@ -139,59 +267,190 @@ impl N64 {
         Ai::new(sync::Sync::new_logger(&sync)).register();
         Ri::new(sync::Sync::new_logger(&sync)).register();
 
+        // Connect the cartridge save memory, stored in a file next to the ROM.
+        let save_type = game
+            .save_type
+            .or(header.save_type())
+            .unwrap_or(SaveType::None);
+        let savefn = |ext: &str| romfn.with_extension(ext);
+        match save_type {
+            SaveType::Eeprom4k | SaveType::Eeprom16k => {
+                let size = if save_type == SaveType::Eeprom4k {
+                    Eeprom::SIZE_4K
+                } else {
+                    Eeprom::SIZE_16K
+                };
+                let eeprom = Eeprom::load(size, &savefn("eep"))?;
+                Pi::get_mut().set_eeprom(Some(eeprom))?;
+            }
+            SaveType::Sram32k | SaveType::Sram96k => {
+                Sram::new(sync::Sync::new_logger(&sync), save_type, &savefn("sra"))?.register()
+            }
+            SaveType::FlashRam => {
+                FlashRam::new(sync::Sync::new_logger(&sync), &savefn("fla"))?.register()
+            }
+            SaveType::None => {}
+        }
+        info!(logger, "save memory"; o!("type" => format!("{:?}", save_type)));
+
+        // Plug an accessory supported by the game into the first controller
+        // (the Controller Pak, unless the game is known not to support it).
+        // The Transfer Pak needs a Game Boy cartridge, so it must be
+        // explicitly selected with set_accessory().
+        let accessories = game.accessories.unwrap_or(Accessories::MEMPAK);
+        let pak = if accessories.contains(Accessories::MEMPAK) {
+            Some(Accessory::Mempak(Mempak::load(&savefn("mpk"))?))
+        } else if accessories.contains(Accessories::RUMBLE) {
+            Some(Accessory::Rumble(RumblePak::new()))
+        } else {
+            None
+        };
+        Pi::get_mut().set_accessory(0, pak)?;
+
         // Now that all devices have been created, map the CPU buses.
         R4300::get_mut().map_bus()?;
         RSPCPU::get_mut().map_bus()?;
 
-        return Ok(N64 {
+        let n64 = N64 {
             logger,
             sync,
             initial_state: CurrentState().clone(),
-        });
+            game,
+        };
+
+        // Default to the region the game was released for; it can be
+        // changed with set_region() before setup_cic().
+        let region = n64.game.region.unwrap_or(header.region());
+        Vi::get_mut().set_region(region);
+
+        if n64.game.expansion_pak == Some(true) {
+            warn!(n64.logger, "game requires the Expansion Pak, which is not emulated");
+        }
+
+        match n64.cic_model() {
+            Ok(cic) if !Cartridge::get().verify_crc(cic) => {
+                warn!(n64.logger, "ROM checksum mismatch, the game will not boot"; o!(
+                    "crc1" => format!("{:08x}", header.crc1),
+                    "crc2" => format!("{:08x}", header.crc2)))
+            }
+            Ok(_) => {}
+            Err(err) => warn!(n64.logger, "cannot verify ROM checksum"; o!("err" => err.to_string())),
+        }
+        Ok(n64)
     }
 
-    // Setup the CIC (copy protection) emulation.
-    pub fn setup_cic(&mut self, hard_reset: bool) -> Result<()> {
-        // The 32-bit word at offset 0x24 in PIF RAM (bus addr: 0x1FC0_07E4)
-        // is filled by PIF during boot. It contains the encryption seed
-        // (that PIF got after negotiation with CIC), and some other information
-        // that the CPU can use.
-
-        // bits     | reg | description
-        // 00080000 | S3  | osRomType (0=GamePack, 1=DD)
-        // 00040000 | S7  | osVersion
-        // 00020000 | S5  | osResetType (1 = NMI, 0 = cold reset)
-        // 0000FF00 | S6  | CIC IPL3 seed value
-        // 000000FF | --  | CIC IPL2 seed value
-        // -------- | S4  | TV Type (0=PAL, 1=NTSC, 2=MPAL)
-
-        // Setup the encryption seed, given the CIC model that we detect
-        // by checksumming the ROM header.
-        let mut seed: u32 = match Cartridge::get().detect_cic_model()? {
-            CicModel::Cic6101 => 0x3F, // starfox
-            CicModel::Cic6102 => 0x3F, // mario
-            CicModel::Cic6103 => 0x78, // banjo
-            CicModel::Cic6105 => 0x91, // zelda
-            CicModel::Cic6106 => 0x85, // f-zero x
-        } << 8;
-
-        // Set the NMI/reset bit
-        if !hard_reset {
-            seed |= 0x0002_0000;
+    /// Save memory type of the cartridge, from the game database or the
+    /// ROM header.
+    pub fn save_type(&self) -> SaveType {
+        self.game
+            .save_type
+            .or(self.rom_header().save_type())
+            .unwrap_or(SaveType::None)
+    }
+
+    /// Write the contents of the cartridge save memory to disk. This is
+    /// also done periodically and when the emulator is dropped.
+    pub fn flush_saves(&mut self) -> Result<()> {
+        Pi::get_mut().flush_joybus()?;
+        if let Some(sram) = CurrentDeviceMap().get_mut::<Sram>() {
+            sram.flush()?;
+        }
+        if let Some(flash) = CurrentDeviceMap().get_mut::<FlashRam>() {
+            flash.flush()?;
+        }
+        Ok(())
+    }
+
+    // CIC model used by the cartridge, unless overridden by the game database.
+    fn cic_model(&self) -> Result<CicModel> {
+        match self.game.cic {
+            Some(cic) => Ok(cic),
+            None => Cartridge::get().detect_cic_model(),
+        }
+    }
+
+    /// Plug an accessory into the specified controller port (0-3), or
+    /// unplug the current one (if None).
+    pub fn set_accessory(&mut self, port: usize, pak: Option<Accessory>) -> Result<()> {
+        Pi::get_mut().set_accessory(port, pak)
+    }
+
+    /// Connect a device to the specified joybus channel: 0-3 are the
+    /// controller ports (by default, only a controller in the first port
+    /// is connected), and 4 is the cartridge.
+    pub fn set_joybus_device(&mut self, ch: usize, dev: JoybusDevice) -> Result<()> {
+        Pi::get_mut().set_joybus_device(ch, dev)
+    }
+
+    /// Controller Pak plugged into the specified controller port (0-3),
+    /// if any. This can be used to manage the notes stored in it.
+    pub fn mempak(&mut self, port: usize) -> Option<&mut Mempak> {
+        match Pi::get_mut().accessory_mut(port) {
+            Some(Accessory::Mempak(pak)) => Some(pak),
+            _ => None,
         }
+    }
+
+    /// Settings of the loaded game, from the game database.
+    pub fn game_info(&self) -> &GameInfo {
+        &self.game
+    }
+
+    /// Metadata of the loaded ROM.
+    pub fn rom_header(&self) -> &RomHeader {
+        Cartridge::get().header()
+    }
+
+    /// Select the console region. This must be called before `setup_cic`,
+    /// as it also changes the TV type reported to the game by the emulated
+    /// PIF boot. A real PIF ROM hardcodes the TV type, so it must match the
+    /// selected region.
+    pub fn set_region(&mut self, region: Region) {
+        Vi::get_mut().set_region(region);
+    }
 
-        R4300::get_mut().bus.write::<u32>(0x1FC0_07E4, seed);
+    /// Select how interlaced video modes are displayed.
+    pub fn set_deinterlace(&mut self, mode: Deinterlace) {
+        Vi::get_mut().set_deinterlace(mode);
+    }
 
-        // FIXME: fix RDRAM initialization emulation. IPL3 does initialize RDRAM (starting at 0x0400_0040),
-        // and is supposed to end up writing the RAM size at 0x8000_0318, but it does not currently work.
-        // This is relied upon by libdragon at least. So fix it by setting the RDRAM as already initialized
-        // and copying the RAM size.
+    /// Capture the RDP command stream into the specified file, for
+    /// offline replay (see the `dpreplay` tool).
+    pub fn start_rdp_capture(&mut self, path: &Path) -> Result<()> {
+        Dp::get_mut().start_capture(path)
+    }
+
+    /// Reset the PIF boot process. PIF negotiates with the CIC (copy
+    /// protection) of the cartridge, and then lets the CPU execute IPL.
+    pub fn setup_cic(&mut self, hard_reset: bool) -> Result<()> {
+        let cic = self.cic_model()?;
+        let tv_type = Vi::get().region().tv_type();
+        Pi::get_mut().reset(cic, tv_type, hard_reset);
+
+        // HACK: IPL3 initializes RDRAM by calibrating the RI current and
+        // programming each RDRAM module through its registers, and then
+        // stores the detected RAM size at 0x8000_0318 (osMemSize). None of
+        // that is emulated (the RDRAM registers are plain storage), so
+        // IPL3 would fail to detect any memory. Instead, report RDRAM as
+        // already initialized through RI_SELECT, which makes IPL3 skip the
+        // sequence (as after a soft reset), and store the RAM size ourselves.
+        // Modelling RI/RDRAM initialization is out of scope of the PIF/CIC
+        // boot emulation.
+        let rdram_size = Ri::get().rdram.len() as u32;
         R4300::get_mut().bus.write::<u32>(0x0470_000C, 0x14);
-        R4300::get_mut().bus.write::<u32>(0x0000_0318, 4*1024*1024);
+        R4300::get_mut().bus.write::<u32>(0x0000_0318, rdram_size);
         Ok(())
     }
 }
 
+impl Drop for N64 {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush_saves() {
+            error!(self.logger, "cannot save game"; o!("err" => err.to_string()));
+        }
+    }
+}
+
 impl hw::OutputProducer for N64 {
     type AudioSampleFormat = S16_STEREO;
 
@@ -213,6 +472,7 @@ impl hw::OutputProducer for N64 {
                 Vi::get_mut().end_frame(screen);
                 Ai::get_mut().end_frame(sound);
                 Pi::get_mut().end_frame();
+                autosave();
             }
             _ => {}
         });
@@ -241,6 +501,7 @@ impl DebuggerModel for N64 {
                     Vi::get_mut().end_frame(screen);
                     Ai::get_mut().end_frame(sound);
                     Pi::get_mut().end_frame();
+                    autosave();
                 }
                 sync::Event::HSync(x, y) if x == 0 => {
                     Vi::get_mut().set_line(y);
@@ -263,6 +524,9 @@ impl DebuggerModel for N64 {
     fn render_debug<'a, 'ui>(&mut self, dr: &DebuggerRenderer<'a, 'ui>) {
         R4300::get_mut().render_debug(dr);
         RSPCPU::get_mut().render_debug(dr);
+        Dp::get_mut().render_debug(dr);
+        dr.render_optionview(Vi::get_mut());
+        dr.render_infoview(Cartridge::get());
     }
 
     fn all_cpus(&self) -> Vec<String> {
@@ -1,22 +1,50 @@
+use super::accessory::Accessory;
+use super::cartridge::{Cartridge, CicModel};
+use super::cic;
+use super::eeprom::Eeprom;
+use super::flashram::FlashRam;
+use super::ipl;
+use super::joybus::{Joybus, JoybusDevice, CARTRIDGE_CHANNEL};
 use super::mi::{IrqMask, Mi};
 use super::r4300::R4300;
-use super::n64::JOY_NAMES;
 use super::si::Si;
 use crate::errors::*;
-use bitfield::Bit;
 use byteorder::{BigEndian, ByteOrder};
 use emu::bus::be::{Device, Mem, MemFlags, Reg32};
+use emu::bus::CurrentDeviceMap;
 use emu::dbg;
-use emu::input::{InputManager, InputValue};
+use emu::input::InputManager;
 use emu::int::Numerics;
 use emu::state::Field;
 use emu::sync;
 use emu_derive::DeviceBE;
+use serde_derive::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::Read;
-use std::ops::Range;
 use std::path::Path;
-use std::result;
+
+// Bits of the PIF control byte (last byte of PIF RAM). Each command bit
+// is cleared by PIF once the command has been executed.
+const PIF_CMD_JOYBUS: u8 = 0x01;
+const PIF_CMD_CHALLENGE: u8 = 0x02;
+const PIF_CMD_TERMINATE_BOOT: u8 = 0x08;
+const PIF_CMD_LOCK_ROM: u8 = 0x10;
+const PIF_CMD_CHECKSUM: u8 = 0x20;
+const PIF_CMD_CLEAR_RAM: u8 = 0x40;
+const PIF_CHECKSUM_OK: u8 = 0x80;
+
+/// State of the PIF boot process.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum BootState {
+    /// Waiting for IPL to request the checksum verification.
+    WaitChecksum,
+    /// Checksum verified, waiting for IPL3 to terminate the boot process.
+    WaitTerminate,
+    /// Boot terminated: the game is running.
+    Run,
+    /// Checksum verification failed: PIF halts the console.
+    Halted,
+}
 
 #[derive(DeviceBE)]
 pub struct Pi {
@@ -82,20 +110,41 @@ pub struct Pi {
 
     logger: slog::Logger,
     cycles: Field<i64>,
+    boot: Field<BootState>,
+    rom_image: Option<Vec<u8>>,
+    cic: Option<CicModel>,
     pub(crate) input: InputManager,
+    joybus: Joybus,
 }
 
 impl Pi {
-    pub fn new(logger: slog::Logger, pifrom: &Path, input: InputManager) -> Result<Box<Pi>> {
-        let mut contents = vec![];
-        File::open(pifrom)?.read_to_end(&mut contents)?;
+    /// Create the PI/PIF. If no PIF ROM is specified, the PIF boot code
+    /// (IPL1/IPL2) is emulated at reset, and the PIF ROM reads as zero.
+    pub fn new(
+        logger: slog::Logger,
+        pifrom: Option<&Path>,
+        input: InputManager,
+    ) -> Result<Box<Pi>> {
+        let rom_image = match pifrom {
+            Some(path) => {
+                let mut contents = vec![];
+                File::open(path)?.read_to_end(&mut contents)?;
+                Some(contents)
+            }
+            None => None,
+        };
+        let contents = rom_image.clone().unwrap_or_else(|| vec![0u8; 0x7C0]);
 
         Ok(Box::new(Pi {
             logger,
             rom: Mem::from_buffer("pif_rom", contents, MemFlags::READACCESS),
             ram: Mem::default(),
             cycles: Field::new("Pi::cycles", 0),
+            boot: Field::new("Pi::boot", BootState::WaitChecksum),
+            rom_image,
+            cic: None,
             input: input,
+            joybus: Joybus::new(),
             dma_ram_addr: Reg32::default(),
             dma_rom_addr: Reg32::default(),
             dma_rd_len: Reg32::default(),
@@ -112,12 +161,85 @@ impl Pi {
         }))
     }
 
+    /// Reset the PIF, as done at power-on (hard reset) or when the reset
+    /// button is pressed (NMI). PIF negotiates with the CIC and writes the
+    /// boot information into PIF RAM, then the CPU starts executing the PIF
+    /// ROM (IPL1/IPL2). Without a PIF ROM, IPL1/IPL2 are emulated instead,
+    /// and the CPU is left at the start of IPL3.
+    pub(crate) fn reset(&mut self, cic: CicModel, tv_type: u32, hard_reset: bool) {
+        self.cic = Some(cic);
+        *self.boot = BootState::WaitChecksum;
+        BigEndian::write_u32(&mut self.ram[0x24..], cic::boot_info(cic, hard_reset));
+
+        if let Some(image) = &self.rom_image {
+            // Undo the ROM lockout. The TV type is hardcoded in the PIF ROM,
+            // so a PIF ROM of the selected region must be used.
+            self.rom.copy_from_slice(image);
+        } else {
+            // Do what IPL2 would do: request the checksum verification
+            // and, if successful, jump to IPL3. Otherwise, IPL2 waits
+            // forever for PIF, so just halt the CPU.
+            self.pif_checksum();
+            if *self.boot == BootState::Halted {
+                R4300::get_mut().ctx_mut().set_halt_line(true);
+            } else {
+                info!(self.logger, "HLE PIF boot"; o!("cic" => format!("{:?}", cic), "tv_type" => tv_type));
+                ipl::hle_boot(self, cic, tv_type, hard_reset);
+            }
+        }
+    }
+
+    // Checksum verification, requested by IPL2: PIF checks the cartridge
+    // with the CIC, and lets the boot proceed only if it matches.
+    fn pif_checksum(&mut self) {
+        if *self.boot != BootState::WaitChecksum {
+            warn!(self.logger, "PIF checksum request outside of boot"; o!("state" => format!("{:?}", *self.boot)));
+            return;
+        }
+        match self.cic {
+            Some(cic) if !Cartridge::get().verify_crc(cic) => {
+                error!(self.logger, "PIF checksum verification failed, halting console"; o!("cic" => format!("{:?}", cic)));
+                *self.boot = BootState::Halted;
+            }
+            _ => {
+                info!(self.logger, "PIF checksum verified");
+                self.ram[0x3F] |= PIF_CHECKSUM_OK;
+                *self.boot = BootState::WaitTerminate;
+            }
+        }
+    }
+
+    // CIC challenge, sent by the game: the challenge is in PIF RAM at
+    // 0x30-0x3E, and is replaced by the CIC response.
+    fn pif_challenge(&mut self) {
+        let mut challenge = [0u8; 15];
+        challenge.copy_from_slice(&self.ram[0x30..0x3F]);
+        match self.cic.and_then(|cic| cic::challenge(cic, &challenge)) {
+            Some(response) => {
+                self.ram[0x2E] = 0;
+                self.ram[0x2F] = 0;
+                self.ram[0x30..0x3F].copy_from_slice(&response);
+            }
+            None => warn!(self.logger, "CIC challenge not supported by this CIC"; o!("cic" => format!("{:?}", self.cic))),
+        }
+    }
+
     fn cb_write_dma_status(&mut self, old: u32, new: u32) {
         self.dma_status.set(old); // write bits are not related to read bits
         info!(self.logger, "write dma status"; o!("val" => format!("{:x}", new)));
         Mi::get_mut().set_irq_line(IrqMask::PI, false);
     }
 
+    // FlashRAM contents are only accessible through DMA (with a different
+    // addressing than CPU accesses), so DMA to/from PI domain 2 must be
+    // special-cased when a FlashRAM is connected.
+    fn dma_flashram(addr: u32) -> Option<&'static mut FlashRam> {
+        match addr {
+            0x0800_0000..=0x0FFF_FFFF => CurrentDeviceMap().get_mut::<FlashRam>(),
+            _ => None,
+        }
+    }
+
     fn cb_write_dma_wr_len(&mut self, _old: u32, len: u32) {
         let mut raddr = self.dma_rom_addr.get();
         let mut waddr = self.dma_ram_addr.get();
@@ -127,129 +249,106 @@ impl Pi {
             "len" => len+1));
 
         let bus = &mut R4300::get_mut().bus;
-        let mut i = 0;
-        while i < len + 1 {
-            let data = bus.read::<u32>(raddr);
-            bus.write::<u32>(waddr, data);
-            raddr = raddr + 4;
-            waddr = waddr + 4;
-            i += 4;
+        if let Some(flash) = Pi::dma_flashram(raddr) {
+            let mut buf = vec![0u8; len as usize + 1];
+            flash.dma_read(raddr & 0x00FF_FFFF, &mut buf);
+            for v in buf {
+                bus.write::<u8>(waddr, v);
+                waddr += 1;
+            }
+            raddr += len + 1;
+        } else {
+            let mut i = 0;
+            while i < len + 1 {
+                let data = bus.read::<u32>(raddr);
+                bus.write::<u32>(waddr, data);
+                raddr = raddr + 4;
+                waddr = waddr + 4;
+                i += 4;
+            }
         }
         self.dma_rom_addr.set(raddr);
         self.dma_ram_addr.set(waddr);
         Mi::get_mut().set_irq_line(IrqMask::PI, true);
     }
 
-    fn cb_write_dma_rd_len(&mut self, _old: u32, val: u32) {
+    fn cb_write_dma_rd_len(&mut self, _old: u32, len: u32) {
         let mut raddr = self.dma_ram_addr.get();
         let mut waddr = self.dma_rom_addr.get();
         info!(self.logger, "DMA xfer"; o!(
             "src(ram)" => raddr.hex(),
             "dst(rom)" => waddr.hex(),
-            "len" => val+1));
+            "len" => len+1));
 
         let bus = &mut R4300::get_mut().bus;
-        let mut i = 0;
-        while i < val + 1 {
-            let v = bus.read::<u32>(raddr);
-            info!(self.logger, "DMA DATA"; "data" => v.hex());
-
-            raddr = raddr + 4;
-            waddr = waddr + 4;
-            i += 4;
+        if let Some(flash) = Pi::dma_flashram(waddr) {
+            let buf = (0..len + 1)
+                .map(|i| bus.read::<u8>(raddr + i))
+                .collect::<Vec<_>>();
+            flash.dma_write(&buf);
+            raddr += len + 1;
+            waddr += len + 1;
+        } else {
+            let mut i = 0;
+            while i < len + 1 {
+                let data = bus.read::<u32>(raddr);
+                bus.write::<u32>(waddr, data);
+                raddr = raddr + 4;
+                waddr = waddr + 4;
+                i += 4;
+            }
         }
+        self.dma_rom_addr.set(waddr);
+        self.dma_ram_addr.set(raddr);
         Mi::get_mut().set_irq_line(IrqMask::PI, true);
+    }
 
-        unimplemented!();
+    /// Set the PI domain 1 timing (latency, pulse width, page size and
+    /// release) from the first word of the cartridge header, as done by
+    /// the PIF boot code.
+    pub(crate) fn set_dom1_config(&mut self, cfg: u32) {
+        self.dom1_latency.set(cfg & 0xFF);
+        self.dom1_pulse_width.set((cfg >> 8) & 0xFF);
+        self.dom1_page_size.set((cfg >> 16) & 0xF);
+        self.dom1_release.set((cfg >> 20) & 0x3);
     }
 
-    pub fn begin_frame(&mut self) {
-        self.input.begin_frame();
+    /// Connect an EEPROM to the cartridge joybus channel (or disconnect it,
+    /// if None). The previous EEPROM, if any, is saved.
+    pub(crate) fn set_eeprom(&mut self, eeprom: Option<Eeprom>) -> Result<()> {
+        let dev = eeprom.map_or(JoybusDevice::None, JoybusDevice::Eeprom);
+        self.joybus.set_device(CARTRIDGE_CHANNEL, dev)
     }
-    pub fn end_frame(&mut self) {
-        self.input.end_frame();
+
+    /// Connect a device to the specified joybus channel.
+    pub(crate) fn set_joybus_device(&mut self, ch: usize, dev: JoybusDevice) -> Result<()> {
+        self.joybus.set_device(ch, dev)
     }
 
-    fn joybus_cmd(
-        &mut self,
-        ch: usize,
-        cmd: Range<usize>,
-        out: Range<usize>,
-    ) -> result::Result<(), &'static str> {
-        if cmd.len() == 0 {
-            return Err("joybus: 0-len command");
-        }
+    /// Plug an accessory into the specified controller port (or unplug it,
+    /// if None). The previous accessory, if any, is saved.
+    pub(crate) fn set_accessory(&mut self, port: usize, pak: Option<Accessory>) -> Result<()> {
+        self.joybus.set_accessory(port, pak)
+    }
 
-        match self.ram[cmd.start] {
-            0 => {
-                // Read controller status
-                if ch == 0 {
-                    self.ram[out.start + 0] = 0x05;
-                    self.ram[out.start + 1] = 0x00;
-                    self.ram[out.start + 2] = 0x02;
-                }
-            }
-            1 => {
-                // Read input data
-                if ch < 4 {
-                    let mut value: u32 = 0;
-                    self.input
-                        .device(JOY_NAMES[ch])
-                        .unwrap()
-                        .visit(|i| match i.value() {
-                            InputValue::Digital(val) => {
-                                if val {
-                                    value.set_bit(i.custom_id(), true);
-                                }
-                            }
-                            InputValue::Analog(val) => {
-                                value |= ((val >> 8) as u8 as u32) << i.custom_id()
-                            }
-                            _ => unreachable!(),
-                        });
-
-                    // S+Left+Right => Reset.
-                    if value.bit(21) && value.bit(20) && value.bit(18) {
-                        value.set_bit(23, true);
-                    }
-
-                    BigEndian::write_u32(&mut self.ram[out.start..], value);
-                }
-            }
-            _ => {
-                return Err("invalid command");
-            }
-        };
+    pub(crate) fn accessory_mut(&mut self, port: usize) -> Option<&mut Accessory> {
+        self.joybus.accessory_mut(port)
+    }
 
-        return Ok(());
+    /// Save the contents of the joybus devices (EEPROM, controller
+    /// accessories) to disk.
+    pub(crate) fn flush_joybus(&mut self) -> Result<()> {
+        self.joybus.flush()
     }
 
-    fn joybus_exec(&mut self) -> result::Result<(), &'static str> {
-        let mut ch = 0;
-        let mut idx = 0;
-        while idx < 0x3F {
-            let t = self.ram[idx];
-            idx += 1;
-            if t == 0xFE {
-                // Special marker: end of joybus
-                return Ok(());
-            }
-            if t < 0x80 {
-                let r = *self.ram.get(idx).ok_or("joybus: premature end of RAM")?;
-                idx += 1;
-
-                if ch >= 1 {
-                    self.ram[idx - 1] |= 0x80;
-                }
-
-                let mid = idx + t as usize;
-                let end = mid + r as usize;
-                self.joybus_cmd(ch, idx..mid, mid..end)?;
-                idx = end;
-                ch += 1;
-            }
+    pub fn begin_frame(&mut self) {
+        self.input.begin_frame();
+    }
+    pub fn end_frame(&mut self) {
+        self.input.end_frame();
+        if let Err(err) = self.joybus.end_frame() {
+            error!(self.logger, "cannot save joybus device"; o!("err" => err.to_string()));
         }
-        return Err("joybus: no PIFRAM marker found");
     }
 }
 
@@ -263,34 +362,55 @@ impl sync::Subsystem for Pi {
         // we can when we are called.
         *self.cycles = target_cycles;
 
-        let status = self.ram[0x3F];
-        if status & 0x20 != 0 {
-            info!(self.logger, "unlock boot");
-            self.ram[0x3F] |= 0x80;
-            self.ram[0x3F] &= !0x20;
+        let cmd = self.ram[0x3F];
+        if *self.boot == BootState::Halted {
+            return Ok(());
         }
 
-        if status & 0x01 != 0 {
-            info!(self.logger, "joybus triggered");
-            self.joybus_exec();
-            self.ram[0x3F] &= !1;
-
-            let mut mem = self.ram.iter();
-            for i in 0..8 {
-                println!(
-                    "JOY: {:03x}: {:02x} {:02x} {:02x} {:02x} -- {:02x} {:02x} {:02x} {:02x}",
-                    i * 8,
-                    mem.next().unwrap(),
-                    mem.next().unwrap(),
-                    mem.next().unwrap(),
-                    mem.next().unwrap(),
-                    mem.next().unwrap(),
-                    mem.next().unwrap(),
-                    mem.next().unwrap(),
-                    mem.next().unwrap()
-                );
+        if cmd & PIF_CMD_CLEAR_RAM != 0 {
+            info!(self.logger, "clear PIF RAM");
+            for v in self.ram.iter_mut() {
+                *v = 0;
+            }
+            return Ok(());
+        }
+
+        if cmd & PIF_CMD_CHECKSUM != 0 {
+            self.ram[0x3F] &= !PIF_CMD_CHECKSUM;
+            self.pif_checksum();
+        }
+
+        if cmd & PIF_CMD_LOCK_ROM != 0 {
+            // After the lockout, the PIF ROM reads as zero until next reset.
+            info!(self.logger, "lock PIF ROM");
+            self.ram[0x3F] &= !PIF_CMD_LOCK_ROM;
+            for v in self.rom.iter_mut() {
+                *v = 0;
             }
+        }
 
+        if cmd & PIF_CMD_TERMINATE_BOOT != 0 {
+            self.ram[0x3F] &= !PIF_CMD_TERMINATE_BOOT;
+            if *self.boot != BootState::WaitTerminate {
+                warn!(self.logger, "PIF boot terminated without checksum verification");
+            }
+            info!(self.logger, "PIF boot terminated");
+            *self.boot = BootState::Run;
+        }
+
+        if cmd & PIF_CMD_CHALLENGE != 0 {
+            info!(self.logger, "CIC challenge");
+            self.pif_challenge();
+            self.ram[0x3F] = 0;
+        }
+
+        if cmd & PIF_CMD_JOYBUS != 0 {
+            info!(self.logger, "joybus triggered");
+            let now = *self.cycles;
+            if let Err(err) = self.joybus.exec(&mut self.ram[..0x3F], &mut self.input, now) {
+                warn!(self.logger, "invalid joybus command"; o!("err" => err));
+            }
+            self.ram[0x3F] &= !PIF_CMD_JOYBUS;
             Si::get_mut().set_busy(false);
         }
 
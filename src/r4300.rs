@@ -1,16 +1,19 @@
 use std::ops::{Deref, DerefMut};
 use mips64;
 use emu::bus::be::{Bus, Device};
+use emu::bus::CurrentDeviceMap;
 
 use super::n64::MAINCPU_NAME;
 use super::ai::Ai;
 use super::cartridge::{Cartridge, CicModel};
 use super::dp::Dp;
 use super::errors::*;
+use super::flashram::FlashRam;
 use super::mi::Mi;
 use super::pi::Pi;
 use super::ri::Ri;
 use super::si::Si;
+use super::sram::Sram;
 use super::sp::{Sp, RSPCPU};
 use super::vi::Vi;
 
@@ -72,6 +75,14 @@ impl R4300 {
         self.bus.map_device(0x0460_0000, Pi::get(), 0)?;
         self.bus.map_device(0x0470_0000, Ri::get(), 2)?;
         self.bus.map_device(0x0480_0000, Si::get(), 0)?;
+        if let Some(sram) = CurrentDeviceMap().get::<Sram>() {
+            for bank in 0..sram.banks() {
+                self.bus.map_device(0x0800_0000 + bank as u32 * 0x4_0000, sram, bank)?;
+            }
+        }
+        if let Some(flash) = CurrentDeviceMap().get::<FlashRam>() {
+            self.bus.map_device(0x0800_0000, flash, 0)?;
+        }
         self.bus.map_device(0x1000_0000, Cartridge::get(), 0)?;
         self.bus.map_device(0x1800_0000, Cartridge::get(), 1)?;
         self.bus.map_device(0x1FC0_0000, Pi::get(), 1)?;
@@ -1,6 +1,8 @@
 extern crate byteorder;
 extern crate emu;
+use super::r4300::R4300;
 use byteorder::{ByteOrder, LittleEndian};
+use emu::bus::Device;
 use emu::gfx::{Color, ColorConverter, ColorFormat, Rgba8888};
 use packed_simd::*;
 use std::arch::x86_64::*;
@@ -69,6 +71,159 @@ impl MColor for MultiColor {
     }
 }
 
+/// Raw pointer to RDRAM, used by the RDP to access color and texture images.
+///
+/// RDRAM is owned by the main CPU bus (or by the replay buffer in
+/// [`dpcap`](../dpcap/index.html)), so the RDP cannot hold a borrow of it
+/// across commands. The pointer is unchecked: creating it and accessing
+/// memory through it are unsafe, and callers must guarantee that the memory
+/// outlives the pointer and is not accessed elsewhere while a slice returned
+/// by it is alive.
+#[derive(Copy, Clone, Debug)]
+pub struct RdramPtr {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Default for RdramPtr {
+    fn default() -> RdramPtr {
+        RdramPtr {
+            ptr: std::ptr::null_mut(),
+            len: 0,
+        }
+    }
+}
+
+impl RdramPtr {
+    /// Return a pointer to RDRAM as currently mapped on the main CPU bus.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`from_slice`](#method.from_slice): the pointer must not be
+    /// used after the bus memory is reallocated or dropped.
+    pub unsafe fn current() -> RdramPtr {
+        match R4300::get_mut().bus.fetch_write::<u8>(0).mem() {
+            Some(mem) => RdramPtr::from_slice(mem),
+            None => RdramPtr::default(),
+        }
+    }
+
+    /// Create a pointer to a memory buffer that emulates RDRAM.
+    ///
+    /// # Safety
+    ///
+    /// The returned pointer is not tied to the lifetime of `mem`. The caller
+    /// must ensure that `mem` is neither moved, reallocated nor dropped while
+    /// the pointer (or any copy of it) is still used.
+    pub unsafe fn from_slice(mem: &mut [u8]) -> RdramPtr {
+        RdramPtr {
+            ptr: mem.as_mut_ptr(),
+            len: mem.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return RDRAM contents starting at the specified address, or None if
+    /// the address is out of bounds.
+    ///
+    /// # Safety
+    ///
+    /// The memory must still be valid (see [`from_slice`](#method.from_slice)),
+    /// and it must not be written through any other path while the returned
+    /// slice is alive.
+    pub unsafe fn slice<'a>(&self, addr: u32) -> Option<&'a [u8]> {
+        self.slice_mut(addr).map(|s| &*s)
+    }
+
+    /// Mutable version of [`slice`](#method.slice).
+    ///
+    /// # Safety
+    ///
+    /// The memory must still be valid (see [`from_slice`](#method.from_slice)),
+    /// and it must not be accessed through any other path, including other
+    /// slices returned by this pointer, while the returned slice is alive.
+    pub unsafe fn slice_mut<'a>(&self, addr: u32) -> Option<&'a mut [u8]> {
+        let addr = addr as usize;
+        if addr >= self.len {
+            return None;
+        }
+        Some(std::slice::from_raw_parts_mut(
+            self.ptr.add(addr),
+            self.len - addr,
+        ))
+    }
+}
+
+// Fixed number of clocks required to decode and dispatch a command word.
+pub(crate) const CMD_OVERHEAD: i64 = 1;
+
+// Return the number of 64-bit words that make up the command with the
+// specified opcode.
+pub(crate) fn command_words(op: u64) -> usize {
+    match op {
+        // Triangles: edge coefficients, plus optional shade, texture
+        // and z-buffer coefficients.
+        0x08..=0x0F => {
+            4 + if op & 4 != 0 { 8 } else { 0 }
+                + if op & 2 != 0 { 8 } else { 0 }
+                + if op & 1 != 0 { 2 } else { 0 }
+        }
+        // Texture Rectangle and Texture Rectangle Flip
+        0x24 | 0x25 => 2,
+        _ => 1,
+    }
+}
+
+// Names of all the RDP commands, indexed by opcode.
+pub(crate) const COMMAND_NAMES: &[(u64, &str)] = &[
+    (0x00, "No Op"),
+    (0x08, "Fill Triangle"),
+    (0x09, "Fill ZBuffer Triangle"),
+    (0x0A, "Texture Triangle"),
+    (0x0B, "Texture ZBuffer Triangle"),
+    (0x0C, "Shade Triangle"),
+    (0x0D, "Shade ZBuffer Triangle"),
+    (0x0E, "Shade Texture Triangle"),
+    (0x0F, "Shade Texture ZBuffer Triangle"),
+    (0x24, "Texture Rectangle"),
+    (0x25, "Texture Rectangle Flip"),
+    (0x26, "Sync Load"),
+    (0x27, "Sync Pipe"),
+    (0x28, "Sync Tile"),
+    (0x29, "Sync Full"),
+    (0x2A, "Set Key GB"),
+    (0x2B, "Set Key R"),
+    (0x2C, "Set Convert"),
+    (0x2D, "Set Scissor"),
+    (0x2E, "Set Prim Depth"),
+    (0x2F, "Set Other Modes"),
+    (0x30, "Load TLUT"),
+    (0x32, "Set Tile Size"),
+    (0x33, "Load Block"),
+    (0x34, "Load Tile"),
+    (0x35, "Set Tile"),
+    (0x36, "Fill Rectangle"),
+    (0x37, "Set Fill Color"),
+    (0x38, "Set Fog Color"),
+    (0x39, "Set Blend Color"),
+    (0x3A, "Set Prim Color"),
+    (0x3B, "Set Env Color"),
+    (0x3C, "Set Combine Mode"),
+    (0x3D, "Set Texture Image"),
+    (0x3E, "Set Z Image"),
+    (0x3F, "Set Color Image"),
+];
+
+pub(crate) fn command_name(op: u64) -> &'static str {
+    COMMAND_NAMES
+        .iter()
+        .find(|(cop, _)| *cop == op)
+        .map_or("Invalid", |(_, name)| name)
+}
+
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum CycleMode {
     One,
@@ -77,6 +232,28 @@ pub(crate) enum CycleMode {
     Fill,
 }
 
+impl CycleMode {
+    // Estimate the number of RDP clocks required to draw npixels pixels of
+    // the specified bpp. Copy and fill modes write 64 bits per clock, while
+    // the other modes go through the pixel pipeline one pixel at a time.
+    pub(crate) fn pixel_cycles(&self, npixels: i64, bpp: usize) -> i64 {
+        match self {
+            CycleMode::One => npixels,
+            CycleMode::Two => npixels * 2,
+            CycleMode::Copy | CycleMode::Fill => (npixels * bpp as i64 + 63) / 64,
+        }
+    }
+
+    // In fill and copy modes, the lower-right corner of rectangles is
+    // inclusive.
+    pub(crate) fn inclusive_rects(&self) -> bool {
+        match self {
+            CycleMode::Fill | CycleMode::Copy => true,
+            CycleMode::One | CycleMode::Two => false,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum DpColorFormat {
     Rgba,
@@ -110,6 +287,8 @@ mod cc;
 mod pipeline;
 mod raster;
 mod rdp;
+mod tmemview;
 
 pub use self::pipeline::PixelPipeline;
-pub use self::rdp::Rdp;
+pub use self::rdp::{OpTiming, Rdp};
+pub use self::tmemview::TmemView;
@@ -3,7 +3,7 @@ extern crate emu;
 extern crate num;
 use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
 use self::emu::fp::formats::*;
-use self::emu::fp::FixedPoint;
+use self::emu::fp::{FixedPoint, Q};
 use self::emu::gfx::*;
 use self::num::ToPrimitive;
 use super::pipeline::PixelPipeline;
@@ -33,6 +33,31 @@ pub fn fill_rect<'a, 'b, CF1, CF2, FP1, O1>(
     }
 }
 
+// Fill a rectangle with a horizontally repeating pattern, where the color
+// of each pixel is computed from its X coordinate.
+#[inline(always)]
+pub fn fill_rect_pattern<'a, CF, FP, O, F>(
+    dst: &mut GfxBufferMut<'a, CF, O>,
+    dr: Rect<FP>,
+    pattern: F,
+) where
+    CF: ColorFormat,
+    FP: FixedPoint,
+    O: ByteOrder,
+    F: Fn(usize) -> Color<CF>,
+{
+    let dr = dr.truncate();
+
+    for dy in dr.c0.y.floor()..=dr.c1.y.floor() {
+        let mut dst = dst.line(dy.to_usize().unwrap());
+
+        for dx in dr.c0.x.floor()..=dr.c1.x.floor() {
+            let didx = dx.to_usize().unwrap();
+            dst.set(didx, pattern(didx));
+        }
+    }
+}
+
 pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
     dst: &mut GfxBufferMut<'a, CF1, O1>,
     dr: Rect<FP1>,
@@ -48,10 +73,10 @@ pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
     let color = MultiColor::from_color(color);
     let black = MultiColor::from_color(Color::<Rgba8888>::new_clamped(0, 0, 0, 0xff));
 
-    for dy in dr.c0.y.floor()..dr.c1.y.floor() {
+    for dy in dr.c0.y.floor()..=dr.c1.y.floor() {
         let mut dst = dst.line(dy.to_usize().unwrap());
 
-        for dx in dr.c0.x.floor()..dr.c1.x.floor() {
+        for dx in dr.c0.x.floor()..=dr.c1.x.floor() {
             let didx = dx.to_usize().unwrap();
             let cres = pp.calc_pixels(color, black);
             if cres.overflown() {
@@ -65,6 +90,13 @@ pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
     }
 }
 
+// Convert a texture coordinate into a pixel index, clamping it within
+// the source buffer.
+#[inline(always)]
+fn clamp_coord<FP: FixedPoint>(v: Q<FP>, size: usize) -> usize {
+    v.floor().to_usize().unwrap_or(0).min(size - 1)
+}
+
 #[inline(always)]
 fn int_draw_rect<'a, 'b, CF1, CF2, FP1, FP2, O1, O2>(
     dst: &mut GfxBufferMut<'a, CF1, O1>,
@@ -84,33 +116,42 @@ fn int_draw_rect<'a, 'b, CF1, CF2, FP1, FP2, O1, O2>(
     let sx = st.x;
     let mut sy = st.y;
 
-    let w = (dr.c1.x.floor() - dr.c0.x.floor()).to_usize().unwrap();
-    if (w + 1) % 4 != 0 {
-        println!("{:?}", w + 1);
-        panic!("cannot unroll loop");
+    let (sw, sh) = (src.width(), src.height());
+    if sw == 0 || sh == 0 {
+        return;
     }
 
+    let x0 = dr.c0.x.floor().to_usize().unwrap();
+    let x1 = dr.c1.x.floor().to_usize().unwrap();
+
     for dy in dr.c0.y.floor()..=dr.c1.y.floor() {
         let mut dst = dst.line(dy.to_usize().unwrap());
-        let src = src.line(sy.floor().to_usize().unwrap());
+        let src = src.line(clamp_coord(sy, sh));
 
-        // FIXME: Do 4 pixels at a time (manual unroll). Not sure if it's OK.
+        // Do 4 pixels at a time (manual unroll), then draw the remaining
+        // pixels one by one.
         let mut sx = sx;
-        for dx in (dr.c0.x.floor()..=dr.c1.x.floor()).step_by(4) {
-            let c1 = src.get(sx.floor().to_usize().unwrap());
+        let mut dx = x0;
+        while dx + 3 <= x1 {
+            let c1 = src.get(clamp_coord(sx, sw));
             sx = sx + dsdt.x;
 
-            let c2 = src.get(sx.floor().to_usize().unwrap());
+            let c2 = src.get(clamp_coord(sx, sw));
             sx = sx + dsdt.x;
 
-            let c3 = src.get(sx.floor().to_usize().unwrap());
+            let c3 = src.get(clamp_coord(sx, sw));
             sx = sx + dsdt.x;
 
-            let c4 = src.get(sx.floor().to_usize().unwrap());
+            let c4 = src.get(clamp_coord(sx, sw));
             sx = sx + dsdt.x;
 
-            let didx = dx.to_usize().unwrap();
-            dst.set4(didx, c1.cconv(), c2.cconv(), c3.cconv(), c4.cconv());
+            dst.set4(dx, c1.cconv(), c2.cconv(), c3.cconv(), c4.cconv());
+            dx += 4;
+        }
+        while dx <= x1 {
+            dst.set(dx, src.get(clamp_coord(sx, sw)).cconv());
+            sx = sx + dsdt.x;
+            dx += 1;
         }
 
         sy = sy + dsdt.y;
@@ -192,10 +233,11 @@ impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
         src: (&[u8], usize, usize, usize),
         st: Point<FPST>,
         dsdt: Point<FPST>,
-    ) {
-        let mut dst = GfxBufferMut::<CF1, LittleEndian>::new(dst.0, dst.1, dst.2, dst.3).unwrap();
-        let src = GfxBuffer::<CF2, O>::new(src.0, src.1, src.2, src.3).unwrap();
+    ) -> Result<(), String> {
+        let mut dst = GfxBufferMut::<CF1, LittleEndian>::new(dst.0, dst.1, dst.2, dst.3)?;
+        let src = GfxBuffer::<CF2, O>::new(src.0, src.1, src.2, src.3)?;
         draw_rect_slopes(&mut dst, dr, &src, st, dsdt);
+        Ok(())
     }
 
     #[inline]
@@ -206,7 +248,7 @@ impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
         src: (&[u8], usize, usize, usize),
         st: Point<FPST>,
         dsdt: Point<FPST>,
-    ) {
+    ) -> Result<(), String> {
         match self.src_cf {
             DpColorFormat::Intensity if self.src_bpp == 4 => {
                 self.draw_rect_slopes2::<CF1, I4, BigEndian>(dst, dr, src, st, dsdt)
@@ -214,10 +256,10 @@ impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
             DpColorFormat::Intensity if self.src_bpp == 8 => {
                 self.draw_rect_slopes2::<CF1, I8, BigEndian>(dst, dr, src, st, dsdt)
             }
-            _ => panic!(
+            _ => Err(format!(
                 "unimplemented src color format: {:?}/{}",
                 self.src_cf, self.src_bpp
-            ),
+            )),
         }
     }
 
@@ -228,7 +270,7 @@ impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
         src: (&[u8], usize, usize, usize),
         st: Point<FPST>,
         dsdt: Point<FPST>,
-    ) {
+    ) -> Result<(), String> {
         match self.dst_cf {
             DpColorFormat::Rgba if self.dst_bpp == 32 => {
                 self.draw_rect_slopes1::<Rgb888>(dst, dr, src, st, dsdt)
@@ -236,10 +278,10 @@ impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
             DpColorFormat::Rgba if self.dst_bpp == 16 => {
                 self.draw_rect_slopes1::<Rgb555>(dst, dr, src, st, dsdt)
             }
-            _ => panic!(
+            _ => Err(format!(
                 "unimplemented dst color format: {:?}/{}",
                 self.dst_cf, self.dst_bpp
-            ),
+            )),
         }
     }
 }
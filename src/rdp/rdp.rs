@@ -3,12 +3,11 @@ extern crate byteorder;
 extern crate emu;
 extern crate slog;
 use self::bit_field::BitField;
-use self::byteorder::{BigEndian, LittleEndian};
-use self::emu::bus::Device;
-use super::super::r4300::R4300;
+use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
 use super::pipeline::PixelPipeline;
-use super::raster::{draw_rect, fill_rect, fill_rect_pp, DpRenderState};
-use super::{CycleMode, DpColorFormat};
+use super::raster::{draw_rect, fill_rect, fill_rect_pattern, fill_rect_pp, DpRenderState};
+use super::{command_name, command_words, CycleMode, DpColorFormat, RdramPtr, CMD_OVERHEAD};
+use emu::dbg::GpuCommand;
 use emu::fp::formats::*;
 use emu::fp::Q;
 use emu::gfx::*;
@@ -44,6 +43,58 @@ impl ImageFormat {
     }
 }
 
+/// Timing information about a command executed by the RDP, used by
+/// [`Dp`](../dp/struct.Dp.html) to advance its clock and busy counters.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct OpTiming {
+    /// Total number of RDP clocks spent executing the command.
+    pub cycles: i64,
+    /// Number of clocks in which the pixel pipeline was busy.
+    pub pipe: i64,
+    /// Number of clocks in which TMEM was busy (texture loads).
+    pub tmem: i64,
+    /// True if the command was a Sync Full.
+    pub sync_full: bool,
+}
+
+impl OpTiming {
+    fn cmd(cycles: i64) -> OpTiming {
+        OpTiming {
+            cycles,
+            ..Default::default()
+        }
+    }
+    fn pipe(cycles: i64) -> OpTiming {
+        OpTiming {
+            cycles,
+            pipe: cycles,
+            ..Default::default()
+        }
+    }
+    fn tmem(cycles: i64) -> OpTiming {
+        OpTiming {
+            cycles,
+            tmem: cycles,
+            ..Default::default()
+        }
+    }
+}
+
+// Number of clocks that a sync command stalls the command stream while the
+// pipeline is drained (pipe, load, tile) or the memory writes are
+// flushed (full).
+const SYNC_PIPE_CYCLES: i64 = 25;
+const SYNC_LOAD_CYCLES: i64 = 25;
+const SYNC_TILE_CYCLES: i64 = 33;
+const SYNC_FULL_CYCLES: i64 = 32;
+
+// Maximum height of a color image. The width is limited to 1024 pixels by
+// the 10-bit field in Set Color Image, and the scissor by 12-bit fields.
+const MAX_IMAGE_HEIGHT: usize = 1024;
+
+// Size of TMEM in bytes.
+const TMEM_SIZE: usize = 4096;
+
 pub struct Rdp {
     logger: slog::Logger,
     tmem: Box<[u8]>,
@@ -55,15 +106,22 @@ pub struct Rdp {
     cycle_mode: CycleMode,
 
     pipeline: PixelPipeline,
+    rdram: RdramPtr,
 
-    cmdbuf: [u64; 16],
+    cmdbuf: [u64; 22],
     cmdlen: usize,
+
+    // Commands executed in the current frame and in the last completed one,
+    // for the debugger. Recording is only enabled on request.
+    cmdlog: Option<Vec<GpuCommand>>,
+    last_frame: Vec<GpuCommand>,
+    last_frame_size: (usize, usize),
 }
 
 impl Rdp {
     pub fn new(logger: slog::Logger) -> Rdp {
         let mut tmem = Vec::new();
-        tmem.resize(4096, 0);
+        tmem.resize(TMEM_SIZE, 0);
         Rdp {
             logger: logger,
             tmem: tmem.into_boxed_slice(),
@@ -74,8 +132,12 @@ impl Rdp {
             fill_color: 0,
             cycle_mode: CycleMode::One,
             pipeline: PixelPipeline::new(),
-            cmdbuf: [0u64; 16],
+            rdram: RdramPtr::default(),
+            cmdbuf: [0u64; 22],
             cmdlen: 0,
+            cmdlog: None,
+            last_frame: Vec::new(),
+            last_frame_size: (0, 0),
         }
     }
 
@@ -88,22 +150,401 @@ impl Rdp {
             .unwrap()
     }
 
-    fn framebuffer<'s, 'r: 's>(&'s self) -> (&'r mut [u8], usize, usize, usize) {
-        let fb_mem = R4300::get_mut()
-            .bus
-            .fetch_write::<u8>(self.fb.dram_addr)
-            .mem()
-            .unwrap();
-        (fb_mem, 320, 240, self.fb.pitch())
+    // Return the color image memory, with its width, height and pitch.
+    // The width comes from Set Color Image, while the height is derived from
+    // the scissor, clipped so that the image never exceeds the end of RDRAM.
+    fn framebuffer<'s, 'r: 's>(&'s self) -> Option<(&'r mut [u8], usize, usize, usize)> {
+        // Safe because set_rdram() requires RDRAM to stay valid and untouched
+        // while commands are executed.
+        let fb_mem = match unsafe { self.rdram.slice_mut(self.fb.dram_addr) } {
+            Some(mem) => mem,
+            None => {
+                error!(self.logger, "color image pointing outside RDRAM"; "ptr" => self.fb.dram_addr.hex());
+                return None;
+            }
+        };
+
+        let pitch = self.fb.pitch();
+        if pitch == 0 {
+            return None;
+        }
+        let height = (self.clip.c1.y.ceil().max(0) as usize)
+            .min(MAX_IMAGE_HEIGHT)
+            .min(fb_mem.len() / pitch);
+        Some((fb_mem, self.fb.width, height, pitch))
+    }
+
+    // Clip a rectangle against the scissor and the color image size.
+    // If inclusive is false, the lower-right corner of the rectangle is
+    // excluded (as in 1-cycle/2-cycle mode). Returns the clipped rectangle
+    // with integer coordinates (with an inclusive lower-right corner), or None
+    // if the rectangle is fully clipped.
+    fn clip_rect(
+        &self,
+        rect: Rect<U30F2>,
+        inclusive: bool,
+        width: usize,
+        height: usize,
+    ) -> Option<Rect<U30F2>> {
+        let x0 = (rect.c0.x.floor() as i64).max(self.clip.c0.x.ceil() as i64);
+        let y0 = (rect.c0.y.floor() as i64).max(self.clip.c0.y.ceil() as i64);
+        let (x1, y1) = if inclusive {
+            (rect.c1.x.floor() as i64, rect.c1.y.floor() as i64)
+        } else {
+            (rect.c1.x.ceil() as i64 - 1, rect.c1.y.ceil() as i64 - 1)
+        };
+        let x1 = x1
+            .min(self.clip.c1.x.ceil() as i64 - 1)
+            .min(width as i64 - 1);
+        let y1 = y1
+            .min(self.clip.c1.y.ceil() as i64 - 1)
+            .min(height as i64 - 1);
+        if x0 > x1 || y0 > y1 {
+            return None;
+        }
+        Some(Rect::new(
+            Point::from_int(x0 as u32, y0 as u32),
+            Point::from_int(x1 as u32, y1 as u32),
+        ))
+    }
+
+    /// Set the RDRAM memory used for color and texture images.
+    ///
+    /// # Safety
+    ///
+    /// The memory pointed by `rdram` must stay valid for as long as commands
+    /// are executed with [`op`](#method.op), and must not be accessed by
+    /// anything else during each call to it.
+    pub unsafe fn set_rdram(&mut self, rdram: RdramPtr) {
+        self.rdram = rdram;
+    }
+
+    /// Return the TMEM contents.
+    pub fn tmem(&self) -> &[u8] {
+        &self.tmem[..]
+    }
+
+    pub fn tmem_mut(&mut self) -> &mut [u8] {
+        &mut self.tmem[..]
+    }
+
+    /// Discard a partially-received multi-word command (if any).
+    pub fn flush(&mut self) {
+        self.cmdlen = 0;
+    }
+
+    /// Enable or disable recording of the executed commands (see
+    /// [`last_frame`](#method.last_frame)).
+    pub fn set_cmdlog(&mut self, enabled: bool) {
+        if enabled != self.cmdlog.is_some() {
+            self.cmdlog = if enabled { Some(Vec::new()) } else { None };
+        }
+    }
+
+    /// Mark the end of a frame: the commands recorded so far become the
+    /// ones returned by [`last_frame`](#method.last_frame).
+    pub fn end_frame(&mut self) {
+        if let Some(cmdlog) = self.cmdlog.as_mut() {
+            self.last_frame = std::mem::replace(cmdlog, Vec::new());
+            self.last_frame_size = (self.fb.width, self.clip.c1.y.ceil().max(0) as usize);
+        }
+    }
+
+    /// Return the commands recorded during the last frame, and the size of
+    /// the color image they were drawn into.
+    pub fn last_frame(&self) -> (&[GpuCommand], (usize, usize)) {
+        (&self.last_frame, self.last_frame_size)
+    }
+
+    /// Return a description of the specified tile descriptor.
+    pub(crate) fn tile_info(&self, idx: usize) -> Vec<String> {
+        let tile = &self.tiles[idx];
+        vec![
+            format!("format: {:?}, bpp: {}, palette: {}", tile.color_format, tile.bpp, tile.palette),
+            format!("tmem: {:03x}, pitch: {}", tile.tmem_addr, tile.pitch),
+            format!(
+                "size: {}x{} (s: {}-{}, t: {}-{})",
+                tile.rect.width().floor() + 1,
+                tile.rect.height().floor() + 1,
+                tile.rect.c0.x.floor(),
+                tile.rect.c1.x.floor(),
+                tile.rect.c0.y.floor(),
+                tile.rect.c1.y.floor(),
+            ),
+            format!(
+                "clamp: {:?}, mirror: {:?}, mask: {:?}, shift: {:?}",
+                tile.clamp, tile.mirror, tile.mask, tile.shift
+            ),
+        ]
+    }
+
+    // Read a TLUT entry. The TLUT is stored in the upper half of TMEM, with
+    // each 16-bit entry replicated four times. Entries are decoded as RGBA16.
+    fn tlut_color(&self, idx: usize) -> Color<Rgba8888> {
+        let addr = TMEM_SIZE / 2 + (idx & 0xFF) * 8;
+        let c: Color<Rgba8888> =
+            Color::<Xbgr1555>::from_bits(BigEndian::read_u16(&self.tmem[addr..])).cconv();
+        let (r, g, b, _) = c.components();
+        let a = self.tmem[addr + 1] as i32 & 1;
+        Color::new_clamped(r, g, b, a * 0xFF)
+    }
+
+    /// Decode the specified tile into an RGBA image, using the tile
+    /// format, size and pitch (and the TLUT for color-indexed formats).
+    pub(crate) fn decode_tile(&self, idx: usize) -> Option<OwnedGfxBufferLE<Rgba8888>> {
+        let tile = &self.tiles[idx];
+        let width = tile.rect.width().floor() as usize + 1;
+        let height = tile.rect.height().floor() as usize + 1;
+        if tile.pitch == 0 || width > 1024 || height > 1024 {
+            return None;
+        }
+
+        let mut out = OwnedGfxBufferLE::<Rgba8888>::new(width, height);
+        {
+            let mut dst = out.buf_mut();
+            for y in 0..height {
+                let mut line = dst.line(y);
+                for x in 0..width {
+                    let bitpos = (tile.tmem_addr as usize + y * tile.pitch) * 8 + x * tile.bpp;
+                    let addr = (bitpos / 8) % TMEM_SIZE;
+                    let b0 = self.tmem[addr] as i32;
+                    let b1 = self.tmem[(addr + 1) % TMEM_SIZE] as i32;
+                    // 4-bit texels: even texels are in the high nibble
+                    let n = if bitpos & 4 == 0 { b0 >> 4 } else { b0 & 0xF };
+
+                    let c = match (tile.color_format, tile.bpp) {
+                        (DpColorFormat::Rgba, 16) => {
+                            let c: Color<Rgba8888> =
+                                Color::<Xbgr1555>::from_bits((b0 << 8 | b1) as u16).cconv();
+                            let (r, g, b, _) = c.components();
+                            Color::new_clamped(r, g, b, (b1 & 1) * 0xFF)
+                        }
+                        (DpColorFormat::Rgba, 32) => Color::new_clamped(
+                            b0,
+                            b1,
+                            self.tmem[(addr + 2) % TMEM_SIZE] as i32,
+                            self.tmem[(addr + 3) % TMEM_SIZE] as i32,
+                        ),
+                        (DpColorFormat::ColorIndex, 4) => self.tlut_color(tile.palette << 4 | n as usize),
+                        (DpColorFormat::ColorIndex, 8) => self.tlut_color(b0 as usize),
+                        (DpColorFormat::IntensityAlpha, 4) => {
+                            let i = (n >> 1) * 0xFF / 7;
+                            Color::new_clamped(i, i, i, (n & 1) * 0xFF)
+                        }
+                        (DpColorFormat::IntensityAlpha, 8) => {
+                            let i = (b0 >> 4) * 0x11;
+                            Color::new_clamped(i, i, i, (b0 & 0xF) * 0x11)
+                        }
+                        (DpColorFormat::IntensityAlpha, 16) => Color::new_clamped(b0, b0, b0, b1),
+                        (DpColorFormat::Intensity, 4) => {
+                            let i = n * 0x11;
+                            Color::new_clamped(i, i, i, i)
+                        }
+                        (DpColorFormat::Intensity, 8) => Color::new_clamped(b0, b0, b0, b0),
+                        _ => return None,
+                    };
+                    line.set(x, c);
+                }
+            }
+        }
+        Some(out)
+    }
+
+    /// Decode the TLUT into a 16x16 image.
+    pub(crate) fn decode_tlut(&self) -> OwnedGfxBufferLE<Rgba8888> {
+        let mut out = OwnedGfxBufferLE::<Rgba8888>::new(16, 16);
+        {
+            let mut dst = out.buf_mut();
+            for y in 0..16 {
+                let mut line = dst.line(y);
+                for x in 0..16 {
+                    line.set(x, self.tlut_color(y * 16 + x));
+                }
+            }
+        }
+        out
+    }
+
+    /// Process one 64-bit word of the command stream. Returns the estimated
+    /// timing of the command; words that do not complete a command only
+    /// account for the decoding overhead.
+    pub fn op(&mut self, cmd: u64) -> OpTiming {
+        let timing = self.exec(cmd);
+        if self.cmdlen == 0 && self.cmdlog.is_some() {
+            let desc = self.describe();
+            self.cmdlog.as_mut().unwrap().push(desc);
+        }
+        timing
+    }
+
+    // Decode the command that was just executed, for the debugger.
+    fn describe(&self) -> GpuCommand {
+        let op = self.cmdbuf[0].get_bits(56..62);
+        let words = &self.cmdbuf[..command_words(op)];
+        let cmd = words[0];
+        let rect = |w: u64| {
+            (
+                w.get_bits(12..24) as f32 / 4.0,
+                w.get_bits(0..12) as f32 / 4.0,
+                w.get_bits(44..56) as f32 / 4.0,
+                w.get_bits(32..44) as f32 / 4.0,
+            )
+        };
+        let area = |r: (f32, f32, f32, f32)| {
+            Some((r.0 as usize, r.1 as usize, r.2 as usize, r.3 as usize))
+        };
+
+        let mut params = Vec::new();
+        let mut touched = None;
+        match op {
+            0x08..=0x0F => {
+                // Edge coefficients: y in s11.2, x in s15.16
+                let y = |bits: u64| (bits as i16) << 2 >> 2;
+                let (yl, ym, yh) = (
+                    y(cmd.get_bits(32..46)),
+                    y(cmd.get_bits(16..30)),
+                    y(cmd.get_bits(0..14)),
+                );
+                let (xl, xh, xm) = (
+                    words[1].get_bits(32..64) as i32,
+                    words[2].get_bits(32..64) as i32,
+                    words[3].get_bits(32..64) as i32,
+                );
+                let (dxldy, dxhdy, dxmdy) = (
+                    words[1].get_bits(0..32) as i32,
+                    words[2].get_bits(0..32) as i32,
+                    words[3].get_bits(0..32) as i32,
+                );
+                params.push(format!(
+                    "tile: {}, level: {}, left major: {}",
+                    cmd.get_bits(48..51),
+                    cmd.get_bits(51..54),
+                    cmd.get_bit(55)
+                ));
+                params.push(format!(
+                    "yh: {}, ym: {}, yl: {}",
+                    yh as f32 / 4.0,
+                    ym as f32 / 4.0,
+                    yl as f32 / 4.0
+                ));
+                params.push(format!(
+                    "xh: {}, xm: {}, xl: {}",
+                    xh as f32 / 65536.0,
+                    xm as f32 / 65536.0,
+                    xl as f32 / 65536.0
+                ));
+                // Evaluate each edge at both its ends: H spans yh-yl, M spans
+                // yh-ym, and L spans ym-yl. Slopes are per scanline.
+                let edge = |x: i32, dxdy: i32, dy: i16| {
+                    (x as f64 + dxdy as f64 * (dy as f64 / 4.0)) / 65536.0
+                };
+                let xs = [
+                    edge(xh, dxhdy, 0),
+                    edge(xh, dxhdy, yl - yh),
+                    edge(xm, dxmdy, 0),
+                    edge(xm, dxmdy, ym - yh),
+                    edge(xl, dxldy, 0),
+                    edge(xl, dxldy, yl - ym),
+                ];
+                let xmin = xs.iter().cloned().fold(std::f64::MAX, f64::min).max(0.0);
+                let xmax = xs.iter().cloned().fold(std::f64::MIN, f64::max).max(0.0);
+                touched = Some((
+                    xmin as usize,
+                    (yh >> 2).max(0) as usize,
+                    xmax as usize,
+                    (yl >> 2).max(0) as usize,
+                ));
+                if op & 4 != 0 {
+                    params.push("shade".into());
+                }
+                if op & 2 != 0 {
+                    params.push("texture".into());
+                }
+                if op & 1 != 0 {
+                    params.push("z-buffer".into());
+                }
+                params.push(self.pipeline.fmt_combiner());
+                params.push(self.pipeline.fmt_blender());
+            }
+            0x24 | 0x25 => {
+                let r = rect(cmd);
+                params.push(format!("tile: {}", cmd.get_bits(24..27)));
+                params.push(format!("rect: ({}, {}) - ({}, {})", r.0, r.1, r.2, r.3));
+                params.push(format!(
+                    "s: {}, t: {}, dsdx: {}, dtdy: {}",
+                    words[1].get_bits(48..64) as i16 as f32 / 32.0,
+                    words[1].get_bits(32..48) as i16 as f32 / 32.0,
+                    words[1].get_bits(16..32) as i16 as f32 / 1024.0,
+                    words[1].get_bits(0..16) as i16 as f32 / 1024.0
+                ));
+                params.push(format!("{:?}", self.tiles[cmd.get_bits(24..27) as usize]));
+                params.push(self.pipeline.fmt_combiner());
+                params.push(self.pipeline.fmt_blender());
+                touched = area(r);
+            }
+            0x36 => {
+                let r = rect(cmd);
+                params.push(format!("rect: ({}, {}) - ({}, {})", r.0, r.1, r.2, r.3));
+                params.push(format!("cycle mode: {:?}", self.cycle_mode));
+                touched = area(r);
+            }
+            0x2D => {
+                let r = rect(cmd);
+                params.push(format!("rect: ({}, {}) - ({}, {})", r.2, r.3, r.0, r.1));
+            }
+            0x2F => {
+                params.push(format!("cycle mode: {:?}", self.cycle_mode));
+                params.push(self.pipeline.fmt_blender());
+            }
+            0x3C => params.push(self.pipeline.fmt_combiner()),
+            0x30 | 0x32 | 0x33 | 0x34 => {
+                params.push(format!("tile: {}", cmd.get_bits(24..27)));
+                params.push(format!(
+                    "s0: {}, t0: {}, s1: {}, t1: {}",
+                    cmd.get_bits(44..56) as f32 / 4.0,
+                    cmd.get_bits(32..44) as f32 / 4.0,
+                    cmd.get_bits(12..24) as f32 / 4.0,
+                    cmd.get_bits(0..12) as f32 / 4.0
+                ));
+            }
+            0x35 => {
+                let idx = cmd.get_bits(24..27) as usize;
+                params.push(format!("tile: {}", idx));
+                params.push(format!("{:?}", self.tiles[idx]));
+            }
+            0x3D | 0x3E | 0x3F => params.push(format!(
+                "format: {}, bpp: {}, width: {}, addr: {:08x}",
+                cmd.get_bits(53..56),
+                4 << cmd.get_bits(51..53),
+                cmd.get_bits(32..42) + 1,
+                cmd.get_bits(0..26)
+            )),
+            0x37 | 0x38 | 0x39 | 0x3A | 0x3B => {
+                params.push(format!("color: {:08x}", cmd.get_bits(0..32)))
+            }
+            _ => {}
+        }
+
+        GpuCommand {
+            op: op as u32,
+            name: command_name(op),
+            words: words.to_vec(),
+            params,
+            area: touched,
+        }
     }
 
-    pub fn op(&mut self, cmd: u64) {
+    fn exec(&mut self, cmd: u64) -> OpTiming {
         info!(self.logger, "DP command"; "cmd" => cmd.hex());
         self.cmdbuf[self.cmdlen] = cmd;
         self.cmdlen += 1;
 
         let op = self.cmdbuf[0].get_bits(56..62);
-        match op {
+        if self.cmdlen < command_words(op) {
+            return OpTiming::cmd(CMD_OVERHEAD);
+        }
+
+        let timing = match op {
             0x2D => {
                 // Set Scissor
                 self.clip = Rect::from_bits(
@@ -114,6 +555,7 @@ impl Rdp {
                 );
                 info!(self.logger, "DP: Set Scissor"; "clip" => ?self.clip);
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
             }
             0x3D | 0x3F => {
                 // Set Color/Texture Image
@@ -132,11 +574,35 @@ impl Rdp {
                     info!(self.logger, "DP: Set Texture Image"; "format" => ?self.tex);
                 }
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
+            }
+            0x26 => {
+                // Sync Load: wait for pending TMEM loads to complete
+                info!(self.logger, "DP: Sync Load");
+                self.cmdlen = 0;
+                OpTiming::cmd(SYNC_LOAD_CYCLES)
+            }
+            0x27 => {
+                // Sync Pipe: wait for the pixel pipeline to drain
+                info!(self.logger, "DP: Sync Pipe");
+                self.cmdlen = 0;
+                OpTiming::cmd(SYNC_PIPE_CYCLES)
             }
             0x28 => {
                 // Sync Tile
                 info!(self.logger, "DP: Sync Tile");
                 self.cmdlen = 0;
+                OpTiming::cmd(SYNC_TILE_CYCLES)
+            }
+            0x29 => {
+                // Sync Full: wait for all memory writes to complete; the DP
+                // then raises its interrupt.
+                info!(self.logger, "DP: Sync Full");
+                self.cmdlen = 0;
+                OpTiming {
+                    sync_full: true,
+                    ..OpTiming::cmd(SYNC_FULL_CYCLES)
+                }
             }
             0x2F => {
                 // Set Other Modes
@@ -150,48 +616,65 @@ impl Rdp {
                 self.pipeline.set_other_modes(cmd);
                 warn!(self.logger, "DP: Set Other Modes"; "blender" => self.pipeline.fmt_blender());
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
             }
             0x24 => {
                 // Texture rectangle (2 words)
-                if self.cmdlen != 2 {
-                    return;
-                }
-
                 let tile = self.cmdbuf[0].get_bits(24..27) as usize;
                 let x1 = self.cmdbuf[0].get_bits(44..56) as u32;
                 let y1 = self.cmdbuf[0].get_bits(32..44) as u32;
                 let x0 = self.cmdbuf[0].get_bits(12..24) as u32;
                 let y0 = self.cmdbuf[0].get_bits(0..12) as u32;
-                let mut rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
+                let rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
 
                 let s = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(48..64) as i16);
                 let t = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(32..48) as i16);
-                let dsdx = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(16..32) as i16);
+                let mut dsdx = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(16..32) as i16);
                 let dtdy = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(0..16) as i16);
+                info!(self.logger, "DP: Textured Rectangle"; "idx" => tile, "tile" => ?self.tiles[tile], "screen" => ?rect, "s" => ?s, "t" => ?t, "dsdx" => ?dsdx, "dtdy" => ?dtdy);
+                self.cmdlen = 0;
+
+                // Copy mode draws 4 pixels per clock, so DsDx is specified
+                // per clock (4.0 for a 1:1 copy) rather than per pixel.
+                if let CycleMode::Copy = self.cycle_mode {
+                    dsdx = Q::<I6F10>::from_bits(dsdx.bits() >> 2);
+                }
+
+                let (fb_mem, fb_width, fb_height, fb_pitch) = match self.framebuffer() {
+                    Some(fb) => fb,
+                    None => return OpTiming::cmd(CMD_OVERHEAD),
+                };
+
+                let inclusive = self.cycle_mode.inclusive_rects();
+                let clipped = match self.clip_rect(rect, inclusive, fb_width, fb_height) {
+                    Some(r) => r,
+                    None => return OpTiming::cmd(CMD_OVERHEAD),
+                };
 
-                let ptex = Point::new(s, t);
-                let slope = Point::new(dsdx, dtdy);
-                info!(self.logger, "DP: Textured Rectangle"; "idx" => tile, "tile" => ?self.tiles[tile], "screen" => ?rect, "ptex" => ?ptex, "slope" => ?slope);
+                // Adjust the texture coordinates to account for the pixels
+                // that were clipped out on the top/left sides.
+                let skip_x = clipped.c0.x.floor() as i32 - rect.c0.x.floor() as i32;
+                let skip_y = clipped.c0.y.floor() as i32 - rect.c0.y.floor() as i32;
+                let ptex = Point::<I22F10>::from_bits(
+                    s.bits() as i32 + dsdx.bits() as i32 * skip_x,
+                    t.bits() as i32 + dtdy.bits() as i32 * skip_y,
+                );
+                let slope = Point::<I22F10>::from_bits(dsdx.bits() as i32, dtdy.bits() as i32);
 
-                let tmem_addr = self.tiles[tile].tmem_addr as usize;
+                let tmem_addr = (self.tiles[tile].tmem_addr as usize).min(TMEM_SIZE);
                 let tmem_pitch = self.tiles[tile].pitch;
                 let tex_rect = self.tiles[tile].rect;
+                let tex_height = match tmem_pitch {
+                    0 => 0,
+                    p => (tex_rect.height().floor() as usize + 1).min((TMEM_SIZE - tmem_addr) / p),
+                };
                 let src = (
                     &self.tmem[tmem_addr..],
                     tex_rect.width().floor() as usize + 1,
-                    tex_rect.height().floor() as usize + 1,
+                    tex_height,
                     tmem_pitch,
                 );
-
-                let mut fb_writer = R4300::get_mut().bus.fetch_write::<u8>(self.fb.dram_addr);
-                let fb_mem = fb_writer.mem().unwrap();
-                let dst = (fb_mem, 320, 240, self.fb.pitch());
-
-                // FIXME: draw_rect_slopes() use inclusive rectangles... maybe we need clipping?
-                let w = rect.width() - 1;
-                let h = rect.height() - 1;
-                rect.set_width(w);
-                rect.set_height(h);
+                let dst = (fb_mem, fb_width, fb_height, fb_pitch);
 
                 let state = DpRenderState {
                     dst_cf: self.fb.color_format,
@@ -200,9 +683,15 @@ impl Rdp {
                     src_bpp: self.tiles[tile].bpp,
                     phantom: PhantomData,
                 };
-                state.draw_rect_slopes(dst, rect, src, ptex.cast(), slope.cast());
+                if let Err(err) = state.draw_rect_slopes(dst, clipped, src, ptex, slope) {
+                    error!(self.logger, "DP: cannot draw textured rectangle"; "err" => err);
+                }
 
-                self.cmdlen = 0;
+                let npixels = (clipped.width().floor() as i64 + 1)
+                    * (clipped.height().floor() as i64 + 1);
+                OpTiming::pipe(
+                    CMD_OVERHEAD + self.cycle_mode.pixel_cycles(npixels, self.fb.bpp),
+                )
             }
             0x34 => {
                 // Load Tile
@@ -217,15 +706,38 @@ impl Rdp {
                 // Load_Tile also updates the internal tile rect
                 self.tiles[tile].rect = rect;
 
-                let tmem_addr = self.tiles[tile].tmem_addr as usize;
+                self.cmdlen = 0;
+
+                let tmem_addr = (self.tiles[tile].tmem_addr as usize).min(TMEM_SIZE);
                 let tmem_pitch = self.tiles[tile].pitch;
-                let tex_reader = R4300::get().bus.fetch_read::<u8>(self.tex.dram_addr);
-                let tex_mem = tex_reader.mem().unwrap();
+                let tex_pitch = self.tex.pitch();
+                let tex_mem = match unsafe { self.rdram.slice(self.tex.dram_addr) } {
+                    Some(mem) => mem,
+                    None => {
+                        error!(self.logger, "texture image pointing outside RDRAM"; "ptr" => self.tex.dram_addr.hex());
+                        return OpTiming::cmd(CMD_OVERHEAD);
+                    }
+                };
+                if tmem_pitch == 0 || tex_pitch == 0 {
+                    warn!(self.logger, "DP: Load Tile with zero pitch"; "tmem_pitch" => tmem_pitch, "tex_pitch" => tex_pitch);
+                    return OpTiming::cmd(CMD_OVERHEAD);
+                }
+
+                // Clip the loaded area against the texture image (both its
+                // width and the end of RDRAM) and against the end of TMEM.
+                let s0 = rect.c0.x.floor() as usize;
+                let t0 = rect.c0.y.floor() as usize;
                 let width = rect.width().floor() as usize + 1;
-                let height = rect.height().floor() as usize + 1;
+                let height = (rect.height().floor() as usize + 1)
+                    .min((TMEM_SIZE - tmem_addr) / tmem_pitch)
+                    .min((tex_mem.len() / tex_pitch).saturating_sub(t0));
 
-                let copy_width = width.min(self.tex.width); // FIXME: is this correct? See RDPI4Decode
+                let copy_width = width.min(self.tex.width.saturating_sub(s0)); // FIXME: is this correct? See RDPI4Decode
+                if copy_width == 0 || height == 0 {
+                    return OpTiming::cmd(CMD_OVERHEAD);
+                }
                 rect.set_width(Q::from_int(copy_width as u32 - 1));
+                rect.set_height(Q::from_int(height as u32 - 1));
 
                 info!(self.logger, "DP: Load Tile: draw_rect"; "rect" => ?rect, "copy_width" => copy_width);
                 if self.tiles[tile].bpp == 16 && self.tex.bpp == 16 {
@@ -239,9 +751,9 @@ impl Rdp {
 
                     let tex = GfxBufferLE::<Rgba5551>::new(
                         &tex_mem,
-                        copy_width,
-                        height,
-                        self.tex.pitch(),
+                        self.tex.width,
+                        t0 + height,
+                        tex_pitch,
                     )
                     .unwrap();
 
@@ -261,7 +773,7 @@ impl Rdp {
                     .unwrap();
 
                     let tex =
-                        GfxBufferLE::<I8>::new(&tex_mem, copy_width, height, self.tex.pitch())
+                        GfxBufferLE::<I8>::new(&tex_mem, self.tex.width, t0 + height, tex_pitch)
                             .unwrap();
 
                     draw_rect(
@@ -271,13 +783,13 @@ impl Rdp {
                         rect.cast::<U27F5>(),
                     );
                 } else {
-                    panic!(
-                        "unknown src/dst bpp combination in load tile: dst={} src={}",
-                        self.tiles[tile].bpp, self.tex.bpp,
-                    );
+                    error!(self.logger, "unknown src/dst bpp combination in load tile"; "dst" => self.tiles[tile].bpp, "src" => self.tex.bpp);
+                    return OpTiming::cmd(CMD_OVERHEAD);
                 }
 
-                self.cmdlen = 0;
+                // TMEM is loaded 64 bits per clock.
+                let nbytes = (copy_width * height * self.tex.bpp / 8) as i64;
+                OpTiming::tmem(CMD_OVERHEAD + (nbytes + 7) / 8)
             }
             0x35 => {
                 // Set Tile
@@ -299,70 +811,98 @@ impl Rdp {
                 tile.shift[1] = cmd.get_bits(10..14) as u32;
                 info!(self.logger, "DP: Set Tile"; "idx" => idx, "format" => ?tile);
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
             }
             0x36 => {
                 let x1 = cmd.get_bits(44..56) as u32;
                 let y1 = cmd.get_bits(32..44) as u32;
                 let x0 = cmd.get_bits(12..24) as u32;
                 let y0 = cmd.get_bits(0..12) as u32;
-                let mut rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
+                let rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
                 info!(self.logger, "DP: Fill Rectangle"; "rect" => ?rect);
+                self.cmdlen = 0;
 
-                match self.cycle_mode {
-                    CycleMode::Fill => {
-                        // Fill rectangle works with 32-bit packed words. Thus, we treat everything
-                        // as RGBA8888, but we need to convert the rect coordinates to adjust them
-                        // to a fake 32-bit resolution.
-                        let bppconv = 32 / self.fb.bpp as u32;
+                let (fb_mem, fb_width, fb_height, fb_pitch) = match self.framebuffer() {
+                    Some(fb) => fb,
+                    None => return OpTiming::cmd(CMD_OVERHEAD),
+                };
 
-                        rect.c0.x /= bppconv;
-                        rect.c0.y /= bppconv;
-                        rect.c1.x = ((rect.c1.x + 1) / bppconv) - 1;
-                        rect.c1.y = ((rect.c1.y + 1) / bppconv) - 1;
+                let inclusive = self.cycle_mode.inclusive_rects();
+                let rect = match self.clip_rect(rect, inclusive, fb_width, fb_height) {
+                    Some(r) => r,
+                    None => return OpTiming::cmd(CMD_OVERHEAD),
+                };
 
-                        if rect.truncate().cast::<U30F2>() != rect {
-                            panic!("Coordinates in DP Fill Rectangle were not 32-bit aligned");
-                        }
+                let npixels =
+                    (rect.width().floor() as i64 + 1) * (rect.height().floor() as i64 + 1);
+                let timing = OpTiming::pipe(
+                    CMD_OVERHEAD + self.cycle_mode.pixel_cycles(npixels, self.fb.bpp),
+                );
 
-                        let fb = self.framebuffer();
+                let fill = self.fill_color;
+                match (self.cycle_mode, self.fb.bpp) {
+                    (CycleMode::Fill, 32) => {
                         let mut dst = GfxBufferMut::<Rgba8888, BigEndian>::new(
-                            fb.0,
-                            fb.1 / bppconv as usize,
-                            fb.2,
-                            fb.3,
+                            fb_mem, fb_width, fb_height, fb_pitch,
                         )
                         .unwrap();
-                        let color = Color::<Rgba8888>::from_bits(self.fill_color);
-                        fill_rect(&mut dst, rect, color);
+                        fill_rect(&mut dst, rect, Color::<Rgba8888>::from_bits(fill));
                     }
-                    CycleMode::One => {
-                        let fb = self.framebuffer();
+                    (CycleMode::Fill, 16) => {
+                        // The fill color contains two 16-bit pixels, which are
+                        // written alternately on even and odd columns.
+                        let mut dst = GfxBufferMut::<Rgba5551, BigEndian>::new(
+                            fb_mem, fb_width, fb_height, fb_pitch,
+                        )
+                        .unwrap();
+                        fill_rect_pattern(&mut dst, rect, |x| {
+                            Color::<Rgba5551>::from_bits((fill >> (16 - (x & 1) * 16)) as u16)
+                        });
+                    }
+                    (CycleMode::Fill, 8) => {
+                        // The fill color contains four 8-bit pixels.
                         let mut dst =
-                            GfxBufferMut::<Rgba8888, LittleEndian>::new(fb.0, fb.1, fb.2, fb.3)
+                            GfxBufferMut::<I8, BigEndian>::new(fb_mem, fb_width, fb_height, fb_pitch)
                                 .unwrap();
-
-                        if rect.truncate().cast::<U30F2>() != rect {
-                            panic!("Coordinates in DP Fill Rectangle were not 32-bit aligned");
-                        }
-
-                        let color = Color::<Abgr8888>::from_bits(self.fill_color); // FIXME: this is probably not correct
+                        fill_rect_pattern(&mut dst, rect, |x| {
+                            Color::<I8>::from_bits((fill >> (24 - (x & 3) * 8)) as u8)
+                        });
+                    }
+                    (CycleMode::One, 32) | (CycleMode::Two, 32) => {
+                        let mut dst = GfxBufferMut::<Rgba8888, LittleEndian>::new(
+                            fb_mem, fb_width, fb_height, fb_pitch,
+                        )
+                        .unwrap();
+                        let color = Color::<Abgr8888>::from_bits(fill); // FIXME: this is probably not correct
                         fill_rect_pp(&mut dst, rect, color, &mut self.pipeline);
                     }
-                    _ => unimplemented!(),
+                    (CycleMode::One, 16) | (CycleMode::Two, 16) => {
+                        let mut dst = GfxBufferMut::<Xbgr1555, BigEndian>::new(
+                            fb_mem, fb_width, fb_height, fb_pitch,
+                        )
+                        .unwrap();
+                        let color = Color::<Abgr8888>::from_bits(fill); // FIXME: this is probably not correct
+                        fill_rect_pp(&mut dst, rect, color, &mut self.pipeline);
+                    }
+                    (mode, bpp) => {
+                        warn!(self.logger, "DP: unsupported Fill Rectangle"; "mode" => ?mode, "bpp" => bpp);
+                    }
                 }
-                self.cmdlen = 0;
+                timing
             }
             0x37 => {
                 let color = cmd.get_bits(0..32) as u32;
                 info!(self.logger, "DP: Set Fill Color"; "color" => color.hex());
                 self.fill_color = color;
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
             }
             0x3C => {
                 // Set Combine Mode
                 self.pipeline.set_combine_mode(cmd);
                 info!(self.logger, "DP: Set Combine Mode"; "cmd" => cmd.hex(), "cc" => self.pipeline.fmt_combiner());
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
             }
             0x39 => {
                 // Set Blend Color
@@ -370,12 +910,15 @@ impl Rdp {
                 self.pipeline.set_blend_color(c.cconv());
                 info!(self.logger, "DP: Set Blend Color"; "c" => ?c);
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
             }
 
             _ => {
-                warn!(self.logger, "unimplemented command"; "cmd" => (((cmd>>56)&0x3F) as u8).hex());
+                warn!(self.logger, "unimplemented command"; "cmd" => (op as u8).hex());
                 self.cmdlen = 0;
+                OpTiming::cmd(CMD_OVERHEAD)
             }
         };
+        timing
     }
 }
@@ -0,0 +1,53 @@
+extern crate emu;
+use super::Rdp;
+use emu::dbg::{MemoryBank, MemoryView, TextureView};
+use emu::gfx::{OwnedGfxBufferLE, Rgba8888};
+
+/// TmemView exposes the RDP texture memory to the debugger: raw contents
+/// through the memory view, and the eight tile descriptors as textures.
+pub struct TmemView<'a>(pub &'a mut Rdp);
+
+impl<'a> MemoryView for TmemView<'a> {
+    fn name(&self) -> &str {
+        "TMEM"
+    }
+
+    fn banks(&self) -> Vec<MemoryBank> {
+        vec![MemoryBank::new(
+            "TMEM",
+            0,
+            self.0.tmem().len() as u64 - 1,
+            true,
+        )]
+    }
+
+    fn mem_slice(&self, _bank_idx: usize, start: u64, end: u64) -> &[u8] {
+        &self.0.tmem()[start as usize..=end as usize]
+    }
+
+    fn mem_slice_mut(&mut self, _bank_idx: usize, start: u64, end: u64) -> &mut [u8] {
+        &mut self.0.tmem_mut()[start as usize..=end as usize]
+    }
+}
+
+impl<'a> TextureView for TmemView<'a> {
+    fn name(&self) -> &str {
+        "RDP"
+    }
+
+    fn num_textures(&self) -> usize {
+        8
+    }
+
+    fn texture_info(&self, idx: usize) -> Vec<String> {
+        self.0.tile_info(idx)
+    }
+
+    fn texture(&self, idx: usize) -> Option<OwnedGfxBufferLE<Rgba8888>> {
+        self.0.decode_tile(idx)
+    }
+
+    fn palette(&self) -> Option<OwnedGfxBufferLE<Rgba8888>> {
+        Some(self.0.decode_tlut())
+    }
+}
@@ -0,0 +1,79 @@
+//! Persistence of battery-backed save memories (EEPROM, SRAM, FlashRAM,
+//! Controller Pak, Game Boy cartridge RAM) to files on disk.
+use crate::errors::*;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Delay (in frames) after the last write, after which a modified save
+// memory is written to disk.
+const AUTOSAVE_FRAMES: u32 = 60;
+
+/// Save file backing a save memory. The memory notifies writes with
+/// modified(), and the file is written once the game has stopped writing
+/// for a while, so that a save made of many writes is stored in one go.
+pub(crate) struct SaveFile {
+    path: Option<PathBuf>,
+    dirty: bool,
+    idle_frames: u32,
+}
+
+impl SaveFile {
+    /// A memory that is not persisted on disk.
+    pub fn none() -> SaveFile {
+        SaveFile {
+            path: None,
+            dirty: false,
+            idle_frames: 0,
+        }
+    }
+
+    pub fn new(path: &Path) -> SaveFile {
+        SaveFile {
+            path: Some(path.to_owned()),
+            ..SaveFile::none()
+        }
+    }
+
+    /// Load the save file into data, if it exists. Returns the number of
+    /// bytes loaded: files of a different size are accepted, and only
+    /// what fits is copied.
+    pub fn load(&self, data: &mut [u8]) -> Result<usize> {
+        match &self.path {
+            Some(path) if path.exists() => {
+                let contents = fs::read(path)?;
+                let len = contents.len().min(data.len());
+                data[..len].copy_from_slice(&contents[..len]);
+                Ok(contents.len())
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Mark the memory as modified.
+    pub fn modified(&mut self) {
+        self.dirty = true;
+        self.idle_frames = 0;
+    }
+
+    /// Called once per frame with the memory contents.
+    pub fn end_frame(&mut self, data: &[u8]) -> Result<()> {
+        if self.dirty {
+            self.idle_frames += 1;
+            if self.idle_frames >= AUTOSAVE_FRAMES {
+                self.flush(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the memory contents to the save file, if they were modified.
+    pub fn flush(&mut self, data: &[u8]) -> Result<()> {
+        if let (true, Some(path)) = (self.dirty, &self.path) {
+            fs::write(path, data)
+                .chain_err(|| format!("cannot write save file: {}", path.display()))?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
@@ -96,26 +96,10 @@ impl Si {
         }
         self.raise_irq();
 
-        if bus.read::<u8>(0x1fc0_07c0) & 1 != 0 {
+        // If the joybus was triggered (bit 0 of the PIF control byte), SI
+        // is busy until PIF has processed the commands.
+        if bus.read::<u8>(0x1FC0_07FF) & 1 != 0 {
             self.set_busy(true);
         }
-
-        // Dump PIF RAM
-        let dst = bus.fetch_read::<u8>(new);
-        let mut mem = dst.iter().unwrap();
-        for i in 0..8 {
-            println!(
-                "SI: {:03x}: {:02x} {:02x} {:02x} {:02x} -- {:02x} {:02x} {:02x} {:02x}",
-                (new & 0xFFF) + i * 8,
-                mem.next().unwrap(),
-                mem.next().unwrap(),
-                mem.next().unwrap(),
-                mem.next().unwrap(),
-                mem.next().unwrap(),
-                mem.next().unwrap(),
-                mem.next().unwrap(),
-                mem.next().unwrap()
-            );
-        }
     }
 }
@@ -0,0 +1,94 @@
+//! Cartridge SRAM, mapped in PI domain 2.
+use crate::cartridge::SaveType;
+use crate::errors::*;
+use crate::savefile::SaveFile;
+
+use emu::bus::be::{Mem, MemFlags};
+use emu_derive::DeviceBE;
+use slog;
+use std::path::Path;
+
+/// Size of a single SRAM bank. 96KB SRAMs are made of three banks, mapped
+/// 256KB apart.
+pub const BANK_SIZE: usize = 0x8000;
+
+#[derive(DeviceBE)]
+pub struct Sram {
+    #[mem(bank = 0, offset = 0, vsize = 0x8000)]
+    bank0: Mem,
+
+    #[mem(bank = 1, offset = 0, vsize = 0x8000)]
+    bank1: Mem,
+
+    #[mem(bank = 2, offset = 0, vsize = 0x8000)]
+    bank2: Mem,
+
+    banks: usize,
+    // The CPU writes directly into the banks, so modifications are
+    // detected by comparing with a copy of the contents.
+    last: Vec<u8>,
+    save: SaveFile,
+    logger: slog::Logger,
+}
+
+impl Sram {
+    /// Create a SRAM of the specified type, backed by the specified
+    /// save file. If the file exists, its contents are loaded.
+    pub fn new(logger: slog::Logger, save_type: SaveType, path: &Path) -> Result<Box<Sram>> {
+        let banks = match save_type {
+            SaveType::Sram32k => 1,
+            SaveType::Sram96k => 3,
+            _ => bail!("invalid SRAM type: {:?}", save_type),
+        };
+
+        let save = SaveFile::new(path);
+        let mut contents = vec![0; BANK_SIZE * 3];
+        save.load(&mut contents[..BANK_SIZE * banks])?;
+
+        let bank = |idx: usize| contents[idx * BANK_SIZE..(idx + 1) * BANK_SIZE].to_vec();
+        Ok(Box::new(Sram {
+            bank0: Mem::from_buffer("sram0", bank(0), MemFlags::default()),
+            bank1: Mem::from_buffer("sram1", bank(1), MemFlags::default()),
+            bank2: Mem::from_buffer("sram2", bank(2), MemFlags::default()),
+            banks,
+            last: contents[..BANK_SIZE * banks].to_vec(),
+            save,
+            logger,
+        }))
+    }
+
+    /// Number of 32KB banks.
+    pub fn banks(&self) -> usize {
+        self.banks
+    }
+
+    /// Current contents of the SRAM (all banks).
+    pub fn contents(&self) -> Vec<u8> {
+        [&self.bank0, &self.bank1, &self.bank2][..self.banks]
+            .iter()
+            .flat_map(|b| b.iter().cloned())
+            .collect()
+    }
+
+    /// Write the SRAM contents to its save file, if they were modified.
+    pub fn flush(&mut self) -> Result<()> {
+        self.check_modified();
+        self.save.flush(&self.last)
+    }
+
+    /// Called once per frame.
+    pub fn end_frame(&mut self) {
+        self.check_modified();
+        if let Err(err) = self.save.end_frame(&self.last) {
+            error!(self.logger, "cannot save SRAM"; o!("err" => err.to_string()));
+        }
+    }
+
+    fn check_modified(&mut self) {
+        let contents = self.contents();
+        if contents != self.last {
+            self.last = contents;
+            self.save.modified();
+        }
+    }
+}
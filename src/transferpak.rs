@@ -0,0 +1,256 @@
+//! Transfer Pak: a controller accessory that holds a Game Boy cartridge,
+//! whose address space is accessed by the N64 through 16KB windows.
+//!
+//! Accessory address map:
+//!
+//!   0x8000-0x8FFF  power: write 0x84 to turn on, 0xFE to turn off
+//!   0xA000-0xAFFF  bank: selects which 16KB of the Game Boy address space
+//!                  is visible in the 0xC000-0xFFFF window
+//!   0xB000-0xBFFF  status (R) / access mode (W)
+//!   0xC000-0xFFFF  Game Boy cartridge window
+use crate::errors::*;
+use crate::savefile::SaveFile;
+
+use std::fs;
+use std::path::Path;
+
+// Bits of the status register
+const STATUS_ACCESS: u8 = 0x01;
+const STATUS_MODE_CHANGED: u8 = 0x04;
+const STATUS_RESET: u8 = 0x08;
+const STATUS_NO_CART: u8 = 0x40;
+const STATUS_POWER: u8 = 0x80;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mbc {
+    None,
+    Mbc1,
+    Mbc3,
+    Mbc5,
+}
+
+/// A Game Boy cartridge, with its memory bank controller (MBC1, MBC3 and
+/// MBC5 are supported; the MBC3 real-time clock is not emulated).
+pub struct GbCart {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Mbc,
+    rom_bank: usize,
+    ram_bank: usize,
+    ram_enabled: bool,
+    ram_banking: bool, // MBC1 banking mode
+    save: SaveFile,
+}
+
+impl GbCart {
+    /// Load a Game Boy ROM. If the cartridge has a battery, its RAM is
+    /// backed by a .sav file next to the ROM.
+    pub fn load(romfn: &Path) -> Result<GbCart> {
+        let rom = fs::read(romfn)
+            .chain_err(|| format!("cannot open Game Boy ROM: {}", romfn.display()))?;
+        if rom.len() < 0x8000 {
+            bail!("invalid Game Boy ROM: {}", romfn.display());
+        }
+
+        let (mbc, battery) = match rom[0x147] {
+            0x00 => (Mbc::None, false),
+            0x01 | 0x02 => (Mbc::Mbc1, false),
+            0x03 => (Mbc::Mbc1, true),
+            0x11 | 0x12 => (Mbc::Mbc3, false),
+            0x0F | 0x10 | 0x13 => (Mbc::Mbc3, true),
+            0x19 | 0x1A | 0x1C | 0x1D => (Mbc::Mbc5, false),
+            0x1B | 0x1E => (Mbc::Mbc5, true),
+            t => bail!("unsupported Game Boy cartridge type: {:#04x}", t),
+        };
+        let ram_size = match rom[0x149] {
+            0 => 0,
+            1 => 0x800,
+            2 => 0x2000,
+            3 => 0x8000,
+            4 => 0x2_0000,
+            5 => 0x1_0000,
+            s => bail!("invalid Game Boy RAM size: {:#04x}", s),
+        };
+
+        let mut cart = GbCart {
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            ram_banking: false,
+            save: SaveFile::none(),
+        };
+
+        if battery && ram_size > 0 {
+            cart.save = SaveFile::new(&romfn.with_extension("sav"));
+            cart.save.load(&mut cart.ram)?;
+        }
+        Ok(cart)
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Read a byte from the Game Boy address space (0x0000-0xBFFF).
+    pub fn read(&self, addr: u16) -> u8 {
+        let addr = addr as usize;
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr],
+            0x4000..=0x7FFF => self.rom[(self.rom_bank * 0x4000 + addr - 0x4000) % self.rom.len()],
+            0xA000..=0xBFFF if self.ram_enabled && !self.ram.is_empty() => {
+                self.ram[(self.ram_bank * 0x2000 + addr - 0xA000) % self.ram.len()]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    /// Write a byte to the Game Boy address space: writes to the ROM area
+    /// are MBC commands.
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match (addr, self.mbc) {
+            (0x0000..=0x1FFF, _) => self.ram_enabled = val & 0x0F == 0x0A,
+            (0x2000..=0x3FFF, Mbc::Mbc1) => {
+                let bank = (val & 0x1F).max(1) as usize;
+                self.rom_bank = (self.rom_bank & 0x60) | bank;
+            }
+            (0x2000..=0x3FFF, Mbc::Mbc3) => self.rom_bank = (val & 0x7F).max(1) as usize,
+            (0x2000..=0x2FFF, Mbc::Mbc5) => self.rom_bank = (self.rom_bank & 0x100) | val as usize,
+            (0x3000..=0x3FFF, Mbc::Mbc5) => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((val as usize & 1) << 8)
+            }
+            (0x4000..=0x5FFF, Mbc::Mbc1) => {
+                if self.ram_banking {
+                    self.ram_bank = val as usize & 3;
+                } else {
+                    self.rom_bank = (self.rom_bank & 0x1F) | ((val as usize & 3) << 5);
+                }
+            }
+            // Values 0x08-0x0C select the RTC registers, which are not emulated
+            (0x4000..=0x5FFF, Mbc::Mbc3) if val <= 3 => self.ram_bank = val as usize,
+            (0x4000..=0x5FFF, Mbc::Mbc5) => self.ram_bank = val as usize & 0x0F,
+            (0x6000..=0x7FFF, Mbc::Mbc1) => self.ram_banking = val & 1 != 0,
+            (0xA000..=0xBFFF, _) if self.ram_enabled && !self.ram.is_empty() => {
+                let off = (self.ram_bank * 0x2000 + addr as usize - 0xA000) % self.ram.len();
+                self.ram[off] = val;
+                self.save.modified();
+            }
+            _ => {}
+        }
+    }
+
+    /// Write the cartridge RAM to its save file, if it was modified.
+    pub fn flush(&mut self) -> Result<()> {
+        self.save.flush(&self.ram)
+    }
+
+    /// Called once per frame.
+    pub fn autosave(&mut self) -> Result<()> {
+        self.save.end_frame(&self.ram)
+    }
+}
+
+pub struct TransferPak {
+    cart: Option<GbCart>,
+    power: bool,
+    bank: usize,
+    access: bool,
+    mode_changed: bool,
+}
+
+impl TransferPak {
+    /// Create a Transfer Pak, optionally with a Game Boy cartridge inserted.
+    pub fn new(cart: Option<GbCart>) -> TransferPak {
+        TransferPak {
+            cart,
+            power: false,
+            bank: 0,
+            access: false,
+            mode_changed: false,
+        }
+    }
+
+    pub fn cart(&self) -> Option<&GbCart> {
+        self.cart.as_ref()
+    }
+
+    fn status(&mut self) -> u8 {
+        let mut status = match (&self.cart, self.access) {
+            (None, _) => STATUS_NO_CART,
+            (Some(_), true) => STATUS_POWER | STATUS_RESET | STATUS_ACCESS,
+            (Some(_), false) => STATUS_POWER,
+        };
+        if self.mode_changed {
+            status |= STATUS_MODE_CHANGED;
+            self.mode_changed = false;
+        }
+        status
+    }
+
+    // Game Boy address corresponding to an address in the cartridge window.
+    fn gb_addr(&self, addr: u16) -> u16 {
+        ((addr & 0x3FFF) as usize + self.bank * 0x4000) as u16
+    }
+
+    pub fn read(&mut self, addr: u16, out: &mut [u8]) {
+        let val = match addr >> 12 {
+            0x8 if self.power => 0x84,
+            0xB if self.power => self.status(),
+            0xC..=0xF if self.power && self.access => {
+                if let Some(cart) = &self.cart {
+                    let base = self.gb_addr(addr);
+                    for (i, v) in out.iter_mut().enumerate() {
+                        *v = cart.read(base.wrapping_add(i as u16));
+                    }
+                    return;
+                }
+                0x00
+            }
+            _ => 0x00,
+        };
+        for v in out.iter_mut() {
+            *v = val;
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: &[u8]) {
+        let val = data[data.len() - 1];
+        match addr >> 12 {
+            0x8 => match val {
+                0x84 => self.power = true,
+                0xFE => self.power = false,
+                _ => {}
+            },
+            0xA if self.power => self.bank = val as usize & 3,
+            0xB if self.power => {
+                self.access = val & 1 != 0;
+                self.mode_changed = true;
+            }
+            0xC..=0xF if self.power && self.access => {
+                let base = self.gb_addr(addr);
+                if let Some(cart) = self.cart.as_mut() {
+                    for (i, v) in data.iter().enumerate() {
+                        cart.write(base.wrapping_add(i as u16), *v);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match self.cart.as_mut() {
+            Some(cart) => cart.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn end_frame(&mut self) -> Result<()> {
+        match self.cart.as_mut() {
+            Some(cart) => cart.autosave(),
+            None => Ok(()),
+        }
+    }
+}
@@ -1,12 +1,39 @@
 use emu::bus::be::{Device, Reg32};
+use emu::dbg::OptionView;
 use emu::gfx::*;
 use emu::int::Numerics;
 use emu_derive::DeviceBE;
 
+use super::dp::Dp;
 use super::mi::{IrqMask, Mi};
+use super::n64::Region;
 use super::r4300::R4300;
 
 use slog;
+use std::str::FromStr;
+
+/// Deinterlacing method used to display interlaced modes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Deinterlace {
+    /// Merge the lines of the two most recent fields into the same picture
+    /// (best for still images).
+    Weave,
+    /// Display each field alone, doubling its lines (no combing artifacts
+    /// on moving images, at the cost of vertical resolution).
+    Bob,
+}
+
+impl FromStr for Deinterlace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weave" => Ok(Deinterlace::Weave),
+            "bob" => Ok(Deinterlace::Bob),
+            _ => Err(format!("invalid deinterlace mode: {}", s)),
+        }
+    }
+}
 
 #[derive(DeviceBE)]
 pub struct Vi {
@@ -29,7 +56,8 @@ pub struct Vi {
     //     3: neither (replicate pixels, no interpolate)
     // [11] reserved - diagnostics only
     // [15:12] reserved
-    #[reg(offset = 0x00, rwmask = 0xFFFF)]
+    // [16] dither_filter_enable (restore 16-bit dithered pixels)
+    #[reg(offset = 0x00, rwmask = 0x1FFFF)]
     status: Reg32,
 
     // [23:0] frame buffer origin in bytes
@@ -100,6 +128,23 @@ pub struct Vi {
 
     logger: slog::Logger,
     framecount: usize,
+    frame: OwnedGfxBufferLE<Rgb888>,
+    field: usize, // field being scanned out (always 0 in non-interlaced modes)
+    deinterlace: Deinterlace,
+    filters: Filters,
+    dither_seed: u32,
+    region: Region,
+}
+
+/// Filters of the VI output stage that can be disabled from the debugger,
+/// to compare the picture with and without them. Each filter is applied
+/// only if also enabled in the status register.
+#[derive(Copy, Clone)]
+struct Filters {
+    aa: bool,
+    divot: bool,
+    gamma: bool,
+    dither_filter: bool,
 }
 
 impl Vi {
@@ -121,17 +166,106 @@ impl Vi {
             y_scale: Reg32::default(),
             logger,
             framecount: 0,
+            frame: OwnedGfxBufferLE::new(0, 0),
+            field: 0,
+            deinterlace: Deinterlace::Weave,
+            filters: Filters {
+                aa: true,
+                divot: true,
+                gamma: true,
+                dither_filter: true,
+            },
+            dither_seed: 1,
+            region: Region::Ntsc,
         })
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Return the video timing programmed in the registers, as (half-lines
+    /// per field, duration of a line, extra duration of the leap line), with
+    /// durations in VCLK cycles. Until the VI is programmed, the standard
+    /// timing of the console region is used.
+    pub fn timing(&self) -> (usize, usize, usize) {
+        let (mut vsync, mut hsync, mut leap) = (
+            self.vertical_sync.get() & 0x3FF,
+            self.horizontal_sync.get(),
+            self.horizontal_sync_leap.get(),
+        );
+        if vsync == 0 || hsync & 0xFFF == 0 {
+            let (v, h, l) = self.region.vi_timing();
+            vsync = v;
+            hsync = h;
+            leap = l;
+        }
+        let line = (hsync & 0xFFF) as usize + 1;
+
+        // PAL: during vertical sync, the line duration is replaced by one of
+        // the two leap values, selected for each of five consecutive fields
+        // by the leap pattern.
+        let pattern = (hsync >> 16) & 0x1F;
+        let (leap_a, leap_b) = split_reg(leap);
+        let leap = if pattern & (1 << (self.framecount % 5)) != 0 {
+            leap_a
+        } else {
+            leap_b
+        };
+        let extra = (leap as usize + 1).saturating_sub(line);
+
+        (vsync as usize + 1, line, extra)
+    }
+
+    pub fn set_deinterlace(&mut self, mode: Deinterlace) {
+        self.deinterlace = mode;
+    }
+
+    fn interlaced(&self) -> bool {
+        self.status.get() & (1 << 6) != 0
+    }
+
+    /// Called at the beginning of each half-line.
     pub fn set_line(&mut self, y: usize) {
-        // FIXME: handle interleaved mode (LSB is fixed within the same field)
-        // FIXME: NTSC has 525 lines, what happens to this 9-bit register when line > 512?
-        self.current_line.set(y as u32);
+        // The register is sampled once per line, so it counts half-lines
+        // two by two; its LSB is the field number, which is constant within
+        // a field (and always 0 in non-interlaced modes).
+        let line = (((y as u32) & !1) | self.field as u32) & 0x3FF;
+        self.current_line.set(line);
 
-        if y as u32 == self.vertical_interrupt.get() {
+        // The interrupt is raised once per line, when the line containing
+        // the programmed half-line begins. This is the same in both fields,
+        // so interlaced modes get one interrupt per field.
+        if y & 1 == 0 && line >> 1 == self.vertical_interrupt.get() >> 1 {
             Mi::get_mut().set_irq_line(IrqMask::VI, true);
         }
+
+        self.scan_line(y);
+    }
+
+    // Scan out the line beginning at the specified half-line, if it is
+    // within the active video area. Each line is rendered with the register
+    // values current at that time, so that mid-frame changes (eg: of origin
+    // or scale, for split-screen effects) are visible.
+    fn scan_line(&mut self, y: usize) {
+        let (vstart, vend) = split_reg(self.vertical_video.get());
+        let (vstart, vend) = (vstart as usize, vend as usize);
+        if y < vstart || y >= vend || (y - vstart) & 1 != 0 {
+            return;
+        }
+        let line = (y - vstart) / 2;
+
+        if line == 0 {
+            info!(self.logger, "draw frame"; o!("origin" => self.origin.get().hex()));
+        }
+
+        let memio = R4300::get().bus.fetch_read::<u8>(0);
+        let rdram = memio.mem().unwrap();
+        self.render_line(rdram, line);
     }
 
     fn cb_write_current_line(&mut self, _old: u32, _new: u32) {
@@ -144,80 +278,384 @@ impl Vi {
         info!(self.logger, "change VI interrupt"; "line" => new);
     }
 
-    pub fn begin_frame(&mut self, _screen: &mut GfxBufferMutLE<Rgb888>) {}
+    /// Size of the output picture, as programmed in the VI registers. It
+    /// covers the active video area, with one pixel per screen pixel
+    /// horizontally and one line per half-line vertically (so that each line
+    /// of a non-interlaced field covers two lines of the output).
+    pub fn output_size(&self) -> (usize, usize) {
+        let (hstart, hend) = split_reg(self.horizontal_video.get());
+        let (vstart, vend) = split_reg(self.vertical_video.get());
+        (
+            (hend as usize).saturating_sub(hstart as usize),
+            (vend as usize).saturating_sub(vstart as usize) & !1,
+        )
+    }
 
-    pub fn end_frame(&mut self, screen: &mut GfxBufferMutLE<Rgb888>) {
-        self.framecount += 1;
+    /// Return the picture produced by the last scanout, at the resolution
+    /// programmed in the VI registers (see `output_size`).
+    pub fn frame(&self) -> GfxBufferLE<Rgb888> {
+        self.frame.buf()
+    }
+
+    fn scanout(&self) -> Scanout {
+        let status = self.status.get();
+        let (xoff, xscale) = split_reg(self.x_scale.get());
+        let (yoff, yscale) = split_reg(self.y_scale.get());
+        let aamode = (status >> 8) & 3;
+        let bpp32 = status & 3 == 3;
+        Scanout {
+            origin: self.origin.get() as usize,
+            width: self.width.get() as usize,
+            bpp32,
+            interpolate: aamode != 3,
+            aa: aamode < 2 && self.filters.aa,
+            divot: status & (1 << 4) != 0 && self.filters.divot,
+            dither_filter: status & (1 << 16) != 0 && !bpp32 && self.filters.dither_filter,
+            xscale,
+            xoff,
+            yscale,
+            yoff,
+        }
+    }
+
+    // Apply gamma correction to a color component. Gamma dither adds random
+    // noise to the lower bits to hide the banding caused by the correction.
+    fn gamma(&mut self, c: i32) -> i32 {
+        let c = c.max(0).min(0xFF);
+        let status = self.status.get();
+        let dither = if status & (1 << 2) != 0 {
+            // xorshift32
+            self.dither_seed ^= self.dither_seed << 13;
+            self.dither_seed ^= self.dither_seed >> 17;
+            self.dither_seed ^= self.dither_seed << 5;
+            self.dither_seed as i32 & 0x3F
+        } else {
+            0
+        };
+
+        if status & (1 << 3) != 0 && self.filters.gamma {
+            ((((c << 6) + dither) as f32).sqrt() * 2.0) as i32
+        } else {
+            (c + (dither >> 5)).min(0xFF)
+        }
+    }
 
-        let bpp = self.status.get() & 3;
+    // Render the specified line of the current field into the output picture.
+    fn render_line(&mut self, rdram: &[u8], y: usize) {
+        let sc = self.scanout();
+        let fy = sc.yoff + y as u32 * sc.yscale;
 
-        // display disable -> clear screen
-        if bpp == 0 || bpp == 1 {
+        // In non-interlaced modes, each line covers two output lines. In
+        // interlaced modes, the lines of each field are displayed in
+        // between the lines of the other field: weave keeps the other field
+        // on screen, while bob doubles the lines of the current field
+        // (shifted by one line on odd fields).
+        let (y1, y2) = match (self.interlaced(), self.deinterlace) {
+            (false, _) => (y * 2, Some(y * 2 + 1)),
+            (true, Deinterlace::Weave) => (y * 2 + self.field, None),
+            (true, Deinterlace::Bob) => (y * 2 + self.field, Some(y * 2 + self.field + 1)),
+        };
+        let (width, height) = (self.frame.width(), self.frame.height());
+        let y2 = y2.filter(|&y2| y2 < height);
+        if y1 >= height {
+            return;
+        }
+
+        // Blank (or reserved) mode: no data is fetched, the line is black.
+        if self.status.get() & 3 < 2 {
             let black = Color::<Rgb888>::new_clamped(0, 0, 0, 0);
-            for y in 0..480 {
-                let mut line = screen.line(y);
-                for x in 0..640 {
-                    line.set(x, black);
+            let mut frame = self.frame.buf_mut();
+            for dy in Some(y1).into_iter().chain(y2) {
+                let mut dst = frame.line(dy);
+                for x in 0..width {
+                    dst.set(x, black);
                 }
             }
             return;
         }
 
-        info!(self.logger, "draw frame"; o!("origin" => self.origin.get().hex()));
-        let memio = R4300::get().bus.fetch_read::<u8>(self.origin.get());
-        let src = memio.mem().unwrap();
+        // Filter the (one or two) framebuffer lines needed for the vertical
+        // interpolation, then resample them horizontally.
+        let (fbline, wy) = ((fy >> 10) as usize, (fy & 0x3FF) as i32);
+        let top = sc.filter_line(rdram, fbline);
+        let bottom = if sc.interpolate && wy != 0 {
+            Some(sc.filter_line(rdram, fbline + 1))
+        } else {
+            None
+        };
+
+        let mut line: Vec<Color<Rgb888>> = Vec::with_capacity(width);
+        for x in 0..width {
+            let fx = sc.xoff + x as u32 * sc.xscale;
+            let mut c = sc.sample(&top, fx);
+            if let Some(bottom) = &bottom {
+                c = lerp(c, sc.sample(bottom, fx), wy);
+            }
+            let (r, g, b) = (self.gamma(c[0]), self.gamma(c[1]), self.gamma(c[2]));
+            line.push(Color::<Rgb888>::new_clamped(r, g, b, 0));
+        }
+
+        let mut frame = self.frame.buf_mut();
+        for dy in Some(y1).into_iter().chain(y2) {
+            let mut dst = frame.line(dy);
+            for (x, px) in line.iter().enumerate() {
+                dst.set(x, *px);
+            }
+        }
+    }
+
+    // Copy the output picture onto the screen. If the picture fits, it is
+    // copied 1:1 in the top-left corner; otherwise, it is scaled down to fit.
+    fn blit(&self, screen: &mut GfxBufferMutLE<Rgb888>) {
+        let black = Color::<Rgb888>::new_clamped(0, 0, 0, 0);
+        let src = self.frame.buf();
+        let (sw, sh) = (src.width(), src.height());
+        let (dw, dh) = (screen.width(), screen.height());
+        let (num_x, den_x) = if sw <= dw { (1, 1) } else { (sw, dw) };
+        let (num_y, den_y) = if sh <= dh { (1, 1) } else { (sh, dh) };
 
-        match self.width.get() {
-            640 => {
-                let src = GfxBufferLE::<Rgb888>::new(src, 640, 480, 640 * 4).unwrap();
-                for y in 0..480 {
-                    let mut dst = screen.line(y);
-                    let src = src.line(y);
-                    for x in 0..640 {
-                        dst.set(x, src.get(x));
-                    }
+        for y in 0..dh {
+            let mut dst = screen.line(y);
+            let sy = y * num_y / den_y;
+            if sy >= sh {
+                for x in 0..dw {
+                    dst.set(x, black);
                 }
+                continue;
             }
+            let src = src.line(sy);
+            for x in 0..dw {
+                let sx = x * num_x / den_x;
+                dst.set(x, if sx < sw { src.get(sx) } else { black });
+            }
+        }
+    }
+
+    pub fn begin_frame(&mut self, _screen: &mut GfxBufferMutLE<Rgb888>) {
+        // Fields alternate at each frame in interlaced modes.
+        self.field = if self.interlaced() { self.field ^ 1 } else { 0 };
+
+        let (width, height) = self.output_size();
+        if (self.frame.width(), self.frame.height()) != (width, height) {
+            info!(self.logger, "change output resolution"; o!("width" => width, "height" => height));
+            self.frame = OwnedGfxBufferLE::new(width, height);
+        }
+    }
+
+    pub fn end_frame(&mut self, screen: &mut GfxBufferMutLE<Rgb888>) {
+        self.framecount += 1;
+        Dp::get_mut().end_frame();
+        self.blit(screen);
+    }
+}
+
+// Split a VI register made of two 10/12-bit fields into (high, low).
+fn split_reg(val: u32) -> (u32, u32) {
+    ((val >> 16) & 0xFFF, val & 0xFFF)
+}
+
+// Linear interpolation between two colors, with a 0.10 fixed point weight.
+fn lerp(a: [i32; 3], b: [i32; 3], w: i32) -> [i32; 3] {
+    [
+        a[0] + (((b[0] - a[0]) * w) >> 10),
+        a[1] + (((b[1] - a[1]) * w) >> 10),
+        a[2] + (((b[2] - a[2]) * w) >> 10),
+    ]
+}
+
+// Coverage value of a pixel fully covered by a primitive.
+const FULL_COVERAGE: i32 = 7;
+
+// A framebuffer pixel, with its coverage (0-7).
+#[derive(Copy, Clone, Default)]
+struct Pixel {
+    c: [i32; 3],
+    cvg: i32,
+}
+
+// Snapshot of the registers that control how the framebuffer is fetched from
+// RDRAM, filtered and resampled to the output resolution.
+#[derive(Copy, Clone)]
+struct Scanout {
+    origin: usize,
+    width: usize,
+    bpp32: bool,
+    interpolate: bool,
+    aa: bool,
+    divot: bool,
+    dither_filter: bool,
+    // Scale factors and offsets, in 2.10 fixed point.
+    xscale: u32,
+    xoff: u32,
+    yscale: u32,
+    yoff: u32,
+}
+
+impl Scanout {
+    // Fetch a single framebuffer pixel. Coordinates are clamped to the
+    // framebuffer width; pixels outside of RDRAM are black.
+    fn fetch(&self, rdram: &[u8], x: isize, y: isize) -> Pixel {
+        if self.width == 0 {
+            return Pixel::default();
+        }
+        let x = x.max(0).min(self.width as isize - 1) as usize;
+        let y = y.max(0) as usize;
+        let size = if self.bpp32 { 4 } else { 2 };
+        let addr = self.origin + (y * self.width + x) * size;
+        if addr + size > rdram.len() {
+            return Pixel::default();
+        }
+
+        let mem = &rdram[addr..addr + size];
+        let mut px = if self.bpp32 {
+            Pixel {
+                c: [mem[0] as i32, mem[1] as i32, mem[2] as i32],
+                cvg: mem[3] as i32 >> 5,
+            }
+        } else {
+            // The coverage is made of the alpha bit plus two bits stored in
+            // the hidden RDRAM bits, which are not emulated: without them,
+            // edges cannot be told apart, so treat all pixels as fully covered.
+            let v = u16::from_be_bytes([mem[0], mem[1]]);
+            let c: Color<Rgb888> = Color::<Xbgr1555>::from_bits(v).cconv();
+            let (r, g, b, _) = c.components();
+            Pixel {
+                c: [r, g, b],
+                cvg: FULL_COVERAGE,
+            }
+        };
+        if !self.aa {
+            // Resample-only mode: treat all pixels as fully covered.
+            px.cvg = FULL_COVERAGE;
+        }
+        px
+    }
+
+    // Anti-alias filter for partially covered (edge) pixels: estimate the
+    // background color from the fully covered neighbours, and blend it with
+    // the pixel proportionally to the missing coverage.
+    fn antialias(&self, rdram: &[u8], x: isize, y: isize, px: Pixel) -> [i32; 3] {
+        const NEIGHBOURS: [(isize, isize); 6] = [(-1, -1), (1, -1), (-2, 0), (2, 0), (-1, 1), (1, 1)];
 
-            320 => {
-                match bpp {
-                    // 32-bit
-                    3 => {
-                        let src = GfxBufferLE::<Rgb888>::new(src, 320, 240, 320 * 4).unwrap();
-                        for y in 0..240 {
-                            let (mut dst1, mut dst2) = screen.lines(y * 2, y * 2 + 1);
-                            let src = src.line(y);
-                            for x in 0..320 {
-                                let px = src.get(x);
-                                dst1.set(x * 2, px);
-                                dst1.set(x * 2 + 1, px);
-                                dst2.set(x * 2, px);
-                                dst2.set(x * 2 + 1, px);
-                            }
-                        }
-                    }
-                    // 16-bit
-                    2 => {
-                        let src = GfxBufferBE::<Xbgr1555>::new(src, 320, 240, 320 * 2).unwrap();
-                        for y in 0..240 {
-                            let (mut dst1, mut dst2) = screen.lines(y * 2, y * 2 + 1);
-                            let src = src.line(y);
-                            for x in 0..320 {
-                                let px = src.get(x).cconv();
-                                dst1.set(x * 2, px);
-                                dst1.set(x * 2 + 1, px);
-                                dst2.set(x * 2, px);
-                                dst2.set(x * 2 + 1, px);
-                            }
-                        }
-                    }
-                    _ => unimplemented!(),
+        let mut values = [[0i32; 7]; 3];
+        let mut n = 0;
+        for (dx, dy) in NEIGHBOURS.iter() {
+            let np = self.fetch(rdram, x + dx, y + dy);
+            if np.cvg == FULL_COVERAGE {
+                for ch in 0..3 {
+                    values[ch][n] = np.c[ch];
                 }
+                n += 1;
             }
+        }
+        if n == 0 {
+            return px.c;
+        }
+        for ch in 0..3 {
+            values[ch][n] = px.c[ch];
+        }
+        n += 1;
 
-            _ => {
-                error!(self.logger, "unsupported screen width"; o!("width" => self.width.get()));
+        let mut out = [0i32; 3];
+        for ch in 0..3 {
+            // Use the penultimate maximum and minimum, to reject outliers.
+            let v = &mut values[ch][..n];
+            v.sort();
+            let (min, max) = if n > 2 { (v[1], v[n - 2]) } else { (v[0], v[n - 1]) };
+            let bg = max + min - px.c[ch];
+            out[ch] = px.c[ch] + (((bg - px.c[ch]) * (FULL_COVERAGE - px.cvg) + 4) >> 3);
+        }
+        out
+    }
+
+    // Dither de-filter for fully covered 16-bit pixels: move each component
+    // towards the neighbouring pixels, to restore part of the color precision
+    // lost by the RDP dithering.
+    fn restore(&self, rdram: &[u8], x: isize, y: isize, px: Pixel) -> [i32; 3] {
+        let mut out = px.c;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+                let np = self.fetch(rdram, x + dx, y + dy);
+                for ch in 0..3 {
+                    out[ch] += (np.c[ch] - px.c[ch]).signum();
+                }
             }
         }
+        [out[0].max(0).min(0xFF), out[1].max(0).min(0xFF), out[2].max(0).min(0xFF)]
+    }
+
+    // Fetch and filter a framebuffer line (anti-alias, dither de-filter and
+    // divot), returning its colors.
+    fn filter_line(&self, rdram: &[u8], y: usize) -> Vec<[i32; 3]> {
+        let y = y as isize;
+        let pixels: Vec<Pixel> = (0..self.width as isize)
+            .map(|x| {
+                let px = self.fetch(rdram, x, y);
+                let c = if px.cvg != FULL_COVERAGE {
+                    self.antialias(rdram, x, y, px)
+                } else if self.dither_filter {
+                    self.restore(rdram, x, y, px)
+                } else {
+                    px.c
+                };
+                Pixel { c, cvg: px.cvg }
+            })
+            .collect();
+
+        // Divot filter: remove the one-pixel artifacts that the anti-alias
+        // filter can leave along edges, by taking the median of three
+        // horizontally adjacent pixels where any of them is not fully covered.
+        (0..pixels.len())
+            .map(|x| {
+                let px = pixels[x];
+                if !self.divot || x == 0 || x + 1 >= pixels.len() {
+                    return px.c;
+                }
+                let (l, r) = (pixels[x - 1], pixels[x + 1]);
+                if l.cvg == FULL_COVERAGE && px.cvg == FULL_COVERAGE && r.cvg == FULL_COVERAGE {
+                    return px.c;
+                }
+                let median = |a: i32, b: i32, c: i32| a.max(b).min(a.min(b).max(c));
+                [
+                    median(l.c[0], px.c[0], r.c[0]),
+                    median(l.c[1], px.c[1], r.c[1]),
+                    median(l.c[2], px.c[2], r.c[2]),
+                ]
+            })
+            .collect()
+    }
+
+    // Sample a filtered line at the specified horizontal position (2.10
+    // fixed point), interpolating between the two neighbouring pixels unless
+    // the anti-alias mode asks for replication.
+    fn sample(&self, line: &[[i32; 3]], fx: u32) -> [i32; 3] {
+        if line.is_empty() {
+            return [0, 0, 0];
+        }
+        let x = ((fx >> 10) as usize).min(line.len() - 1);
+        let wx = (fx & 0x3FF) as i32;
+        if !self.interpolate || wx == 0 || x + 1 >= line.len() {
+            return line[x];
+        }
+        lerp(line[x], line[x + 1], wx)
+    }
+}
+
+impl OptionView for Vi {
+    fn name(&self) -> &str {
+        "VI"
+    }
+
+    fn visit_options<F>(&mut self, mut visit: F)
+    where
+        F: for<'a> FnMut(&'a str, &'a mut bool),
+    {
+        visit("Anti-alias", &mut self.filters.aa);
+        visit("Divot filter", &mut self.filters.divot);
+        visit("Gamma", &mut self.filters.gamma);
+        visit("Dither filter", &mut self.filters.dither_filter);
     }
 }
@@ -0,0 +1,101 @@
+extern crate r64emu;
+
+use r64emu::accessory::{Accessory, RumblePak};
+use r64emu::transferpak::{GbCart, TransferPak};
+use std::fs;
+
+#[test]
+fn rumble_pak() {
+    let mut pak = Accessory::Rumble(RumblePak::new());
+    let mut buf = [0u8; 32];
+
+    // Detection
+    pak.write(0x8000, &[0xFE; 32]);
+    pak.read(0x8000, &mut buf);
+    assert_eq!(buf, [0x80; 32]);
+    pak.read(0x0000, &mut buf);
+    assert_eq!(buf, [0x00; 32]);
+
+    assert_eq!(pak.motor(), false);
+    pak.write(0xC000, &[0x01; 32]);
+    assert_eq!(pak.motor(), true);
+    pak.write(0xC000, &[0x00; 32]);
+    assert_eq!(pak.motor(), false);
+}
+
+// Create a Game Boy ROM with a MBC3 and 32KB of battery-backed RAM, where
+// each 16KB bank is filled with its own index.
+fn make_gb_rom(name: &str) -> std::path::PathBuf {
+    let mut rom = vec![0u8; 0x4000 * 8];
+    for (i, v) in rom.iter_mut().enumerate() {
+        *v = (i / 0x4000) as u8;
+    }
+    rom[0x147] = 0x13;
+    rom[0x149] = 0x03;
+
+    let path = std::env::temp_dir().join(name);
+    fs::write(&path, &rom).unwrap();
+    fs::remove_file(path.with_extension("sav")).ok();
+    path
+}
+
+#[test]
+fn transfer_pak() {
+    let path = make_gb_rom("r64emu_transferpak_test.gb");
+    let mut pak = Accessory::Transfer(TransferPak::new(Some(GbCart::load(&path).unwrap())));
+    let mut buf = [0u8; 32];
+
+    // Powered off
+    pak.read(0x8000, &mut buf);
+    assert_eq!(buf, [0x00; 32]);
+
+    pak.write(0x8000, &[0x84; 32]);
+    pak.read(0x8000, &mut buf);
+    assert_eq!(buf, [0x84; 32]);
+
+    pak.write(0xB000, &[0x01; 32]);
+    pak.read(0xB000, &mut buf);
+    assert_eq!(buf[0], 0x8D);
+    pak.read(0xB000, &mut buf);
+    assert_eq!(buf[0], 0x89);
+
+    // Game Boy 0x4000 (switchable ROM bank) is at window bank 1
+    pak.write(0xA000, &[0x01; 32]);
+    pak.read(0xC000, &mut buf);
+    assert_eq!(buf, [0x01; 32]);
+
+    // Select ROM bank 5 through the MBC
+    pak.write(0xA000, &[0x00; 32]);
+    pak.write(0xE000, &[0x05; 32]);
+    pak.write(0xA000, &[0x01; 32]);
+    pak.read(0xC000, &mut buf);
+    assert_eq!(buf, [0x05; 32]);
+
+    // Enable and write the cartridge RAM (Game Boy 0xA000, window bank 2)
+    pak.write(0xA000, &[0x00; 32]);
+    pak.write(0xC000, &[0x0A; 32]);
+    pak.write(0xA000, &[0x02; 32]);
+    pak.write(0xE000, &[0x42; 32]);
+    pak.read(0xE000, &mut buf);
+    assert_eq!(buf, [0x42; 32]);
+
+    pak.flush().unwrap();
+    let saved = fs::read(path.with_extension("sav")).unwrap();
+    assert_eq!(saved.len(), 0x8000);
+    assert_eq!(&saved[..32], &[0x42; 32]);
+
+    let cart = GbCart::load(&path).unwrap();
+    assert_eq!(&cart.ram()[..32], &[0x42; 32]);
+
+    fs::remove_file(path.with_extension("sav")).ok();
+    fs::remove_file(&path).ok();
+}
+
+#[test]
+fn transfer_pak_no_cart() {
+    let mut pak = TransferPak::new(None);
+    let mut buf = [0u8; 32];
+    pak.write(0x8000, &[0x84; 32]);
+    pak.read(0xB000, &mut buf);
+    assert_eq!(buf[0], 0x40);
+}
@@ -0,0 +1,52 @@
+extern crate r64emu;
+
+use r64emu::ai::dac_period;
+use r64emu::Region;
+
+// Compute the register values programmed by libultra's osAiSetFrequency.
+fn ai_set_frequency(vclk: i64, freq: i64) -> (u32, u32) {
+    let dac_rate = (vclk as f64 / freq as f64 + 0.5) as u32;
+    let bit_rate = (dac_rate / 66).min(16);
+    (dac_rate - 1, bit_rate - 1)
+}
+
+#[test]
+fn ai_dac_pitch_all_bit_rates() {
+    for region in &[Region::Ntsc, Region::Pal, Region::Mpal] {
+        let vclk = region.vclk();
+        // Higher frequencies result in bit rates below 16 bits.
+        for &freq in &[11025, 22050, 32000, 44100, 48000, 64000, 96000] {
+            let (dac_rate, bit_rate) = ai_set_frequency(vclk, freq);
+            let period = dac_period(dac_rate, bit_rate).unwrap();
+            assert_eq!(
+                period,
+                dac_rate as i64 + 1,
+                "freq:{} bitrate:{}",
+                freq,
+                bit_rate
+            );
+
+            let out = vclk as f64 / period as f64;
+            assert!(
+                (out - freq as f64).abs() / (freq as f64) < 0.002,
+                "freq:{} bitrate:{} out:{}",
+                freq,
+                bit_rate,
+                out
+            );
+        }
+    }
+}
+
+#[test]
+fn ai_dac_bit_rate_too_slow() {
+    // The DAC waits for the serial transfer of each frame.
+    assert_eq!(dac_period(1103, 15), Some(1104));
+    assert_eq!(dac_period(999, 15), Some(66 * 16));
+    assert_eq!(dac_period(99, 3), Some(264));
+}
+
+#[test]
+fn ai_dac_clock_stopped() {
+    assert_eq!(dac_period(1103, 0), None);
+}
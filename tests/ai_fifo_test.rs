@@ -0,0 +1,72 @@
+extern crate r64emu;
+
+use r64emu::ai::AudioFifo;
+
+#[test]
+fn ai_fifo_double_buffer() {
+    let mut fifo = AudioFifo::default();
+    assert!(!fifo.busy());
+    assert!(!fifo.full());
+    assert_eq!(fifo.pop(), None);
+
+    // The first buffer starts immediately (interrupt), the second is queued.
+    assert_eq!(fifo.push(0x1000, 8), true);
+    assert!(fifo.busy());
+    assert!(!fifo.full());
+    assert_eq!(fifo.push(0x2000, 8), false);
+    assert!(fifo.full());
+
+    // A third buffer is dropped while the FIFO is full.
+    assert_eq!(fifo.push(0x3000, 8), false);
+    assert_eq!(fifo.len(), 8);
+
+    assert_eq!(fifo.pop(), Some((0x1000, false)));
+    assert_eq!(fifo.len(), 4);
+    assert!(fifo.full());
+
+    // End of first buffer: the second starts playing (interrupt).
+    assert_eq!(fifo.pop(), Some((0x1004, true)));
+    assert!(!fifo.full());
+    assert!(fifo.busy());
+    assert_eq!(fifo.addr(), 0x2000);
+    assert_eq!(fifo.len(), 8);
+
+    assert_eq!(fifo.pop(), Some((0x2000, false)));
+    assert_eq!(fifo.pop(), Some((0x2004, false)));
+    assert!(!fifo.busy());
+    assert_eq!(fifo.len(), 0);
+    assert_eq!(fifo.pop(), None);
+}
+
+#[test]
+fn ai_fifo_ignore_zero_length() {
+    let mut fifo = AudioFifo::default();
+    assert_eq!(fifo.push(0x1000, 0), false);
+    assert!(!fifo.busy());
+
+    // Lengths below 8 bytes are truncated to zero.
+    for len in 1..8 {
+        assert_eq!(fifo.push(0x1000, len), false);
+        assert!(!fifo.busy());
+        assert_eq!(fifo.pop(), None);
+    }
+
+    // Lengths and addresses are 8-byte aligned.
+    assert_eq!(fifo.push(0x1003, 0x17), true);
+    assert_eq!(fifo.addr(), 0x1000);
+    assert_eq!(fifo.len(), 0x10);
+}
+
+#[test]
+fn ai_fifo_8kb_boundary_bug() {
+    let mut fifo = AudioFifo::default();
+    fifo.push(0x1FF8, 16);
+
+    assert_eq!(fifo.pop(), Some((0x1FF8, false)));
+    assert_eq!(fifo.pop(), Some((0x1FFC, false)));
+    // The carry into the upper bits is applied one frame late: the first
+    // frame after the boundary is fetched from the previous 8KB block.
+    assert_eq!(fifo.pop(), Some((0x0000, false)));
+    assert_eq!(fifo.pop(), Some((0x2004, false)));
+    assert!(!fifo.busy());
+}
@@ -0,0 +1,118 @@
+extern crate flate2;
+extern crate r64emu;
+extern crate zip;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use r64emu::cartridge::{rom_crc, romswap, unpack, CicModel, RomHeader, SaveType};
+use r64emu::Region;
+use std::io::{Cursor, Write};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+fn make_rom() -> Vec<u8> {
+    let mut rom = vec![0x80, 0x37, 0x12, 0x40];
+    rom.extend((4..0x1000).map(|i| i as u8));
+    rom
+}
+
+#[test]
+fn rom_byte_orders() {
+    let z64 = make_rom();
+    let v64 = z64
+        .chunks(2)
+        .flat_map(|w| vec![w[1], w[0]])
+        .collect::<Vec<_>>();
+    let n64 = z64
+        .chunks(4)
+        .flat_map(|w| vec![w[3], w[2], w[1], w[0]])
+        .collect::<Vec<_>>();
+
+    assert_eq!(romswap(z64.clone()).unwrap(), z64);
+    assert_eq!(romswap(v64).unwrap(), z64);
+    assert_eq!(romswap(n64).unwrap(), z64);
+}
+
+#[test]
+fn rom_invalid() {
+    let mut rom = make_rom();
+    rom[0] = 0x12;
+    assert!(romswap(rom).is_err());
+    assert!(romswap(vec![0x80, 0x37, 0x12, 0x40]).is_err());
+}
+
+#[test]
+fn rom_unpack_gzip() {
+    let rom = make_rom();
+    let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+    enc.write_all(&rom).unwrap();
+    let gz = enc.finish().unwrap();
+
+    assert_eq!(unpack(gz).unwrap(), rom);
+    assert_eq!(unpack(rom.clone()).unwrap(), rom);
+}
+
+fn make_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, data) in files {
+        zip.start_file(*name, opts).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[test]
+fn rom_unpack_zip() {
+    let rom = make_rom();
+    let readme = b"This is not a ROM";
+
+    // A file with a ROM extension is preferred.
+    let data = make_zip(&[("readme.txt", &readme[..]), ("game.z64", &rom[..])]);
+    assert_eq!(unpack(data).unwrap(), rom);
+
+    // Otherwise, a file is recognized by its header.
+    let data = make_zip(&[("readme.txt", &readme[..]), ("game.bin", &rom[..])]);
+    assert_eq!(unpack(data).unwrap(), rom);
+
+    let data = make_zip(&[("readme.txt", &readme[..])]);
+    assert!(unpack(data).is_err());
+}
+
+#[test]
+fn rom_header() {
+    let mut rom = make_rom();
+    rom[0x08..0x0C].copy_from_slice(&[0x80, 0x24, 0x60, 0x00]);
+    rom[0x0C..0x10].copy_from_slice(&[0x00, 0x00, 0x14, 0x44]);
+    rom[0x20..0x34].copy_from_slice(b"SUPER MARIO 64      ");
+    rom[0x3B..0x40].copy_from_slice(b"NSMP\x01");
+
+    let h = RomHeader::parse(&rom);
+    assert_eq!(h.entrypoint, 0x8024_6000);
+    assert_eq!(h.title, "SUPER MARIO 64");
+    assert_eq!(h.game_code, "NSMP");
+    assert_eq!(h.version, 1);
+    assert_eq!(h.libultra_version(), "2.0D");
+    assert_eq!(h.region(), Region::Pal);
+    assert_eq!(h.save_type(), None);
+
+    // Homebrew header declaring a 16K EEPROM
+    rom[0x3B..0x40].copy_from_slice(b"NEDE\x20");
+    let h = RomHeader::parse(&rom);
+    assert_eq!(h.region(), Region::Ntsc);
+    assert_eq!(h.save_type(), Some(SaveType::Eeprom16k));
+}
+
+#[test]
+fn rom_checksum() {
+    // The expected values were computed on the same data with the algorithm
+    // of n64crc.c (the reference checksum tool), ported independently.
+    let rom = (0..0x10_1000)
+        .map(|i: usize| (i * 7 + (i >> 8)) as u8)
+        .collect::<Vec<_>>();
+
+    assert_eq!(rom_crc(&rom, CicModel::Cic6102), (0xF8CE_4DDC, 0x8D5C_CD0E));
+    assert_eq!(rom_crc(&rom, CicModel::Cic6103), (0xA394_6759, 0xD858_8D94));
+    assert_eq!(rom_crc(&rom, CicModel::Cic6105), (0xDF2A_F436, 0xBE3B_A2FE));
+    assert_eq!(rom_crc(&rom, CicModel::Cic6106), (0x0410_0F9E, 0x217F_1DE2));
+}
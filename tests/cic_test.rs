@@ -0,0 +1,25 @@
+extern crate r64emu;
+
+use r64emu::cartridge::CicModel;
+use r64emu::cic;
+
+#[test]
+fn cic_boot_info() {
+    assert_eq!(cic::boot_info(CicModel::Cic6102, true), 0x0000_3F3F);
+    assert_eq!(cic::boot_info(CicModel::Cic6105, true), 0x0000_9191);
+    assert_eq!(cic::boot_info(CicModel::Cic6103, false), 0x0002_7878);
+}
+
+#[test]
+fn cic_challenge() {
+    let challenge = [
+        0x10, 0x17, 0x1e, 0x25, 0x2c, 0x33, 0x3a, 0x41, 0x48, 0x4f, 0x56, 0x5d, 0x64, 0x6b, 0x72,
+    ];
+    let response = [
+        0x04, 0x3a, 0xd9, 0x98, 0x6a, 0x70, 0x39, 0xd8, 0x0c, 0xa3, 0x02, 0x38, 0xac, 0x45, 0x86,
+    ];
+    assert_eq!(cic::challenge(CicModel::Cic6105, &challenge), Some(response));
+
+    // Only the 6105 supports the challenge
+    assert_eq!(cic::challenge(CicModel::Cic6102, &challenge), None);
+}
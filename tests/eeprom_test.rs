@@ -0,0 +1,53 @@
+extern crate r64emu;
+
+use r64emu::eeprom::Eeprom;
+use std::fs;
+
+#[test]
+fn eeprom_blocks() {
+    let mut eeprom = Eeprom::new(Eeprom::SIZE_4K);
+    assert_eq!(eeprom.status(0), [0x00, 0x80, 0x00]);
+
+    let mut buf = [0u8; 8];
+    eeprom.read_block(3, &mut buf);
+    assert_eq!(buf, [0xFF; 8]);
+
+    eeprom.write_block(3, &[1, 2, 3, 4, 5, 6, 7, 8], 1000);
+    eeprom.read_block(3, &mut buf);
+    assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // 4K EEPROMs ignore the upper bits of the block number
+    eeprom.read_block(64 + 3, &mut buf);
+    assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(&eeprom.data()[24..32], &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // The EEPROM is busy for a while after a write
+    assert_eq!(eeprom.status(1001)[2], 0x80);
+    assert_eq!(eeprom.status(1000 + 100_000_000)[2], 0x00);
+
+    let eeprom = Eeprom::new(Eeprom::SIZE_16K);
+    assert_eq!(eeprom.status(0), [0x00, 0xC0, 0x00]);
+}
+
+#[test]
+fn eeprom_save_file() {
+    let path = std::env::temp_dir().join("r64emu_eeprom_test.eep");
+    fs::remove_file(&path).ok();
+
+    let mut eeprom = Eeprom::load(Eeprom::SIZE_16K, &path).unwrap();
+    eeprom.flush().unwrap();
+    assert!(!path.exists(), "unmodified EEPROM should not be saved");
+
+    eeprom.write_block(255, &[9; 8], 0);
+    eeprom.flush().unwrap();
+    assert_eq!(fs::read(&path).unwrap(), eeprom.data());
+
+    let reloaded = Eeprom::load(Eeprom::SIZE_16K, &path).unwrap();
+    assert_eq!(reloaded.data(), eeprom.data());
+
+    // A 4K EEPROM loads the beginning of a larger save file
+    let small = Eeprom::load(Eeprom::SIZE_4K, &path).unwrap();
+    assert_eq!(small.data(), &eeprom.data()[..Eeprom::SIZE_4K]);
+
+    fs::remove_file(&path).ok();
+}
@@ -0,0 +1,87 @@
+extern crate r64emu;
+
+use r64emu::cartridge::{RomHeader, SaveType};
+use r64emu::gamedb::{Accessories, GameDb, GameInfo};
+use r64emu::Region;
+
+fn header(game_code: &str, crc1: u32, crc2: u32) -> RomHeader {
+    RomHeader {
+        pi_config: 0x8037_1240,
+        clock_rate: 0xF,
+        entrypoint: 0x8000_0400,
+        libultra: 0x1444,
+        crc1,
+        crc2,
+        title: "TEST".into(),
+        game_code: game_code.into(),
+        version: 0,
+    }
+}
+
+#[test]
+fn gamedb_builtin() {
+    let db = GameDb::builtin();
+    let game = db.lookup(&header("NSME", 0, 0));
+    assert_eq!(
+        game.name.as_ref().map(|s| s.as_str()),
+        Some("Super Mario 64")
+    );
+    assert_eq!(game.save_type, Some(SaveType::Eeprom4k));
+    assert_eq!(game.expansion_pak, None);
+
+    let game = db.lookup(&header("NDOE", 0, 0));
+    assert_eq!(game.expansion_pak, Some(true));
+
+    assert_eq!(db.lookup(&header("NXXE", 0, 0)), GameInfo::default());
+}
+
+#[test]
+fn gamedb_merge() {
+    let db = GameDb::parse(
+        "
+        # comment
+        [NAB]
+        save = eeprom4k
+        accessories = mempak, rumble
+
+        [NABP]
+        save = sram32k
+
+        [12345678-9ABCDEF0]
+        hacks = foo,bar
+        ",
+    )
+    .unwrap();
+
+    let game = db.lookup(&header("NABE", 0, 0));
+    assert_eq!(game.save_type, Some(SaveType::Eeprom4k));
+    assert_eq!(
+        game.accessories,
+        Some(Accessories::MEMPAK | Accessories::RUMBLE)
+    );
+    assert!(!game.has_hack("foo"));
+
+    let game = db.lookup(&header("NABP", 0x1234_5678, 0x9ABC_DEF0));
+    assert_eq!(game.save_type, Some(SaveType::Sram32k));
+    assert_eq!(
+        game.accessories,
+        Some(Accessories::MEMPAK | Accessories::RUMBLE)
+    );
+    assert!(game.has_hack("foo"));
+    assert!(game.has_hack("bar"));
+}
+
+#[test]
+fn gamedb_errors() {
+    assert!(GameDb::parse("save = sram32k").is_err());
+    assert!(GameDb::parse("[NAB]\nsave = floppy").is_err());
+    assert!(GameDb::parse("[NAB]\nfoo = bar").is_err());
+    assert!(GameDb::parse("[NAB]\n[NAB]").is_err());
+
+    let mut game = GameInfo::default();
+    assert!(game.set_option("region").is_err());
+    game.set_option("region=pal").unwrap();
+    game.set_option("expansion_pak = required").unwrap();
+    assert_eq!(game.region, Some(Region::Pal));
+    assert_eq!(game.expansion_pak, Some(true));
+}
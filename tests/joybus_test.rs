@@ -0,0 +1,97 @@
+extern crate emu;
+extern crate r64emu;
+
+use emu::input::InputManager;
+use r64emu::eeprom::Eeprom;
+use r64emu::joybus::{Joybus, JoybusDevice};
+
+fn pifram(cmds: &[u8]) -> Vec<u8> {
+    let mut ram = vec![0u8; 0x3F];
+    ram[..cmds.len()].copy_from_slice(cmds);
+    ram
+}
+
+#[test]
+fn joybus_controllers() {
+    let mut joybus = Joybus::new();
+    let mut input = InputManager::new(vec![]);
+
+    // Read buttons on all 4 ports (as done by libultra): only the first
+    // controller is connected.
+    #[rustfmt::skip]
+    let mut ram = pifram(&[
+        0xFF, 0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFE,
+    ]);
+    joybus.exec(&mut ram, &mut input, 0).unwrap();
+    assert_eq!(&ram[1..8], &[0x01, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(ram[10], 0x84);
+    assert_eq!(ram[18], 0x84);
+    assert_eq!(ram[26], 0x84);
+
+    // Status and reset, skipping the first port
+    joybus
+        .set_device(1, JoybusDevice::Controller(None))
+        .unwrap();
+    joybus.set_device(2, JoybusDevice::Mouse).unwrap();
+    #[rustfmt::skip]
+    let mut ram = pifram(&[
+        0x00,
+        0x01, 0x03, 0xFF, 0xFF, 0xFF, 0xFF,
+        0x01, 0x03, 0x00, 0xFF, 0xFF, 0xFF,
+        0xFE,
+    ]);
+    joybus.exec(&mut ram, &mut input, 0).unwrap();
+    assert_eq!(&ram[1..7], &[0x01, 0x03, 0xFF, 0x05, 0x00, 0x02]);
+    assert_eq!(&ram[7..13], &[0x01, 0x03, 0x00, 0x02, 0x00, 0x00]);
+
+    // Wrong RX length, and unsupported command
+    #[rustfmt::skip]
+    let mut ram = pifram(&[
+        0x01, 0x02, 0x00, 0xFF, 0xFF,
+        0x01, 0x01, 0x04, 0xFF,
+        0xFE,
+    ]);
+    joybus.exec(&mut ram, &mut input, 0).unwrap();
+    assert_eq!(&ram[0..5], &[0x01, 0x42, 0x00, 0x05, 0x00]);
+    assert_eq!(&ram[5..9], &[0x01, 0x81, 0x04, 0xFF]);
+}
+
+#[test]
+fn joybus_eeprom() {
+    let mut joybus = Joybus::new();
+    let mut input = InputManager::new(vec![]);
+    joybus
+        .set_device(4, JoybusDevice::Eeprom(Eeprom::new(Eeprom::SIZE_4K)))
+        .unwrap();
+
+    // Write block 5, then read it back
+    #[rustfmt::skip]
+    let mut ram = pifram(&[
+        0x00, 0x00, 0x00, 0x00,
+        0x0A, 0x01, 0x05, 0x05, 1, 2, 3, 4, 5, 6, 7, 8, 0xFF,
+        0xFE,
+    ]);
+    joybus.exec(&mut ram, &mut input, 0).unwrap();
+    assert_eq!(&ram[5..6], &[0x01]);
+    assert_eq!(ram[16], 0x00);
+
+    #[rustfmt::skip]
+    let mut ram = pifram(&[
+        0x00, 0x00, 0x00, 0x00,
+        0x02, 0x08, 0x04, 0x05, 0, 0, 0, 0, 0, 0, 0, 0,
+        0xFE,
+    ]);
+    joybus.exec(&mut ram, &mut input, 0).unwrap();
+    assert_eq!(ram[5], 0x08);
+    assert_eq!(&ram[8..16], &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // Without an EEPROM, the cartridge channel reports no device
+    joybus.set_device(4, JoybusDevice::None).unwrap();
+    let mut ram = pifram(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x00, 0, 0, 0, 0xFE]);
+    joybus.exec(&mut ram, &mut input, 0).unwrap();
+    assert_eq!(ram[5], 0x83);
+}
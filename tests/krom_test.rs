@@ -22,6 +22,7 @@ use std::io;
 use std::path::Path;
 
 static KROM_PATH: &'static str = "roms/tests";
+static PIF_PATH: &'static str = "bios/pifdata.bin";
 
 const RES_320: u32 = 0x20;
 const APPROX: u32 = 0x40;
@@ -29,7 +30,7 @@ const FPS30: u32 = 0x80;
 const FPS60: u32 = 0x100;
 const FPS120: u32 = 0x200;
 
-fn test_krom(romfn: &str, flags: u32) -> Result<(), Error> {
+fn test_krom(romfn: &str, bios: Option<&Path>, flags: u32) -> Result<(), Error> {
     let logger = slog::Logger::root(Discard, o!());
     let (scale, resw, resh) = if flags & RES_320 != 0 {
         (2, 320, 240)
@@ -38,7 +39,7 @@ fn test_krom(romfn: &str, flags: u32) -> Result<(), Error> {
     };
 
     // Create N64 object and emulate 5 frames
-    let mut n64 = N64::new(logger, Path::new(romfn), Path::new("bios/pifdata.bin")).unwrap();
+    let mut n64 = N64::new(logger, Path::new(romfn), bios).unwrap();
     n64.setup_cic(true).unwrap();
     let mut screen1 = OwnedGfxBufferLE::<Rgb888>::new(640, 480);
     let mut sound1 = OwnedSndBuffer::<S16_STEREO>::with_capacity(512);
@@ -161,7 +162,7 @@ macro_rules! krom {
     ($test_name:ident, $romfn:expr, $flags:expr) => {
         #[test]
         fn $test_name() {
-            test_krom(&format!("{}/{}", KROM_PATH, $romfn), $flags).unwrap();
+            test_krom(&format!("{}/{}", KROM_PATH, $romfn), None, $flags).unwrap();
         }
     };
 }
@@ -206,6 +207,18 @@ macro_rules! krom_rsp {
     };
 }
 
+// All tests boot through the HLE PIF boot; this one goes through the real
+// PIF ROM instead, if available (it cannot be distributed with the sources).
+#[test]
+fn pif_boot() {
+    let bios = Path::new(PIF_PATH);
+    if !bios.exists() {
+        return;
+    }
+    let romfn = format!("{}/{}", KROM_PATH, "CPUTest/CPU/ADD/CPUADD.N64");
+    test_krom(&romfn, Some(bios), 0).unwrap();
+}
+
 krom_cpu!(cpu_xor, "XOR/CPUXOR.N64", 0);
 krom_cpu!(cpu_ddivu, "DDIVU/CPUDDIVU.N64", 0);
 krom_cpu!(cpu_dmultu, "DMULTU/CPUDMULTU.N64", 0);
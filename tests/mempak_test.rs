@@ -0,0 +1,100 @@
+extern crate r64emu;
+
+use r64emu::accessory::data_crc;
+use r64emu::mempak::{self, Mempak};
+use std::fs;
+
+// Build an exported note, with the specified name and number of pages.
+fn make_note(name: &[u8], npages: usize) -> Vec<u8> {
+    let mut note = vec![0u8; mempak::NOTE_ENTRY_SIZE];
+    note[0..4].copy_from_slice(b"NSME");
+    note[4..6].copy_from_slice(b"01");
+    note[8] = 0x02;
+    note[0x10..0x10 + name.len()].copy_from_slice(name);
+    for i in 0..npages {
+        note.extend(std::iter::repeat(i as u8 + 1).take(mempak::PAGE_SIZE));
+    }
+    note
+}
+
+#[test]
+fn mempak_format() {
+    let pak = Mempak::new();
+    assert_eq!(pak.notes(), vec![]);
+    assert_eq!(pak.free_pages(), 123);
+
+    // ID block with checksums
+    assert_eq!(&pak.data()[0x3C..0x40], &[0x66, 0x25, 0x99, 0xCD]);
+    assert_eq!(&pak.data()[0x20..0x40], &pak.data()[0xC0..0xE0]);
+
+    // Inode table checksum, and backup copy
+    assert_eq!(pak.data()[0x101], 0x71);
+    assert_eq!(&pak.data()[0x100..0x200], &pak.data()[0x200..0x300]);
+}
+
+#[test]
+fn mempak_notes() {
+    let mut pak = Mempak::new();
+
+    // "MARIO" in the N64 font encoding
+    let note1 = make_note(&[0x26, 0x1A, 0x2B, 0x22, 0x28], 3);
+    let note2 = make_note(&[0x10, 0x11, 0x0F, 0x34], 2);
+    assert_eq!(pak.import_note(&note1).unwrap(), 0);
+    assert_eq!(pak.import_note(&note2).unwrap(), 1);
+    assert_eq!(pak.free_pages(), 118);
+
+    let notes = pak.notes();
+    assert_eq!(notes.len(), 2);
+    assert_eq!(notes[0].name, "MARIO");
+    assert_eq!(notes[0].game_code, "NSME");
+    assert_eq!(notes[0].publisher, "01");
+    assert_eq!(notes[0].pages, 3);
+    assert_eq!(notes[1].name, "01 !");
+    assert_eq!(notes[1].pages, 2);
+
+    // Exporting gives back the same note, with the newly allocated start page
+    let exported = pak.export_note(1).unwrap();
+    assert_eq!(&exported[mempak::NOTE_ENTRY_SIZE..], &note2[mempak::NOTE_ENTRY_SIZE..]);
+    assert_eq!(&exported[6..8], &[0x00, 0x08]);
+
+    // Freed pages are reused
+    pak.delete_note(0).unwrap();
+    assert_eq!(pak.free_pages(), 121);
+    assert_eq!(pak.import_note(&note1).unwrap(), 0);
+    assert_eq!(pak.export_note(0).unwrap()[mempak::NOTE_ENTRY_SIZE..], note1[mempak::NOTE_ENTRY_SIZE..]);
+
+    assert!(pak.import_note(&make_note(b"", 200)).is_err());
+    assert!(pak.import_note(&[0; 100]).is_err());
+}
+
+#[test]
+fn mempak_save_file() {
+    let path = std::env::temp_dir().join("r64emu_mempak_test.mpk");
+    fs::remove_file(&path).ok();
+
+    let mut pak = Mempak::load(&path).unwrap();
+    pak.flush().unwrap();
+    assert!(!path.exists(), "unmodified Controller Pak should not be saved");
+
+    pak.write(0x1000, &[0x55; 32]);
+    pak.flush().unwrap();
+    assert_eq!(fs::read(&path).unwrap().len(), mempak::SIZE);
+
+    let reloaded = Mempak::load(&path).unwrap();
+    let mut buf = [0u8; 32];
+    reloaded.read(0x1000, &mut buf);
+    assert_eq!(buf, [0x55; 32]);
+
+    // Addresses above 0x8000 are not mapped
+    reloaded.read(0x8000, &mut buf);
+    assert_eq!(buf, [0; 32]);
+
+    fs::remove_file(&path).ok();
+}
+
+#[test]
+fn accessory_crc() {
+    assert_eq!(data_crc(&[0x00; 32]), 0x00);
+    assert_eq!(data_crc(&[0x80; 32]), 0xB8);
+    assert_eq!(data_crc(&[0x01; 32]), 0xEB);
+}
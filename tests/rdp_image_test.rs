@@ -0,0 +1,169 @@
+//! RDP image tests: each case is a TOML file under tests/rdpimage, listing
+//! RDP command words (plus optional RDRAM/TMEM contents, or a capture file)
+//! and the expected framebuffer as a PNG image.
+//!
+//! The expected images are derived by hand from the documented behavior of
+//! the commands (fill colors, texel copies), not captured on hardware: they
+//! catch regressions in the rasterizer, but do not prove its accuracy.
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate serde_derive;
+
+extern crate emu;
+extern crate image;
+extern crate r64emu;
+extern crate toml;
+
+use emu::gfx::{BufferLineGetter, OwnedGfxBufferLE, Rgba8888};
+use image::{Pixel, Rgba, RgbaImage};
+use r64emu::dpcap::DpReplay;
+use slog::Discard;
+use std::fs;
+use std::path::Path;
+
+// A block of memory to load before running the commands. The contents are
+// read from a binary file, relative to the test file.
+#[derive(Deserialize)]
+struct MemBlock {
+    addr: String,
+    file: String,
+}
+
+#[derive(Deserialize)]
+struct TestCase {
+    /// RDP command words, as hex strings (TOML integers are signed).
+    #[serde(default)]
+    commands: Vec<String>,
+    /// Capture file (created with --dp-capture) to replay after the
+    /// commands, if any.
+    capture: Option<String>,
+    #[serde(default)]
+    rdram: Vec<MemBlock>,
+    #[serde(default)]
+    tmem: Vec<MemBlock>,
+    /// Expected framebuffer (PNG, RGBA)
+    expected: String,
+    /// Maximum difference allowed for each color component.
+    #[serde(default)]
+    tolerance: u8,
+}
+
+fn parse_hex(s: &str) -> u64 {
+    let s = s.trim_start_matches("0x").replace("_", "");
+    u64::from_str_radix(&s, 16).expect("invalid hex number")
+}
+
+// Create an image that highlights the differences: pixels that match are
+// shown as a dimmed greyscale version of the expected image, mismatching pixels
+// are painted in red.
+fn diff_image(found: &OwnedGfxBufferLE<Rgba8888>, expected: &RgbaImage, tolerance: u8) -> RgbaImage {
+    let (w, h) = expected.dimensions();
+    let found = found.buf();
+    RgbaImage::from_fn(w, h, |x, y| {
+        let cf = found.line(y as usize).get(x as usize).components();
+        let ce = expected.get_pixel(x, y).channels4();
+        let cf = [cf.0 as u8, cf.1 as u8, cf.2 as u8];
+        let ce = [ce.0, ce.1, ce.2];
+        if cf.iter().zip(ce.iter()).all(|(f, e)| diff(*f, *e) <= tolerance) {
+            let grey = ((ce[0] as u32 + ce[1] as u32 + ce[2] as u32) / 3 / 4) as u8;
+            Rgba([grey, grey, grey, 0xFF])
+        } else {
+            Rgba([0xFF, 0, 0, 0xFF])
+        }
+    })
+}
+
+fn diff(a: u8, b: u8) -> u8 {
+    (a as i32 - b as i32).abs() as u8
+}
+
+fn test_image(testname: &str) {
+    let tomlname = Path::new(testname);
+    let tomlsrc = fs::read_to_string(tomlname).expect("TOML file not found");
+    let test: TestCase = toml::from_str(&tomlsrc).unwrap();
+    let dir = tomlname.parent().unwrap();
+
+    let logger = slog::Logger::root(Discard, o!());
+    let mut replay = DpReplay::new(logger);
+
+    // Load the inputs
+    for block in &test.rdram {
+        let data = fs::read(dir.join(&block.file)).expect("RDRAM input file not found");
+        replay.write_mem(parse_hex(&block.addr) as u32, &data).unwrap();
+    }
+    for block in &test.tmem {
+        let data = fs::read(dir.join(&block.file)).expect("TMEM input file not found");
+        replay.write_tmem(parse_hex(&block.addr) as usize, &data).unwrap();
+    }
+
+    // Run the commands
+    let cmds: Vec<u64> = test.commands.iter().map(|c| parse_hex(c)).collect();
+    replay.run(&cmds);
+    if let Some(capture) = &test.capture {
+        replay.run_file(&dir.join(capture)).unwrap();
+    }
+
+    let found = replay.framebuffer().unwrap();
+    let expected: RgbaImage = image::open(dir.join(&test.expected))
+        .expect("expected image not found")
+        .to_rgba();
+    if expected.dimensions() != (found.width() as u32, found.height() as u32) {
+        panic!(
+            "invalid expected image size: {:?} (framebuffer: {}x{})",
+            expected.dimensions(),
+            found.width(),
+            found.height()
+        );
+    }
+
+    // Count the mismatching pixels
+    let mut ndiffs = 0;
+    {
+        let buf = found.buf();
+        for y in 0..found.height() {
+            let line = buf.line(y);
+            for x in 0..found.width() {
+                let cf = line.get(x).components();
+                let ce = expected.get_pixel(x as u32, y as u32).channels4();
+                let cf = [cf.0 as u8, cf.1 as u8, cf.2 as u8];
+                let ce = [ce.0, ce.1, ce.2];
+                if cf.iter().zip(ce.iter()).any(|(f, e)| diff(*f, *e) > test.tolerance) {
+                    if ndiffs < 16 {
+                        println!("({:3},{:3}): found {:?}, expected {:?}", x, y, cf, ce);
+                    }
+                    ndiffs += 1;
+                }
+            }
+        }
+    }
+
+    if ndiffs != 0 {
+        let diffname = std::env::temp_dir().join(format!(
+            "rdpimage-{}.diff.png",
+            tomlname.file_stem().unwrap().to_string_lossy()
+        ));
+        diff_image(&found, &expected, test.tolerance)
+            .save(&diffname)
+            .unwrap();
+        panic!(
+            "{} pixels different from expected image (diff image: {})",
+            ndiffs,
+            diffname.display()
+        );
+    }
+}
+
+macro_rules! define_image_test {
+    ($test:ident, $fn:expr) => {
+        #[test]
+        fn $test() {
+            test_image(concat!("tests/rdpimage/", $fn));
+        }
+    };
+}
+
+define_image_test!(rdp_fill_rect16, "fill_rect16.toml");
+define_image_test!(rdp_fill_rect32, "fill_rect32.toml");
+define_image_test!(rdp_fill_rect16_over, "fill_rect16_over.toml");
+define_image_test!(rdp_texrect_copy16, "texrect_copy16.toml");
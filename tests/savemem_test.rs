@@ -0,0 +1,92 @@
+extern crate emu;
+extern crate r64emu;
+extern crate slog;
+
+use emu::bus::be::{Bus, Device};
+use r64emu::cartridge::SaveType;
+use r64emu::flashram::{self, FlashRam};
+use r64emu::sram::{self, Sram};
+use slog::{o, Discard, Logger};
+use std::fs;
+
+fn logger() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+#[test]
+fn sram_banks() {
+    let path = std::env::temp_dir().join("r64emu_sram_test.sra");
+    fs::remove_file(&path).ok();
+
+    Sram::new(logger(), SaveType::Sram96k, &path)
+        .unwrap()
+        .register();
+    let mut bus = Bus::new(logger());
+    for bank in 0..Sram::get().banks() {
+        bus.map_device(0x0800_0000 + bank as u32 * 0x4_0000, Sram::get(), bank)
+            .unwrap();
+    }
+
+    bus.write::<u32>(0x0800_0010, 0x1122_3344);
+    bus.write::<u32>(0x0808_0010, 0x5566_7788);
+    assert_eq!(bus.read::<u32>(0x0800_0010), 0x1122_3344);
+    assert_eq!(bus.read::<u32>(0x0808_0010), 0x5566_7788);
+
+    Sram::get_mut().flush().unwrap();
+    let saved = fs::read(&path).unwrap();
+    assert_eq!(saved.len(), sram::BANK_SIZE * 3);
+    assert_eq!(&saved[0x10..0x14], &[0x11, 0x22, 0x33, 0x44]);
+    assert_eq!(
+        &saved[sram::BANK_SIZE * 2 + 0x10..sram::BANK_SIZE * 2 + 0x14],
+        &[0x55, 0x66, 0x77, 0x88]
+    );
+
+    fs::remove_file(&path).ok();
+}
+
+#[test]
+fn flashram_commands() {
+    let path = std::env::temp_dir().join("r64emu_flashram_test.fla");
+    fs::remove_file(&path).ok();
+
+    FlashRam::new(logger(), &path).unwrap().register();
+    let mut bus = Bus::new(logger());
+    bus.map_device(0x0800_0000, FlashRam::get(), 0).unwrap();
+
+    // Silicon ID
+    let mut id = [0u8; 8];
+    bus.write::<u32>(0x0801_0000, 0xE100_0000);
+    FlashRam::get().dma_read(0, &mut id);
+    assert_eq!(id, [0x11, 0x11, 0x80, 0x01, 0x00, 0xC2, 0x00, 0x1E]);
+    assert_eq!(bus.read::<u32>(0x0800_0000), 0x1111_8001);
+    bus.write::<u32>(0x0800_0000, 0);
+    FlashRam::get().dma_read(0, &mut id);
+    assert_eq!(id, [0; 8]);
+
+    // Program page 3
+    let page = (0..flashram::PAGE_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+    bus.write::<u32>(0x0801_0000, 0x4B00_0003);
+    bus.write::<u32>(0x0801_0000, 0x7800_0000);
+    bus.write::<u32>(0x0801_0000, 0xB400_0000);
+    FlashRam::get_mut().dma_write(&page);
+    bus.write::<u32>(0x0801_0000, 0xA500_0003);
+    assert_eq!(&FlashRam::get().data()[3 * 128..4 * 128], &page[..]);
+
+    // Read it back: in read mode, the array is addressed in 16-bit units.
+    let mut buf = vec![0u8; flashram::PAGE_SIZE];
+    bus.write::<u32>(0x0801_0000, 0xF000_0000);
+    FlashRam::get().dma_read(3 * 128 / 2, &mut buf);
+    assert_eq!(buf, page);
+
+    // Erase the page
+    bus.write::<u32>(0x0801_0000, 0x4B00_0003);
+    bus.write::<u32>(0x0801_0000, 0x7800_0000);
+    assert_eq!(
+        &FlashRam::get().data()[3 * 128..4 * 128],
+        &[0xFF; flashram::PAGE_SIZE][..]
+    );
+
+    FlashRam::get_mut().flush().unwrap();
+    assert_eq!(fs::read(&path).unwrap().len(), flashram::SIZE);
+    fs::remove_file(&path).ok();
+}